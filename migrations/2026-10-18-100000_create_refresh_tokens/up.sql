@@ -0,0 +1,18 @@
+create table auth.refresh_tokens
+(
+    id         serial primary key,
+    user_id    integer                 not null references auth.users (id) on delete cascade,
+    family_id  varchar(64)             not null,
+    token_hash varchar(64)             not null,
+    created_at timestamp default now() not null,
+    expires_at timestamp               not null,
+    used_at    timestamp,
+    revoked_at timestamp,
+    CONSTRAINT refresh_tokens_token_hash_unique UNIQUE (token_hash)
+);
+
+create index refresh_tokens_family_id_index
+    on auth.refresh_tokens (family_id);
+
+create index refresh_tokens_user_id_index
+    on auth.refresh_tokens (user_id);
@@ -0,0 +1,6 @@
+-- This file should undo anything in `up.sql`
+
+ALTER TABLE auth.refresh_tokens DROP COLUMN IF EXISTS client_id;
+ALTER TABLE auth.refresh_tokens DROP COLUMN IF EXISTS scope;
+DROP TABLE IF EXISTS auth.oauth_authorization_codes;
+DROP TABLE IF EXISTS auth.oauth_clients;
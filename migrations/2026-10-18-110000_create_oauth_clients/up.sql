@@ -0,0 +1,31 @@
+create table auth.oauth_clients
+(
+    id            serial primary key,
+    client_id     varchar(255)            not null,
+    name          varchar(255)            not null,
+    redirect_uris text[]                  not null,
+    scopes        text[]                  not null,
+    created_at    timestamp default now() not null,
+    updated_at    timestamp default now() not null,
+    CONSTRAINT oauth_clients_client_id_unique UNIQUE (client_id)
+);
+
+create table auth.oauth_authorization_codes
+(
+    id             serial primary key,
+    code_hash      varchar(64)             not null,
+    client_id      integer                 not null references auth.oauth_clients (id) on delete cascade,
+    user_id        integer                 not null references auth.users (id) on delete cascade,
+    redirect_uri   text                    not null,
+    scope          varchar(255)            not null,
+    code_challenge varchar(128)            not null,
+    created_at     timestamp default now() not null,
+    expires_at     timestamp               not null,
+    used_at        timestamp,
+    CONSTRAINT oauth_authorization_codes_code_hash_unique UNIQUE (code_hash)
+);
+
+-- client_id is null for first party tokens, issued by /users/auth
+alter table auth.refresh_tokens
+    add column scope     varchar(255),
+    add column client_id integer references auth.oauth_clients (id) on delete cascade;
@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+
+ALTER TABLE auth.oauth_authorization_codes DROP COLUMN IF EXISTS nonce;
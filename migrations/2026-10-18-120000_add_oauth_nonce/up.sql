@@ -0,0 +1,2 @@
+alter table auth.oauth_authorization_codes
+    add column nonce varchar(255);
@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+
+ALTER TABLE auth.oauth_clients DROP COLUMN IF EXISTS client_secret;
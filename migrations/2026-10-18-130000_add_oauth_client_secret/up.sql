@@ -0,0 +1,3 @@
+-- Confidential clients have an argon2 hashed secret, public clients (SPA, mobile) don't
+alter table auth.oauth_clients
+    add column client_secret varchar(255);
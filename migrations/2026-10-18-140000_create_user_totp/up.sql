@@ -0,0 +1,11 @@
+-- The secret is encrypted with MFA_ENCRYPTION_KEY (AES-256-GCM), it is only active once confirmed
+create table auth.user_totp
+(
+    id             serial primary key,
+    user_id        integer                 not null references auth.users (id) on delete cascade,
+    secret         varchar(255)            not null,
+    created_at     timestamp default now() not null,
+    confirmed_at   timestamp,
+    last_used_step bigint,
+    CONSTRAINT user_totp_user_id_unique UNIQUE (user_id)
+);
@@ -0,0 +1,17 @@
+-- Passkeys and security keys, the public key is DER encoded (SubjectPublicKeyInfo)
+create table auth.webauthn_credentials
+(
+    id            serial primary key,
+    user_id       integer                 not null references auth.users (id) on delete cascade,
+    credential_id varchar(1365)           not null,
+    public_key    text                    not null,
+    algorithm     integer                 not null,
+    sign_count    bigint    default 0     not null,
+    name          varchar(255),
+    created_at    timestamp default now() not null,
+    last_used_at  timestamp,
+    CONSTRAINT webauthn_credentials_credential_id_unique UNIQUE (credential_id)
+);
+
+create index webauthn_credentials_user_id_index
+    on auth.webauthn_credentials (user_id);
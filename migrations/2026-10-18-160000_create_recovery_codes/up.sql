@@ -0,0 +1,13 @@
+-- Single use codes for users who lost their second factor, stored as an HMAC-SHA256
+-- keyed with MFA_ENCRYPTION_KEY so a code is found with one indexed query
+create table auth.recovery_codes
+(
+    id         serial primary key,
+    user_id    integer                 not null references auth.users (id) on delete cascade,
+    code_hash  varchar(64)             not null,
+    created_at timestamp default now() not null,
+    used_at    timestamp
+);
+
+create index recovery_codes_user_id_code_hash_index
+    on auth.recovery_codes (user_id, code_hash);
@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+
+ALTER TABLE auth.users DROP COLUMN IF EXISTS email_verified_at;
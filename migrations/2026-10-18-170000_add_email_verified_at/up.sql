@@ -0,0 +1,3 @@
+-- Set once the user followed the link emailed at signup
+alter table auth.users
+    add column email_verified_at timestamp;
@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+
+ALTER TABLE auth.users DROP COLUMN IF EXISTS credentials_revoked_at;
+DROP TABLE IF EXISTS auth.password_reset_tokens;
@@ -0,0 +1,17 @@
+create table auth.password_reset_tokens
+(
+    id         serial primary key,
+    user_id    integer                 not null references auth.users (id) on delete cascade,
+    token_hash varchar(64)             not null,
+    created_at timestamp default now() not null,
+    expires_at timestamp               not null,
+    used_at    timestamp,
+    CONSTRAINT password_reset_tokens_token_hash_unique UNIQUE (token_hash)
+);
+
+create index password_reset_tokens_user_id_index
+    on auth.password_reset_tokens (user_id);
+
+-- Sessions and access tokens issued before are refused, the cookie backend can't end them otherwise
+alter table auth.users
+    add column credentials_revoked_at timestamp;
@@ -0,0 +1,14 @@
+create table auth.magic_links
+(
+    id         serial primary key,
+    user_id    integer                 not null references auth.users (id) on delete cascade,
+    jti        varchar(64)             not null,
+    created_at timestamp default now() not null,
+    expires_at timestamp               not null,
+    used_at    timestamp,
+    revoked_at timestamp,
+    CONSTRAINT magic_links_jti_unique UNIQUE (jti)
+);
+
+create index magic_links_user_id_index
+    on auth.magic_links (user_id);
@@ -0,0 +1,17 @@
+create table auth.email_otps
+(
+    id         serial primary key,
+    user_id    integer                 not null references auth.users (id) on delete cascade,
+    login      varchar(255)            not null,
+    code_hash  varchar(255)            not null,
+    attempts   integer   default 0     not null,
+    created_at timestamp default now() not null,
+    expires_at timestamp               not null,
+    used_at    timestamp
+);
+
+create index email_otps_login_index
+    on auth.email_otps (login);
+
+create index email_otps_user_id_index
+    on auth.email_otps (user_id);
@@ -0,0 +1,6 @@
+-- This file should undo anything in `up.sql`
+
+ALTER TABLE auth.users DROP COLUMN IF EXISTS mfa_attempts;
+ALTER TABLE auth.users DROP COLUMN IF EXISTS is_admin;
+ALTER TABLE auth.users DROP COLUMN IF EXISTS locked_until;
+ALTER TABLE auth.users DROP COLUMN IF EXISTS failed_logins;
@@ -0,0 +1,8 @@
+-- Failed password attempts since the last success, reset when the account locks.
+-- Second factor attempts since the last password check, kept out of the session
+-- so replaying an older cookie doesn't reset them.
+alter table auth.users
+    add column failed_logins integer default 0 not null,
+    add column locked_until  timestamp,
+    add column is_admin      boolean default false not null,
+    add column mfa_attempts  integer default 0 not null;
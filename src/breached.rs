@@ -0,0 +1,254 @@
+use std::cmp::Ordering;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use lazy_static::lazy_static;
+use log::{info, warn};
+use openssl::sha::sha1;
+
+use crate::local_env::*;
+
+lazy_static! {
+    static ref DATASET: Option<Dataset> = BREACHED_PASSWORDS_FILE.as_ref().map(|path| {
+        Dataset::open(path).unwrap_or_else(|e| {
+            panic!("Can't load BREACHED_PASSWORDS_FILE {}: {}", path, e);
+        })
+    });
+}
+
+/// First bytes of a filter built by `kz-auth build-breach-filter`
+const MAGIC: &[u8; 8] = b"KZBLOOM1";
+
+/// Breached passwords, as published by Have I Been Pwned
+enum Dataset {
+    /// "SHA1:COUNT" lines ordered by hash, searched on disk
+    Sorted(String),
+    /// Hashes seen at least `min_count` times, kept in memory
+    Filter(BloomFilter),
+}
+
+impl Dataset {
+    fn open(path: &str) -> io::Result<Dataset> {
+        let mut magic = [0u8; 8];
+        let is_filter = File::open(path)?.read_exact(&mut magic).is_ok() && &magic == MAGIC;
+        if !is_filter {
+            info!("[{}] -- Searching the ordered HIBP file {}", "Breached", path);
+            return Ok(Dataset::Sorted(path.to_string()));
+        }
+
+        let filter = BloomFilter::read(&mut BufReader::new(File::open(path)?))?;
+        info!("[{}] -- Loaded a filter of {} hashes seen at least {} times", "Breached", filter.items, filter.min_count);
+        // The counts are not in the filter, it can't be stricter than when it was built
+        if *BREACHED_PASSWORDS_MIN_COUNT > filter.min_count {
+            warn!("[{}] -- The filter was built with --min-count {}, BREACHED_PASSWORDS_MIN_COUNT {} can't be applied", "Breached", filter.min_count, *BREACHED_PASSWORDS_MIN_COUNT);
+        }
+        Ok(Dataset::Filter(filter))
+    }
+}
+
+pub struct BloomFilter {
+    bits: Vec<u8>,
+    hashes: u32,
+    min_count: u64,
+    items: u64,
+}
+
+impl BloomFilter {
+    /// Sized for `items` entries with the given false positive rate
+    pub fn new(items: u64, false_positive_rate: f64, min_count: u64) -> BloomFilter {
+        let ln2 = std::f64::consts::LN_2;
+        let size = (-(items.max(1) as f64) * false_positive_rate.ln() / (ln2 * ln2)).ceil() as u64;
+        let hashes = ((size as f64 / items.max(1) as f64) * ln2).round().max(1.0) as u32;
+        BloomFilter {
+            bits: vec![0; size.div_ceil(8) as usize],
+            hashes,
+            min_count,
+            items: 0,
+        }
+    }
+
+    /// SHA-1 is uniform already, its first 16 bytes seed double hashing
+    fn positions(&self, digest: &[u8; 20]) -> impl Iterator<Item = usize> {
+        let h1 = u64::from_be_bytes(digest[0..8].try_into().unwrap());
+        let h2 = u64::from_be_bytes(digest[8..16].try_into().unwrap()) | 1;
+        let size = self.bits.len() as u64 * 8;
+        (0..self.hashes as u64).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % size) as usize)
+    }
+
+    pub fn insert(&mut self, digest: &[u8; 20]) {
+        let positions: Vec<usize> = self.positions(digest).collect();
+        for x in positions {
+            self.bits[x / 8] |= 1 << (x % 8);
+        }
+        self.items += 1;
+    }
+
+    pub fn contains(&self, digest: &[u8; 20]) -> bool {
+        self.positions(digest).all(|x| self.bits[x / 8] & (1 << (x % 8)) != 0)
+    }
+
+    pub fn write<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        out.write_all(MAGIC)?;
+        out.write_all(&self.hashes.to_be_bytes())?;
+        out.write_all(&self.min_count.to_be_bytes())?;
+        out.write_all(&self.items.to_be_bytes())?;
+        out.write_all(&(self.bits.len() as u64).to_be_bytes())?;
+        out.write_all(&self.bits)
+    }
+
+    fn read<R: Read>(input: &mut R) -> io::Result<BloomFilter> {
+        let mut magic = [0u8; 8];
+        let mut word = [0u8; 4];
+        let mut long = [0u8; 8];
+        input.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Not a breached password filter"));
+        }
+
+        input.read_exact(&mut word)?;
+        let hashes = u32::from_be_bytes(word);
+        input.read_exact(&mut long)?;
+        let min_count = u64::from_be_bytes(long);
+        input.read_exact(&mut long)?;
+        let items = u64::from_be_bytes(long);
+        input.read_exact(&mut long)?;
+        let mut bits = vec![0; u64::from_be_bytes(long) as usize];
+        input.read_exact(&mut bits)?;
+        if bits.is_empty() || hashes == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Empty breached password filter"));
+        }
+
+        Ok(BloomFilter { bits, hashes, min_count, items })
+    }
+}
+
+/// Hash and count of a "SHA1:COUNT" line
+fn parse_line(line: &str) -> Option<([u8; 20], u64)> {
+    let (hash, count) = line.trim().split_once(':')?;
+    let digest: [u8; 20] = hex::decode(hash).ok()?.try_into().ok()?;
+    Some((digest, count.parse().ok()?))
+}
+
+/// Binary search of an ordered HIBP file, returns how many times the hash was seen
+fn search_sorted(path: &str, digest: &[u8; 20]) -> io::Result<u64> {
+    let target = hex::encode_upper(digest);
+    let mut reader = BufReader::new(File::open(path)?);
+    let (mut low, mut high) = (0, reader.get_ref().metadata()?.len());
+    let mut line = String::new();
+
+    while low < high {
+        let middle = low + (high - low) / 2;
+        // Start from the byte before so a line starting at `middle` isn't skipped
+        let mut start = middle;
+        if middle > 0 {
+            reader.seek(SeekFrom::Start(middle - 1))?;
+            line.clear();
+            start = middle - 1 + reader.read_line(&mut line)? as u64;
+        } else {
+            reader.seek(SeekFrom::Start(0))?;
+        }
+
+        line.clear();
+        let read = reader.read_line(&mut line)? as u64;
+        if read == 0 {
+            high = middle;
+            continue;
+        }
+
+        let hash = line.get(..40).unwrap_or_default().to_ascii_uppercase();
+        match hash.as_str().cmp(&target) {
+            Ordering::Equal => return Ok(parse_line(&line).map(|x| x.1).unwrap_or(0)),
+            Ordering::Less => low = start + read,
+            Ordering::Greater => high = middle,
+        }
+    }
+    Ok(0)
+}
+
+/// Fail fast on a missing or invalid dataset
+pub fn check_config() {
+    lazy_static::initialize(&DATASET);
+}
+
+/// Whether the password was seen in BREACHED_PASSWORDS_MIN_COUNT breaches or more.
+/// Always false when no dataset is configured.
+pub fn is_breached(password: &str) -> bool {
+    let digest = sha1(password.as_bytes());
+    match DATASET.as_ref() {
+        Some(Dataset::Filter(filter)) => filter.contains(&digest),
+        Some(Dataset::Sorted(path)) => match search_sorted(path, &digest) {
+            Ok(count) => count > 0 && count >= *BREACHED_PASSWORDS_MIN_COUNT,
+            Err(e) => {
+                // A broken dataset shouldn't block every signup
+                warn!("[{}] -- {} can't be searched: {}", "Breached", path, e);
+                false
+            }
+        },
+        None => false,
+    }
+}
+
+/// Build a filter from an ordered (or not) HIBP file, keeping hashes seen at least `min_count` times
+pub fn build_filter(input: &str, output: &str, min_count: u64, false_positive_rate: f64) -> io::Result<u64> {
+    let lines = || -> io::Result<_> {
+        Ok(BufReader::new(File::open(input)?)
+            .lines()
+            .map_while(Result::ok)
+            .filter_map(|x| parse_line(&x))
+            .filter(move |x| x.1 >= min_count))
+    };
+
+    // Two passes, the filter is sized from the number of hashes kept
+    let items = lines()?.count() as u64;
+    let mut filter = BloomFilter::new(items, false_positive_rate, min_count);
+    for (digest, _count) in lines()? {
+        filter.insert(&digest);
+    }
+
+    let mut out = BufWriter::new(File::create(output)?);
+    filter.write(&mut out)?;
+    out.flush()?;
+    Ok(items)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Ordered like the HIBP file, with its CRLF line endings
+    fn sample(name: &str) -> String {
+        let mut lines: Vec<String> = ["password", "123456", "qwerty", "letmein", "dragon"].iter()
+            .enumerate()
+            .map(|(i, x)| format!("{}:{}", hex::encode_upper(sha1(x.as_bytes())), i + 1))
+            .collect();
+        lines.sort();
+
+        let path = std::env::temp_dir().join(format!("kz-auth-{}-{}.txt", name, std::process::id()));
+        std::fs::write(&path, lines.join("\r\n")).unwrap();
+        path.to_string_lossy().to_string()
+    }
+
+    #[test]
+    fn test_search_sorted() {
+        let path = sample("sorted");
+        assert_eq!(search_sorted(&path, &sha1(b"password")).unwrap(), 1);
+        assert_eq!(search_sorted(&path, &sha1(b"dragon")).unwrap(), 5);
+        assert_eq!(search_sorted(&path, &sha1(b"correct horse")).unwrap(), 0);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_filter() {
+        let path = sample("filter");
+        let output = format!("{}.bin", path);
+        assert_eq!(build_filter(&path, &output, 3, 0.001).unwrap(), 3);
+
+        let filter = BloomFilter::read(&mut File::open(&output).unwrap()).unwrap();
+        assert_eq!(filter.min_count, 3);
+        assert!(filter.contains(&sha1(b"qwerty")));
+        assert!(filter.contains(&sha1(b"dragon")));
+        assert!(!filter.contains(&sha1(b"password")));
+        assert!(!filter.contains(&sha1(b"correct horse")));
+        std::fs::remove_file(path).unwrap();
+        std::fs::remove_file(output).unwrap();
+    }
+}
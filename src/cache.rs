@@ -0,0 +1,31 @@
+use std::time::Duration;
+use lazy_static::lazy_static;
+use r2d2::Pool;
+
+use crate::local_env::*;
+
+lazy_static! {
+    /// None without REDIS_HOST
+    pub static ref REDIS: Option<Pool<redis::Client>> = REDIS_HOST.as_ref().map(|host| {
+        let redis_url = format!("redis://{}:{}", host, *REDIS_PORT);
+
+        let client = redis::Client::open(redis_url).unwrap();
+        // Don't connect before the first use, and give up quickly so requests
+        // that don't need Redis keep working while it is down
+        Pool::builder()
+            .max_size(10)
+            .connection_timeout(Duration::from_secs(2))
+            .build_unchecked(client)
+    });
+}
+
+pub type RedisConnection = r2d2::PooledConnection<redis::Client>;
+
+pub fn get_connection() -> redis::RedisResult<RedisConnection> {
+    let pool = REDIS.as_ref().ok_or_else(|| {
+        redis::RedisError::from((redis::ErrorKind::ClientError, "Redis pool", String::from("REDIS_HOST is not set")))
+    })?;
+    pool.get().map_err(|e| {
+        redis::RedisError::from((redis::ErrorKind::IoError, "Redis pool", e.to_string()))
+    })
+}
@@ -0,0 +1,121 @@
+use std::io::{Error, ErrorKind, Result};
+use std::slice::Iter;
+
+use crate::breached;
+use crate::crypto;
+use crate::oauth;
+use crate::session_keys;
+use crate::users::database;
+
+const USAGE: &str = "Usage:
+    kz-auth create-client <client_id> <name> [--redirect-uri <uri>]... [--scope <scope>]... [--confidential]
+    kz-auth generate-session-key
+    kz-auth generate-mfa-key
+    kz-auth grant-admin <username> [--revoke]
+    kz-auth build-breach-filter <hibp_file> <output> [--min-count <n>] [--false-positive-rate <p>]";
+
+fn usage() -> Error {
+    Error::new(ErrorKind::InvalidInput, USAGE)
+}
+
+fn next_value(args: &mut Iter<String>) -> Result<String> {
+    args.next().cloned().ok_or_else(usage)
+}
+
+/// Administration commands, run instead of the server when arguments are given
+pub fn run(args: &[String]) -> Result<()> {
+    match args.first().map(String::as_str) {
+        Some("create-client") => create_client(&args[1..]),
+        Some("generate-session-key") => {
+            println!("{}", session_keys::generate());
+            Ok(())
+        },
+        Some("generate-mfa-key") => {
+            println!("{}", crypto::generate_key());
+            Ok(())
+        },
+        Some("grant-admin") => grant_admin(&args[1..]),
+        Some("build-breach-filter") => build_breach_filter(&args[1..]),
+        _ => Err(usage()),
+    }
+}
+
+fn grant_admin(args: &[String]) -> Result<()> {
+    let (username, admin) = match args {
+        [username] => (username, true),
+        [username, flag] if flag == "--revoke" => (username, false),
+        _ => return Err(usage()),
+    };
+
+    match database::set_admin(username, admin) {
+        Ok(true) => {
+            println!("{}: admin {}", username, if admin { "granted" } else { "revoked" });
+            Ok(())
+        },
+        Ok(false) => Err(Error::new(ErrorKind::NotFound, format!("Unknown user {}", username))),
+        Err(e) => Err(Error::other(e.to_string())),
+    }
+}
+
+fn create_client(args: &[String]) -> Result<()> {
+    let mut positional = Vec::new();
+    let mut redirect_uris = Vec::new();
+    let mut scopes = Vec::new();
+    let mut confidential = false;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--redirect-uri" => redirect_uris.push(next_value(&mut args)?),
+            "--scope" => scopes.push(next_value(&mut args)?),
+            "--confidential" => confidential = true,
+            _ => positional.push(arg.as_str()),
+        }
+    }
+
+    let (client_id, name) = match positional.as_slice() {
+        [client_id, name] => (*client_id, *name),
+        _ => return Err(usage()),
+    };
+
+    match oauth::create_client(client_id, name, &redirect_uris, &scopes, confidential) {
+        Ok(Some(secret)) => {
+            // Only time the secret is shown, it is stored hashed
+            println!("client_id: {}", client_id);
+            println!("client_secret: {}", secret);
+            Ok(())
+        },
+        Ok(None) => {
+            println!("client_id: {}", client_id);
+            Ok(())
+        },
+        Err(e) => Err(Error::other(e.to_string())),
+    }
+}
+
+fn build_breach_filter(args: &[String]) -> Result<()> {
+    let mut positional = Vec::new();
+    let mut min_count = 1;
+    let mut false_positive_rate = 0.001;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--min-count" => min_count = next_value(&mut args)?.parse().map_err(|_e| usage())?,
+            "--false-positive-rate" => false_positive_rate = next_value(&mut args)?.parse().map_err(|_e| usage())?,
+            _ => positional.push(arg.as_str()),
+        }
+    }
+
+    let (input, output) = match positional.as_slice() {
+        [input, output] => (*input, *output),
+        _ => return Err(usage()),
+    };
+    if !(0.0..1.0).contains(&false_positive_rate) || false_positive_rate == 0.0 {
+        return Err(usage());
+    }
+
+    let items = breached::build_filter(input, output, min_count, false_positive_rate)?;
+    println!("{}: {} hashes seen at least {} times", output, items, min_count);
+    Ok(())
+}
@@ -0,0 +1,125 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
+use lazy_static::lazy_static;
+use log::warn;
+use openssl::{
+    error::ErrorStack,
+    hash::MessageDigest,
+    pkey::PKey,
+    rand::rand_bytes,
+    sign::Signer,
+    symm::{decrypt_aead, encrypt_aead, Cipher}
+};
+
+use crate::local_env::MFA_ENCRYPTION_KEY;
+
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+
+lazy_static! {
+    static ref KEY: Option<Vec<u8>> = MFA_ENCRYPTION_KEY.as_ref().map(|x| parse_key(x));
+}
+
+fn parse_key(data: &str) -> Vec<u8> {
+    let key = STANDARD.decode(data.trim()).unwrap_or_else(|e| {
+        panic!("[{}] -- Can't decode MFA_ENCRYPTION_KEY: {}", "Crypto", e);
+    });
+    if key.len() != KEY_LEN {
+        panic!("[{}] -- Invalid MFA_ENCRYPTION_KEY, {} bytes are required", "Crypto", KEY_LEN);
+    }
+    key
+}
+
+pub fn check_key() {
+    lazy_static::initialize(&KEY);
+    if KEY.is_none() {
+        warn!("[{}] -- No MFA_ENCRYPTION_KEY, second factor enrollment is disabled", "Crypto");
+    }
+}
+
+/// Whether second factor secrets can be stored
+pub fn is_enabled() -> bool {
+    KEY.is_some()
+}
+
+/// Random key, base64 encoded, suitable for MFA_ENCRYPTION_KEY
+pub fn generate_key() -> String {
+    let mut key = [0u8; KEY_LEN];
+    rand_bytes(&mut key).unwrap();
+    STANDARD.encode(key)
+}
+
+fn seal(key: &[u8], plaintext: &[u8], context: &str) -> Result<String, ErrorStack> {
+    let mut nonce = [0u8; NONCE_LEN];
+    rand_bytes(&mut nonce)?;
+    let mut tag = [0u8; TAG_LEN];
+    let ciphertext = encrypt_aead(Cipher::aes_256_gcm(), key, Some(&nonce), context.as_bytes(), plaintext, &mut tag)?;
+
+    Ok(STANDARD.encode([&nonce[..], &ciphertext, &tag].concat()))
+}
+
+fn open(key: &[u8], sealed: &str, context: &str) -> Option<Vec<u8>> {
+    let data = STANDARD.decode(sealed).ok()?;
+    if data.len() < NONCE_LEN + TAG_LEN {
+        return None;
+    }
+    let (nonce, rest) = data.split_at(NONCE_LEN);
+    let (ciphertext, tag) = rest.split_at(rest.len() - TAG_LEN);
+
+    decrypt_aead(Cipher::aes_256_gcm(), key, Some(nonce), context.as_bytes(), ciphertext, tag).ok()
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> Result<Vec<u8>, ErrorStack> {
+    let key = PKey::hmac(key)?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+    signer.update(data)?;
+    signer.sign_to_vec()
+}
+
+/// HMAC-SHA256 keyed with a subkey of `key`, one per context so the AES key isn't reused
+fn keyed_hash(key: &[u8], data: &[u8], context: &str) -> Result<String, ErrorStack> {
+    let subkey = hmac(&hmac(key, b"kz-auth:mac")?, context.as_bytes())?;
+    Ok(hex::encode(hmac(&subkey, data)?))
+}
+
+/// Encrypt a secret with MFA_ENCRYPTION_KEY, `context` binds it to its owner.
+/// None when no key is configured.
+pub fn encrypt(plaintext: &[u8], context: &str) -> Option<Result<String, ErrorStack>> {
+    KEY.as_ref().map(|key| seal(key, plaintext, context))
+}
+
+/// None when no key is configured or the secret was tampered with
+pub fn decrypt(sealed: &str, context: &str) -> Option<Vec<u8>> {
+    KEY.as_ref().and_then(|key| open(key, sealed, context))
+}
+
+/// Hex encoded hash of a secret looked up by its value, keyed with MFA_ENCRYPTION_KEY
+/// so a database dump isn't enough to test guesses. None when no key is configured.
+pub fn hash(data: &[u8], context: &str) -> Option<Result<String, ErrorStack>> {
+    KEY.as_ref().map(|key| keyed_hash(key, data, context))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seal_open() {
+        let key = parse_key(&generate_key());
+        let sealed = seal(&key, b"secret", "totp:1").unwrap();
+
+        assert_eq!(open(&key, &sealed, "totp:1").as_deref(), Some(&b"secret"[..]));
+        assert_eq!(open(&key, &sealed, "totp:2"), None);
+    }
+
+    #[test]
+    fn test_keyed_hash() {
+        let key = parse_key(&generate_key());
+        let hash = keyed_hash(&key, b"code", "recovery:1").unwrap();
+
+        assert_eq!(hash.len(), 64);
+        assert_eq!(keyed_hash(&key, b"code", "recovery:1").unwrap(), hash);
+        assert_ne!(keyed_hash(&key, b"code", "recovery:2").unwrap(), hash);
+        assert_ne!(keyed_hash(&parse_key(&generate_key()), b"code", "recovery:1").unwrap(), hash);
+    }
+}
@@ -1,6 +1,3 @@
-use std::{
-    env,
-};
 use lazy_static::lazy_static;
 use dotenv::dotenv;
 use diesel::{
@@ -12,16 +12,43 @@ use argon2::{
     PasswordHasher,
     PasswordHash
 };
+use lazy_static::lazy_static;
+use openssl::{rand::rand_bytes, sha::sha256};
+
+use crate::local_env::*;
 
 
 macro_rules! get_argon {
     () => {
-        // m_cost, t_cost, p_cost, output_len
-        // memory size, number of iterations, parallelism, output length
-        Argon2::new(Algorithm::Argon2id, Version::V0x13, Params::new(2048, 4, 1, None).unwrap())
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, PARAMS.clone())
     };
 }
 
+lazy_static! {
+    /// m_cost, t_cost, p_cost, output_len
+    /// memory size, number of iterations, parallelism, output length
+    static ref PARAMS: Params = Params::new(*ARGON2_M_COST, *ARGON2_T_COST, *ARGON2_P_COST, None).unwrap_or_else(|e| {
+        panic!("Invalid ARGON2_M_COST, ARGON2_T_COST or ARGON2_P_COST: {}", e);
+    });
+    /// Hash of a random password, checked when the login matches no user
+    static ref DUMMY_HASH: String = generate_hash(&generate_token());
+}
+
+/// Fail fast on invalid parameters
+pub fn check_params() {
+    lazy_static::initialize(&PARAMS);
+}
+
+/// Build the dummy hash at startup rather than during the first failed login
+pub fn init_dummy_hash() {
+    lazy_static::initialize(&DUMMY_HASH);
+}
+
+/// Spend the same time as `verify_password` for a login that matches no user.
+/// The dummy hash uses the current parameters, it can't match older and cheaper hashes.
+pub fn verify_dummy(password: &[u8]) {
+    let _ = verify_password(password, &DUMMY_HASH);
+}
 
 pub fn verify_password(password: &[u8], hashed_password: &str) -> Result<bool, Error> {
     let hash = PasswordHash::new(hashed_password).unwrap();
@@ -30,6 +57,24 @@ pub fn verify_password(password: &[u8], hashed_password: &str) -> Result<bool, E
     Ok(true)
 }
 
+/// Whether a hash was made with another algorithm or weaker parameters than the current ones
+pub fn needs_rehash(hashed_password: &str) -> bool {
+    let hash = match PasswordHash::new(hashed_password) {
+        Ok(x) => x,
+        Err(_e) => return false,
+    };
+    let params = match Params::try_from(&hash) {
+        Ok(x) => x,
+        Err(_e) => return true,
+    };
+
+    hash.algorithm != Algorithm::Argon2id.ident()
+        || hash.version != Some(Version::V0x13.into())
+        || params.m_cost() < PARAMS.m_cost()
+        || params.t_cost() < PARAMS.t_cost()
+        || params.p_cost() < PARAMS.p_cost()
+}
+
 pub fn generate_hash(data: &str) -> String {
     let argon2 = get_argon!();
     let data = data.as_bytes();
@@ -38,6 +83,54 @@ pub fn generate_hash(data: &str) -> String {
     hash.to_string()
 }
 
+/// Random opaque token, hex encoded
+pub fn generate_token() -> String {
+    let mut buf = [0u8; 32];
+    rand_bytes(&mut buf).unwrap();
+    hex::encode(buf)
+}
+
+/// SHA-256 digest of a token, used to store opaque tokens without keeping them in clear
+pub fn hash_token(token: &str) -> String {
+    hex::encode(sha256(token.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dummy_hash_params() {
+        let dummy = PasswordHash::new(&DUMMY_HASH).unwrap();
+        let real = generate_hash("password");
+        let real = PasswordHash::new(&real).unwrap();
+
+        assert_eq!(dummy.algorithm, real.algorithm);
+        assert_eq!(dummy.version, real.version);
+        assert_eq!(dummy.params, real.params);
+        assert!(verify_password(b"password", &DUMMY_HASH).is_err());
+    }
+
+    #[test]
+    fn test_needs_rehash() {
+        let salt = SaltString::generate(&mut OsRng);
+        let weak = Argon2::new(Algorithm::Argon2id, Version::V0x13, Params::new(2048, 1, 1, None).unwrap())
+            .hash_password(b"password", &salt)
+            .unwrap()
+            .to_string();
+        let argon2i = Argon2::new(Algorithm::Argon2i, Version::V0x13, PARAMS.clone())
+            .hash_password(b"password", &salt)
+            .unwrap()
+            .to_string();
+
+        assert!(needs_rehash(&weak));
+        assert!(needs_rehash(&argon2i));
+        assert!(!needs_rehash(&generate_hash("password")));
+        // Old hashes still verify
+        assert!(verify_password(b"password", &weak).unwrap());
+    }
+}
+
 // #[allow(unused_variables)]
 // #[test]
 // fn test() {
@@ -1,4 +1,4 @@
-use actix_web::{get, post, web, App, HttpResponse, HttpServer, Responder, HttpRequest};
+use actix_web::{HttpResponse, HttpRequest};
 
 
 pub async fn check(_req: HttpRequest) -> HttpResponse {
@@ -0,0 +1,359 @@
+use std::fs;
+use chrono::Utc;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use jsonwebtoken::{
+    encode,
+    decode,
+    errors::Error,
+    Algorithm,
+    DecodingKey,
+    EncodingKey,
+    Header,
+    Validation
+};
+use lazy_static::lazy_static;
+use openssl::{pkey::PKey, sha::sha256};
+use serde::{de::DeserializeOwned, Serialize, Deserialize};
+use serde_json::{json, Value};
+
+use crate::hashing::generate_token;
+use crate::local_env::*;
+
+lazy_static! {
+    static ref KEYS: Keys = Keys::load();
+}
+
+pub fn check_keys() {
+    lazy_static::initialize(&KEYS);
+    if *OIDC_ENABLED && KEYS.jwk.is_none() {
+        panic!("[{}] -- OpenID Connect needs JWT_PRIVATE_KEY, ID tokens signed with JWT_SECRET can't be verified by relying parties. Unset OIDC_ENABLED to run without it", "Jwt");
+    }
+}
+
+struct Keys {
+    algorithm: Algorithm,
+    encoding: EncodingKey,
+    decoding: DecodingKey,
+    kid: String,
+    /// Public half of the signing key, only for asymmetric algorithms
+    jwk: Option<Value>,
+}
+
+impl Keys {
+    fn load() -> Keys {
+        if let Some(path) = JWT_PRIVATE_KEY.as_ref() {
+            let pem = fs::read(path).unwrap_or_else(|e| {
+                panic!("[{}] -- Can't read JWT_PRIVATE_KEY {}: {}", "Jwt", path, e);
+            });
+            Keys::from_rsa_pem(&pem)
+        } else if let Some(secret) = JWT_SECRET.as_ref() {
+            Keys::from_secret(secret.as_bytes())
+        } else {
+            panic!("[{}] -- JWT_PRIVATE_KEY or JWT_SECRET environment variable not defined", "Jwt");
+        }
+    }
+
+    fn from_rsa_pem(pem: &[u8]) -> Keys {
+        let rsa = PKey::private_key_from_pem(pem).and_then(|x| x.rsa()).unwrap_or_else(|e| {
+            panic!("[{}] -- Can't parse JWT_PRIVATE_KEY: {}", "Jwt", e);
+        });
+        let public_der = rsa.public_key_to_der().unwrap();
+        let n = URL_SAFE_NO_PAD.encode(rsa.n().to_vec());
+        let e = URL_SAFE_NO_PAD.encode(rsa.e().to_vec());
+        let kid = hex::encode(&sha256(&public_der)[..8]);
+
+        Keys {
+            algorithm: Algorithm::RS256,
+            encoding: EncodingKey::from_rsa_pem(pem).unwrap(),
+            decoding: DecodingKey::from_rsa_components(&n, &e).unwrap(),
+            jwk: Some(json!({
+                "kty": "RSA",
+                "use": "sig",
+                "alg": "RS256",
+                "kid": kid,
+                "n": n,
+                "e": e,
+            })),
+            kid,
+        }
+    }
+
+    fn from_secret(secret: &[u8]) -> Keys {
+        Keys {
+            algorithm: Algorithm::HS256,
+            encoding: EncodingKey::from_secret(secret),
+            decoding: DecodingKey::from_secret(secret),
+            kid: hex::encode(&sha256(secret)[..8]),
+            jwk: None,
+        }
+    }
+
+    fn header(&self) -> Header {
+        let mut header = Header::new(self.algorithm);
+        header.kid = Some(self.kid.clone());
+        header
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Claims {
+    /// Unique token id, used to revoke the token before it expires
+    #[serde(default)]
+    pub jti: String,
+    pub sub: String,
+    pub iat: i64,
+    pub exp: i64,
+    pub iss: String,
+    pub aud: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scope: Option<String>,
+    /// OAuth client the token was issued to, on behalf of a user or its own.
+    /// None for first party logins.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_id: Option<String>,
+    /// Set when the client obtained the token for itself: the subject is the client, not a user
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub client_credentials: bool,
+}
+
+impl Claims {
+    fn new(subject: &str, scope: Option<&str>) -> Claims {
+        let now = Utc::now().timestamp();
+        Claims {
+            jti: generate_token(),
+            sub: subject.to_string(),
+            iat: now,
+            exp: now + *JWT_ACCESS_TOKEN_TTL,
+            iss: JWT_ISSUER.to_string(),
+            aud: JWT_AUDIENCE.to_string(),
+            scope: scope.map(String::from),
+            client_id: None,
+            client_credentials: false,
+        }
+    }
+
+    /// User the token was issued for, None for a client's own token
+    pub fn user_id(&self) -> Option<i32> {
+        if self.client_credentials {
+            return None;
+        }
+        self.sub.parse().ok()
+    }
+}
+
+/// OpenID Connect ID token
+#[derive(Serialize, Deserialize, Debug)]
+pub struct IdClaims {
+    pub sub: String,
+    pub iat: i64,
+    pub exp: i64,
+    pub iss: String,
+    /// The client the token was issued to
+    pub aud: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nonce: Option<String>,
+}
+
+impl IdClaims {
+    fn new(subject: &str, client_id: &str, nonce: Option<&str>) -> IdClaims {
+        let now = Utc::now().timestamp();
+        IdClaims {
+            sub: subject.to_string(),
+            iat: now,
+            exp: now + *JWT_ACCESS_TOKEN_TTL,
+            iss: JWT_ISSUER.to_string(),
+            aud: client_id.to_string(),
+            nonce: nonce.map(String::from),
+        }
+    }
+}
+
+/// Token sent by email, only valid for one purpose ("verify-email"...)
+#[derive(Serialize, Deserialize, Debug)]
+pub struct EmailClaims {
+    pub jti: String,
+    pub sub: String,
+    /// The address the token was sent to, the token is void once it changes
+    pub email: String,
+    pub iat: i64,
+    pub exp: i64,
+    pub iss: String,
+    pub aud: String,
+}
+
+/// Email tokens can't be mistaken for access tokens, or for each other
+fn email_audience(purpose: &str) -> String {
+    format!("{}:{}", *JWT_AUDIENCE, purpose)
+}
+
+impl EmailClaims {
+    fn new(subject: &str, email: &str, purpose: &str, ttl: i64) -> EmailClaims {
+        let now = Utc::now().timestamp();
+        EmailClaims {
+            jti: generate_token(),
+            sub: subject.to_string(),
+            email: email.to_string(),
+            iat: now,
+            exp: now + ttl,
+            iss: JWT_ISSUER.to_string(),
+            aud: email_audience(purpose),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct TokenResponse {
+    pub access_token: String,
+    pub token_type: &'static str,
+    pub expires_in: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub refresh_token: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scope: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id_token: Option<String>,
+}
+
+impl TokenResponse {
+    pub fn bearer(access_token: String, refresh_token: Option<String>) -> TokenResponse {
+        TokenResponse {
+            access_token,
+            token_type: "Bearer",
+            expires_in: *JWT_ACCESS_TOKEN_TTL,
+            refresh_token,
+            scope: None,
+            id_token: None,
+        }
+    }
+
+    pub fn with_scope(mut self, scope: Option<String>) -> TokenResponse {
+        self.scope = scope;
+        self
+    }
+
+    pub fn with_id_token(mut self, id_token: Option<String>) -> TokenResponse {
+        self.id_token = id_token;
+        self
+    }
+}
+
+fn encode_claims<T: Serialize>(keys: &Keys, claims: &T) -> Result<String, Error> {
+    encode(&keys.header(), claims, &keys.encoding)
+}
+
+fn decode_claims<T: DeserializeOwned>(keys: &Keys, token: &str, audience: &str) -> Result<T, Error> {
+    let mut validation = Validation::new(keys.algorithm);
+    validation.set_issuer(&[JWT_ISSUER.as_str()]);
+    validation.set_audience(&[audience]);
+
+    let data = decode::<T>(token, &keys.decoding, &validation)?;
+    Ok(data.claims)
+}
+
+/// Sign a new access token for the given subject (user id), optionally restricted to a scope.
+/// `client_id` is the OAuth client it is issued to, none for first party logins.
+pub fn issue_access_token(subject: &str, client_id: Option<&str>, scope: Option<&str>) -> Result<String, Error> {
+    let mut claims = Claims::new(subject, scope);
+    claims.client_id = client_id.map(String::from);
+    encode_claims(&KEYS, &claims)
+}
+
+/// Sign a new access token for a client authenticated with its own credentials
+pub fn issue_client_access_token(client_id: &str, scope: Option<&str>) -> Result<String, Error> {
+    let mut claims = Claims::new(client_id, scope);
+    claims.client_id = Some(client_id.to_string());
+    claims.client_credentials = true;
+    encode_claims(&KEYS, &claims)
+}
+
+/// Verify signature, expiration, issuer and audience of an access token
+pub fn decode_access_token(token: &str) -> Result<Claims, Error> {
+    decode_claims(&KEYS, token, &JWT_AUDIENCE)
+}
+
+/// Sign a token to be emailed to the user (id) at the given address.
+/// The claims are returned along so single-use tokens can record their jti.
+pub fn issue_email_token(subject: &str, email: &str, purpose: &str, ttl: i64) -> Result<(String, EmailClaims), Error> {
+    let claims = EmailClaims::new(subject, email, purpose, ttl);
+    Ok((encode_claims(&KEYS, &claims)?, claims))
+}
+
+/// Verify signature, expiration, issuer and purpose of an email token
+pub fn decode_email_token(token: &str, purpose: &str) -> Result<EmailClaims, Error> {
+    decode_claims(&KEYS, token, &email_audience(purpose))
+}
+
+/// Sign an OpenID Connect ID token for the given subject and client
+pub fn issue_id_token(subject: &str, client_id: &str, nonce: Option<&str>) -> Result<String, Error> {
+    encode_claims(&KEYS, &IdClaims::new(subject, client_id, nonce))
+}
+
+/// Name of the signing algorithm, as advertised in the discovery document
+pub fn algorithm() -> &'static str {
+    match KEYS.algorithm {
+        Algorithm::RS256 => "RS256",
+        Algorithm::HS256 => "HS256",
+        x => unreachable!("Keys are only loaded for RS256 and HS256, not {:?}", x),
+    }
+}
+
+/// JSON Web Key Set publishing the public signing keys, empty for shared secrets
+pub fn jwks() -> Value {
+    json!({ "keys": KEYS.jwk.iter().collect::<Vec<_>>() })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_access_token_roundtrip() {
+        let keys = Keys::from_secret(b"secret");
+        let token = encode_claims(&keys, &Claims::new("42", None)).unwrap();
+        let claims: Claims = decode_claims(&keys, &token, &JWT_AUDIENCE).unwrap();
+        assert_eq!(claims.sub, "42");
+        assert_eq!(claims.exp - claims.iat, *JWT_ACCESS_TOKEN_TTL);
+    }
+
+    #[test]
+    fn test_client_token_has_no_user() {
+        let mut claims = Claims::new("42", None);
+        assert_eq!(claims.user_id(), Some(42));
+
+        // A client named like a user id
+        claims.client_id = Some(String::from("42"));
+        claims.client_credentials = true;
+        let keys = Keys::from_secret(b"secret");
+        let claims: Claims = decode_claims(&keys, &encode_claims(&keys, &claims).unwrap(), &JWT_AUDIENCE).unwrap();
+        assert_eq!(claims.user_id(), None);
+    }
+
+    #[test]
+    fn test_access_token_wrong_key() {
+        let token = encode_claims(&Keys::from_secret(b"secret"), &Claims::new("42", None)).unwrap();
+        assert!(decode_claims::<Claims>(&Keys::from_secret(b"other"), &token, &JWT_AUDIENCE).is_err());
+    }
+
+    #[test]
+    fn test_email_token_purpose() {
+        let keys = Keys::from_secret(b"secret");
+        let token = encode_claims(&keys, &EmailClaims::new("42", "a@example.com", "verify-email", 60)).unwrap();
+
+        let claims: EmailClaims = decode_claims(&keys, &token, &email_audience("verify-email")).unwrap();
+        assert_eq!(claims.email, "a@example.com");
+        assert!(decode_claims::<EmailClaims>(&keys, &token, &email_audience("reset-password")).is_err());
+        assert!(decode_claims::<Claims>(&keys, &token, &JWT_AUDIENCE).is_err());
+    }
+
+    #[test]
+    fn test_rsa_jwk() {
+        let keys = Keys::from_rsa_pem(&fs::read("key.pem").unwrap());
+        let token = encode_claims(&keys, &Claims::new("42", Some("openid"))).unwrap();
+        let claims: Claims = decode_claims(&keys, &token, &JWT_AUDIENCE).unwrap();
+        assert_eq!(claims.scope.as_deref(), Some("openid"));
+
+        let jwk = keys.jwk.unwrap();
+        assert_eq!(jwk["kid"], keys.kid);
+        assert_eq!(jwk["e"], "AQAB");
+    }
+}
@@ -1,7 +1,7 @@
 use core::panic;
 use std::{net::Ipv4Addr, env, str::FromStr};
-use log::{error, warn, info, debug, trace, LevelFilter};
 
+use actix_web::cookie::SameSite;
 use lazy_static::lazy_static;
 
 fn var_not_defined(var: &str) -> String {
@@ -19,50 +19,419 @@ pub fn check_vars() {
     lazy_static::initialize(&DB_PARAMS);
     lazy_static::initialize(&REDIS_HOST);
     lazy_static::initialize(&REDIS_PORT);
+    lazy_static::initialize(&JWT_SECRET);
+    lazy_static::initialize(&JWT_PRIVATE_KEY);
+    lazy_static::initialize(&JWT_ISSUER);
+    lazy_static::initialize(&JWT_AUDIENCE);
+    lazy_static::initialize(&JWT_ACCESS_TOKEN_TTL);
+    lazy_static::initialize(&REFRESH_TOKEN_TTL);
+    lazy_static::initialize(&OAUTH_CODE_TTL);
+    lazy_static::initialize(&SESSION_BACKEND);
+    lazy_static::initialize(&SESSION_KEY);
+    lazy_static::initialize(&SESSION_KEY_FILE);
+    lazy_static::initialize(&SESSION_KEY_RANDOM);
+    lazy_static::initialize(&SESSION_PREVIOUS_KEYS);
+    lazy_static::initialize(&SESSION_PREVIOUS_KEYS_UNTIL);
+    lazy_static::initialize(&SESSION_COOKIE_NAME);
+    lazy_static::initialize(&SESSION_COOKIE_DOMAIN);
+    lazy_static::initialize(&SESSION_COOKIE_PATH);
+    lazy_static::initialize(&SESSION_COOKIE_SAME_SITE);
+    lazy_static::initialize(&SESSION_COOKIE_SECURE);
+    lazy_static::initialize(&SESSION_COOKIE_PERSISTENT);
+    lazy_static::initialize(&SESSION_IDLE_TIMEOUT);
+    lazy_static::initialize(&SESSION_MAX_LIFETIME);
+    lazy_static::initialize(&MFA_ENCRYPTION_KEY);
+    lazy_static::initialize(&TOTP_ISSUER);
+    lazy_static::initialize(&WEBAUTHN_RP_ID);
+    lazy_static::initialize(&WEBAUTHN_RP_NAME);
+    lazy_static::initialize(&WEBAUTHN_ORIGIN);
+    lazy_static::initialize(&PUBLIC_URL);
+    lazy_static::initialize(&MAILER_KIND);
+    lazy_static::initialize(&MAIL_FROM);
+    lazy_static::initialize(&MAILER_FILE);
+    lazy_static::initialize(&SMTP_HOST);
+    lazy_static::initialize(&SMTP_PORT);
+    lazy_static::initialize(&SMTP_TLS);
+    lazy_static::initialize(&SMTP_USERNAME);
+    lazy_static::initialize(&SMTP_PASSWORD);
+    lazy_static::initialize(&EMAIL_VERIFICATION_TTL);
+    lazy_static::initialize(&REQUIRE_VERIFIED_EMAIL);
+    lazy_static::initialize(&PASSWORD_RESET_TTL);
+    lazy_static::initialize(&MAGIC_LINK_TTL);
+    lazy_static::initialize(&EMAIL_OTP_TTL);
+    lazy_static::initialize(&EMAIL_OTP_MAX_ATTEMPTS);
+    lazy_static::initialize(&LOCKOUT_THRESHOLD);
+    lazy_static::initialize(&LOCKOUT_DURATION);
+    lazy_static::initialize(&RATE_LIMIT_ENABLED);
+    lazy_static::initialize(&RATE_LIMIT_TRUST_PROXY);
+    lazy_static::initialize(&RATE_LIMIT_IP_BURST);
+    lazy_static::initialize(&RATE_LIMIT_IP_PER_MINUTE);
+    lazy_static::initialize(&RATE_LIMIT_LOGIN_BURST);
+    lazy_static::initialize(&RATE_LIMIT_LOGIN_PER_MINUTE);
+    lazy_static::initialize(&RATE_LIMIT_FAIL_OPEN);
+    lazy_static::initialize(&PASSWORD_MIN_LENGTH);
+    lazy_static::initialize(&PASSWORD_MAX_LENGTH);
+    lazy_static::initialize(&PASSWORD_REQUIRED_CLASSES);
+    lazy_static::initialize(&PASSWORD_MIN_SCORE);
+    lazy_static::initialize(&BREACHED_PASSWORDS_FILE);
+    lazy_static::initialize(&BREACHED_PASSWORDS_MIN_COUNT);
+    lazy_static::initialize(&ARGON2_M_COST);
+    lazy_static::initialize(&ARGON2_T_COST);
+    lazy_static::initialize(&ARGON2_P_COST);
+    lazy_static::initialize(&OIDC_ENABLED);
 }
 
 lazy_static! {
     /// Service
-    pub static ref HOST: Ipv4Addr = Ipv4Addr::from_str(env::var("HOST").unwrap_or_else(|e| {
+    pub static ref HOST: Ipv4Addr = Ipv4Addr::from_str(env::var("HOST").unwrap_or_else(|_e| {
         panic!("{}", var_not_defined("HOST"));
     }).as_str()).unwrap();
-    pub static ref PORT: u16 = env::var("PORT").unwrap_or_else(|e| {
+    pub static ref PORT: u16 = env::var("PORT").unwrap_or_else(|_e| {
         panic!("{}", var_not_defined("HOST"));
     }).parse().unwrap_or_else(|e| {
         panic!("Can't parse PORT {}", e);
     });
 
     /// Database
-    pub static ref DB_PASSWORD: String = env::var("DB_PASSWORD").unwrap_or_else(|e| {
+    pub static ref DB_PASSWORD: String = env::var("DB_PASSWORD").unwrap_or_else(|_e| {
         panic!("{}", var_not_defined("DB_PASSWORD"));
     });
-    pub static ref DB_USERNAME: String = env::var("DB_USERNAME").unwrap_or_else(|e| {
+    pub static ref DB_USERNAME: String = env::var("DB_USERNAME").unwrap_or_else(|_e| {
         panic!("{}", var_not_defined("DB_USERNAME"));
     });
-    pub static ref DB_HOST: String = env::var("DB_HOST").unwrap_or_else(|e| {
+    pub static ref DB_HOST: String = env::var("DB_HOST").unwrap_or_else(|_e| {
         panic!("{}", var_not_defined("DB_HOST"));
     });
-    pub static ref DB_PORT: u16 = env::var("DB_PORT").unwrap_or_else(|e| {
+    pub static ref DB_PORT: u16 = env::var("DB_PORT").unwrap_or_else(|_e| {
         panic!("{}", var_not_defined("DB_PORT"));
     }).parse().unwrap_or_else(|e| {
         panic!("Can't parse DB_PORT {}", e);
     });
 
-    pub static ref DB_DATABASE: String = env::var("DB_DATABASE").unwrap_or_else(|e| {
+    pub static ref DB_DATABASE: String = env::var("DB_DATABASE").unwrap_or_else(|_e| {
         panic!("{}", var_not_defined("DB_DATABASE"));
     });
-    pub static ref DB_PARAMS: String = env::var("DB_PARAMS").unwrap_or_else(|e| {
+    pub static ref DB_PARAMS: String = env::var("DB_PARAMS").unwrap_or_else(|_e| {
         panic!("{}", var_not_defined("DB_PARAMS"));
     });
 
     /// Redis
-    pub static ref REDIS_HOST: String = env::var("REDIS_HOST").unwrap_or_else(|e| {
-        panic!("{}", var_not_defined("REDIS_HOST"));
-    });
-    pub static ref REDIS_PORT: u16 = env::var("REDIS_PORT").unwrap_or_else(|e| {
-        panic!("{}", var_not_defined("REDIS_PORT"));
+    /// Needed by the redis session backend and rate limiting, revoked access tokens
+    /// are shared through it when set
+    pub static ref REDIS_HOST: Option<String> = env::var("REDIS_HOST").ok();
+    pub static ref REDIS_PORT: u16 = env::var("REDIS_PORT").unwrap_or_else(|_e| {
+        String::from("6379")
     }).parse().unwrap_or_else(|e| {
         panic!("Can't parse REDIS_PORT {}", e);
     });
 
+    /// JWT
+    /// Either a path to an RSA private key (RS256) or a shared secret (HS256) must be set
+    pub static ref JWT_PRIVATE_KEY: Option<String> = env::var("JWT_PRIVATE_KEY").ok();
+    pub static ref JWT_SECRET: Option<String> = env::var("JWT_SECRET").ok();
+    /// Must be an https URL with OpenID Connect, relying parties fetch its discovery document
+    pub static ref JWT_ISSUER: String = env::var("JWT_ISSUER").unwrap_or_else(|_e| {
+        String::from("kz-auth")
+    });
+    pub static ref JWT_AUDIENCE: String = env::var("JWT_AUDIENCE").unwrap_or_else(|_e| {
+        String::from("kz-auth")
+    });
+    /// Access token lifetime, in seconds
+    pub static ref JWT_ACCESS_TOKEN_TTL: i64 = env::var("JWT_ACCESS_TOKEN_TTL").unwrap_or_else(|_e| {
+        String::from("900")
+    }).parse().unwrap_or_else(|e| {
+        panic!("Can't parse JWT_ACCESS_TOKEN_TTL {}", e);
+    });
+    /// Refresh token lifetime, in seconds
+    pub static ref REFRESH_TOKEN_TTL: i64 = env::var("REFRESH_TOKEN_TTL").unwrap_or_else(|_e| {
+        String::from("2592000")
+    }).parse().unwrap_or_else(|e| {
+        panic!("Can't parse REFRESH_TOKEN_TTL {}", e);
+    });
+
+    /// OAuth
+    /// Authorization code lifetime, in seconds
+    pub static ref OAUTH_CODE_TTL: i64 = env::var("OAUTH_CODE_TTL").unwrap_or_else(|_e| {
+        String::from("60")
+    }).parse().unwrap_or_else(|e| {
+        panic!("Can't parse OAUTH_CODE_TTL {}", e);
+    });
+
+    /// Session
+    /// Storage: redis, memory or cookie
+    pub static ref SESSION_BACKEND: String = env::var("SESSION_BACKEND").unwrap_or_else(|_e| {
+        String::from("redis")
+    });
+    /// Cookie key, base64 encoded (at least 64 bytes), either inline or in a file
+    pub static ref SESSION_KEY: Option<String> = env::var("SESSION_KEY").ok();
+    pub static ref SESSION_KEY_FILE: Option<String> = env::var("SESSION_KEY_FILE").ok();
+    /// Development only, use a random key when none is set. Sessions won't survive a restart
+    pub static ref SESSION_KEY_RANDOM: bool = env::var("SESSION_KEY_RANDOM").unwrap_or_else(|_e| {
+        String::from("false")
+    }).parse().unwrap_or_else(|e| {
+        panic!("Can't parse SESSION_KEY_RANDOM {}", e);
+    });
+    /// Comma separated keys still accepted after a rotation, until SESSION_PREVIOUS_KEYS_UNTIL (RFC 3339).
+    /// The deadline is required with previous keys, SESSION_MAX_LIFETIME after the rotation is enough.
+    pub static ref SESSION_PREVIOUS_KEYS: Option<String> = env::var("SESSION_PREVIOUS_KEYS").ok();
+    pub static ref SESSION_PREVIOUS_KEYS_UNTIL: Option<String> = env::var("SESSION_PREVIOUS_KEYS_UNTIL").ok();
+    /// Cookie attributes
+    pub static ref SESSION_COOKIE_NAME: String = env::var("SESSION_COOKIE_NAME").unwrap_or_else(|_e| {
+        String::from("id")
+    });
+    pub static ref SESSION_COOKIE_DOMAIN: Option<String> = env::var("SESSION_COOKIE_DOMAIN").ok();
+    pub static ref SESSION_COOKIE_PATH: String = env::var("SESSION_COOKIE_PATH").unwrap_or_else(|_e| {
+        String::from("/")
+    });
+    /// strict, lax or none
+    pub static ref SESSION_COOKIE_SAME_SITE: SameSite = match env::var("SESSION_COOKIE_SAME_SITE").unwrap_or_else(|_e| {
+        String::from("lax")
+    }).to_lowercase().as_str() {
+        "strict" => SameSite::Strict,
+        "lax" => SameSite::Lax,
+        "none" => SameSite::None,
+        x => panic!("Can't parse SESSION_COOKIE_SAME_SITE {}", x),
+    };
+    pub static ref SESSION_COOKIE_SECURE: bool = env::var("SESSION_COOKIE_SECURE").unwrap_or_else(|_e| {
+        String::from("true")
+    }).parse().unwrap_or_else(|e| {
+        panic!("Can't parse SESSION_COOKIE_SECURE {}", e);
+    });
+    /// Keep the cookie after the browser is closed, until SESSION_MAX_LIFETIME
+    pub static ref SESSION_COOKIE_PERSISTENT: bool = env::var("SESSION_COOKIE_PERSISTENT").unwrap_or_else(|_e| {
+        String::from("false")
+    }).parse().unwrap_or_else(|e| {
+        panic!("Can't parse SESSION_COOKIE_PERSISTENT {}", e);
+    });
+    /// Logout after this long without a request, in seconds (0 disables it)
+    pub static ref SESSION_IDLE_TIMEOUT: u64 = env::var("SESSION_IDLE_TIMEOUT").unwrap_or_else(|_e| {
+        String::from("1800")
+    }).parse().unwrap_or_else(|e| {
+        panic!("Can't parse SESSION_IDLE_TIMEOUT {}", e);
+    });
+    /// Logout this long after login whatever the activity, in seconds
+    pub static ref SESSION_MAX_LIFETIME: u64 = env::var("SESSION_MAX_LIFETIME").unwrap_or_else(|_e| {
+        String::from("86400")
+    }).parse().unwrap_or_else(|e| {
+        panic!("Can't parse SESSION_MAX_LIFETIME {}", e);
+    });
+
+    /// MFA
+    /// Encrypts second factor secrets at rest, base64 encoded 32 bytes key
+    pub static ref MFA_ENCRYPTION_KEY: Option<String> = env::var("MFA_ENCRYPTION_KEY").ok();
+    /// Name shown by authenticator apps
+    pub static ref TOTP_ISSUER: String = env::var("TOTP_ISSUER").unwrap_or_else(|_e| {
+        String::from("kz-auth")
+    });
+
+    /// WebAuthn
+    /// Domain passkeys are bound to, the origin must be this domain or a subdomain of it
+    pub static ref WEBAUTHN_RP_ID: String = env::var("WEBAUTHN_RP_ID").unwrap_or_else(|_e| {
+        String::from("localhost")
+    });
+    pub static ref WEBAUTHN_RP_NAME: String = env::var("WEBAUTHN_RP_NAME").unwrap_or_else(|_e| {
+        String::from("kz-auth")
+    });
+    /// Origin of the pages running the ceremonies, as reported by the browser
+    pub static ref WEBAUTHN_ORIGIN: String = env::var("WEBAUTHN_ORIGIN").unwrap_or_else(|_e| {
+        String::from("https://localhost")
+    });
+
+    /// Mail
+    /// Base URL of the links sent by email and of the endpoints in the OpenID Connect discovery
+    pub static ref PUBLIC_URL: String = env::var("PUBLIC_URL").unwrap_or_else(|_e| {
+        String::from("https://localhost:8443")
+    });
+    /// Delivery: smtp, file or stdout
+    pub static ref MAILER_KIND: String = env::var("MAILER").unwrap_or_else(|_e| {
+        String::from("stdout")
+    });
+    pub static ref MAIL_FROM: String = env::var("MAIL_FROM").unwrap_or_else(|_e| {
+        String::from("kz-auth <no-reply@localhost>")
+    });
+    /// mbox file written by the file mailer
+    pub static ref MAILER_FILE: String = env::var("MAILER_FILE").unwrap_or_else(|_e| {
+        String::from("mail.mbox")
+    });
+    pub static ref SMTP_HOST: String = env::var("SMTP_HOST").unwrap_or_else(|_e| {
+        String::from("localhost")
+    });
+    pub static ref SMTP_PORT: u16 = env::var("SMTP_PORT").unwrap_or_else(|_e| {
+        String::from("587")
+    }).parse().unwrap_or_else(|e| {
+        panic!("Can't parse SMTP_PORT {}", e);
+    });
+    /// none, starttls or tls
+    pub static ref SMTP_TLS: String = env::var("SMTP_TLS").unwrap_or_else(|_e| {
+        String::from("starttls")
+    });
+    pub static ref SMTP_USERNAME: Option<String> = env::var("SMTP_USERNAME").ok();
+    pub static ref SMTP_PASSWORD: Option<String> = env::var("SMTP_PASSWORD").ok();
+
+    /// Email verification
+    /// Verification link lifetime, in seconds
+    pub static ref EMAIL_VERIFICATION_TTL: i64 = env::var("EMAIL_VERIFICATION_TTL").unwrap_or_else(|_e| {
+        String::from("86400")
+    }).parse().unwrap_or_else(|e| {
+        panic!("Can't parse EMAIL_VERIFICATION_TTL {}", e);
+    });
+    /// Refuse to open a session until the address is verified
+    pub static ref REQUIRE_VERIFIED_EMAIL: bool = env::var("REQUIRE_VERIFIED_EMAIL").unwrap_or_else(|_e| {
+        String::from("false")
+    }).parse().unwrap_or_else(|e| {
+        panic!("Can't parse REQUIRE_VERIFIED_EMAIL {}", e);
+    });
+
+    /// Password reset
+    /// Reset link lifetime, in seconds
+    pub static ref PASSWORD_RESET_TTL: i64 = env::var("PASSWORD_RESET_TTL").unwrap_or_else(|_e| {
+        String::from("3600")
+    }).parse().unwrap_or_else(|e| {
+        panic!("Can't parse PASSWORD_RESET_TTL {}", e);
+    });
+
+    /// Magic links
+    /// Login link lifetime, in seconds
+    pub static ref MAGIC_LINK_TTL: i64 = env::var("MAGIC_LINK_TTL").unwrap_or_else(|_e| {
+        String::from("900")
+    }).parse().unwrap_or_else(|e| {
+        panic!("Can't parse MAGIC_LINK_TTL {}", e);
+    });
+
+    /// Email one-time passcodes
+    /// Code lifetime, in seconds
+    pub static ref EMAIL_OTP_TTL: i64 = env::var("EMAIL_OTP_TTL").unwrap_or_else(|_e| {
+        String::from("600")
+    }).parse().unwrap_or_else(|e| {
+        panic!("Can't parse EMAIL_OTP_TTL {}", e);
+    });
+    /// Wrong guesses before the code stops working
+    pub static ref EMAIL_OTP_MAX_ATTEMPTS: i32 = env::var("EMAIL_OTP_MAX_ATTEMPTS").unwrap_or_else(|_e| {
+        String::from("5")
+    }).parse().unwrap_or_else(|e| {
+        panic!("Can't parse EMAIL_OTP_MAX_ATTEMPTS {}", e);
+    });
+
+    /// Account lockout
+    /// Failed password attempts before the account locks, 0 disables the lockout
+    pub static ref LOCKOUT_THRESHOLD: i32 = env::var("LOCKOUT_THRESHOLD").unwrap_or_else(|_e| {
+        String::from("5")
+    }).parse().unwrap_or_else(|e| {
+        panic!("Can't parse LOCKOUT_THRESHOLD {}", e);
+    });
+    /// Time before a locked account unlocks by itself, in seconds
+    pub static ref LOCKOUT_DURATION: i64 = env::var("LOCKOUT_DURATION").unwrap_or_else(|_e| {
+        String::from("900")
+    }).parse().unwrap_or_else(|e| {
+        panic!("Can't parse LOCKOUT_DURATION {}", e);
+    });
+
+    /// Rate limiting
+    /// Token buckets kept in Redis, per route and client IP or login.
+    /// On by default when REDIS_HOST is set, it can't be turned on without it.
+    pub static ref RATE_LIMIT_ENABLED: bool = env::var("RATE_LIMIT_ENABLED").unwrap_or_else(|_e| {
+        REDIS_HOST.is_some().to_string()
+    }).parse().unwrap_or_else(|e| {
+        panic!("Can't parse RATE_LIMIT_ENABLED {}", e);
+    });
+    /// Take the client IP from Forwarded / X-Forwarded-For, only behind a proxy that sets them
+    pub static ref RATE_LIMIT_TRUST_PROXY: bool = env::var("RATE_LIMIT_TRUST_PROXY").unwrap_or_else(|_e| {
+        String::from("false")
+    }).parse().unwrap_or_else(|e| {
+        panic!("Can't parse RATE_LIMIT_TRUST_PROXY {}", e);
+    });
+    /// Requests allowed at once from an IP
+    pub static ref RATE_LIMIT_IP_BURST: u32 = env::var("RATE_LIMIT_IP_BURST").unwrap_or_else(|_e| {
+        String::from("20")
+    }).parse().unwrap_or_else(|e| {
+        panic!("Can't parse RATE_LIMIT_IP_BURST {}", e);
+    });
+    /// Sustained requests from an IP
+    pub static ref RATE_LIMIT_IP_PER_MINUTE: f64 = env::var("RATE_LIMIT_IP_PER_MINUTE").unwrap_or_else(|_e| {
+        String::from("10")
+    }).parse().unwrap_or_else(|e| {
+        panic!("Can't parse RATE_LIMIT_IP_PER_MINUTE {}", e);
+    });
+    /// Requests allowed at once for a login, whatever the IP
+    pub static ref RATE_LIMIT_LOGIN_BURST: u32 = env::var("RATE_LIMIT_LOGIN_BURST").unwrap_or_else(|_e| {
+        String::from("5")
+    }).parse().unwrap_or_else(|e| {
+        panic!("Can't parse RATE_LIMIT_LOGIN_BURST {}", e);
+    });
+    /// Sustained requests for a login
+    pub static ref RATE_LIMIT_LOGIN_PER_MINUTE: f64 = env::var("RATE_LIMIT_LOGIN_PER_MINUTE").unwrap_or_else(|_e| {
+        String::from("2")
+    }).parse().unwrap_or_else(|e| {
+        panic!("Can't parse RATE_LIMIT_LOGIN_PER_MINUTE {}", e);
+    });
+    /// Let requests through when Redis can't be reached. Off by default: the limits guard
+    /// the password and code endpoints, an outage shouldn't lift them.
+    pub static ref RATE_LIMIT_FAIL_OPEN: bool = env::var("RATE_LIMIT_FAIL_OPEN").unwrap_or_else(|_e| {
+        String::from("false")
+    }).parse().unwrap_or_else(|e| {
+        panic!("Can't parse RATE_LIMIT_FAIL_OPEN {}", e);
+    });
+}
+
+// A second block, a single one hits the macro recursion limit
+lazy_static! {
+    /// Password policy
+    /// Length bounds, in characters
+    pub static ref PASSWORD_MIN_LENGTH: usize = env::var("PASSWORD_MIN_LENGTH").unwrap_or_else(|_e| {
+        String::from("8")
+    }).parse().unwrap_or_else(|e| {
+        panic!("Can't parse PASSWORD_MIN_LENGTH {}", e);
+    });
+    pub static ref PASSWORD_MAX_LENGTH: usize = env::var("PASSWORD_MAX_LENGTH").unwrap_or_else(|_e| {
+        String::from("128")
+    }).parse().unwrap_or_else(|e| {
+        panic!("Can't parse PASSWORD_MAX_LENGTH {}", e);
+    });
+    /// Comma separated classes every password must contain: lowercase, uppercase, digit, symbol
+    pub static ref PASSWORD_REQUIRED_CLASSES: String = env::var("PASSWORD_REQUIRED_CLASSES").unwrap_or_else(|_e| {
+        String::new()
+    });
+    /// Minimum strength score, from 0 (guessable in a few tries) to 4 (very unguessable)
+    pub static ref PASSWORD_MIN_SCORE: u8 = env::var("PASSWORD_MIN_SCORE").unwrap_or_else(|_e| {
+        String::from("2")
+    }).parse().unwrap_or_else(|e| {
+        panic!("Can't parse PASSWORD_MIN_SCORE {}", e);
+    });
+    /// Ordered HIBP "SHA1:COUNT" file, or a filter built from it with `kz-auth build-breach-filter`
+    pub static ref BREACHED_PASSWORDS_FILE: Option<String> = env::var("BREACHED_PASSWORDS_FILE").ok();
+    /// Breaches a password must appear in to be rejected
+    pub static ref BREACHED_PASSWORDS_MIN_COUNT: u64 = env::var("BREACHED_PASSWORDS_MIN_COUNT").unwrap_or_else(|_e| {
+        String::from("1")
+    }).parse().unwrap_or_else(|e| {
+        panic!("Can't parse BREACHED_PASSWORDS_MIN_COUNT {}", e);
+    });
+
+    /// Password hashing
+    /// Argon2id memory in KiB, iterations and parallelism, OWASP minimum by default.
+    /// Hashes made with weaker parameters are upgraded at the next login.
+    pub static ref ARGON2_M_COST: u32 = env::var("ARGON2_M_COST").unwrap_or_else(|_e| {
+        String::from("19456")
+    }).parse().unwrap_or_else(|e| {
+        panic!("Can't parse ARGON2_M_COST {}", e);
+    });
+    pub static ref ARGON2_T_COST: u32 = env::var("ARGON2_T_COST").unwrap_or_else(|_e| {
+        String::from("2")
+    }).parse().unwrap_or_else(|e| {
+        panic!("Can't parse ARGON2_T_COST {}", e);
+    });
+    pub static ref ARGON2_P_COST: u32 = env::var("ARGON2_P_COST").unwrap_or_else(|_e| {
+        String::from("1")
+    }).parse().unwrap_or_else(|e| {
+        panic!("Can't parse ARGON2_P_COST {}", e);
+    });
+
+    /// OpenID Connect
+    /// Discovery, JWKS, userinfo and ID tokens, off by default. Requires JWT_PRIVATE_KEY:
+    /// relying parties can't verify ID tokens signed with the JWT_SECRET.
+    pub static ref OIDC_ENABLED: bool = env::var("OIDC_ENABLED").unwrap_or_else(|_e| {
+        String::from("false")
+    }).parse().unwrap_or_else(|e| {
+        panic!("Can't parse OIDC_ENABLED {}", e);
+    });
+
 }
@@ -0,0 +1,163 @@
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::sync::Mutex;
+use actix_web::web;
+use anyhow::anyhow;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::Utc;
+use lazy_static::lazy_static;
+use log::info;
+
+use crate::hashing::generate_token;
+use crate::local_env::*;
+
+pub mod smtp;
+
+lazy_static! {
+    static ref MAILER: Box<dyn Mailer> = from_config();
+}
+
+pub struct Email {
+    pub to: String,
+    pub subject: String,
+    /// Plain text
+    pub body: String,
+}
+
+/// Delivers emails, selected with MAILER
+pub trait Mailer: Send + Sync {
+    fn send(&self, email: &Email) -> Result<(), anyhow::Error>;
+}
+
+fn from_config() -> Box<dyn Mailer> {
+    info!("[{}] -- Mailer: {}", "Mailer", *MAILER_KIND);
+
+    match MAILER_KIND.as_str() {
+        "smtp" => Box::new(smtp::SmtpMailer::from_config()),
+        "file" => Box::new(FileMailer { path: MAILER_FILE.to_string(), lock: Mutex::new(()) }),
+        "stdout" => Box::new(StdoutMailer),
+        x => panic!("[{}] -- Unknown MAILER {}, expected smtp, file or stdout", "Mailer", x),
+    }
+}
+
+pub fn check_config() {
+    lazy_static::initialize(&MAILER);
+}
+
+/// Send an email from a blocking thread, SMTP servers can be slow to answer
+pub async fn send(email: Email) -> Result<(), anyhow::Error> {
+    web::block(move || MAILER.send(&email)).await?
+}
+
+/// Address part of `Name <address>`
+pub fn address(mailbox: &str) -> &str {
+    match (mailbox.find('<'), mailbox.rfind('>')) {
+        (Some(start), Some(end)) if start < end => &mailbox[start + 1..end],
+        _ => mailbox.trim(),
+    }
+}
+
+/// RFC 2047 encoded word for non ASCII headers
+fn encode_header(value: &str) -> String {
+    if value.is_ascii() {
+        value.to_string()
+    } else {
+        format!("=?utf-8?B?{}?=", STANDARD.encode(value))
+    }
+}
+
+impl Email {
+    /// RFC 5322 message with CRLF line endings
+    pub fn to_message(&self, from: &str) -> Result<String, anyhow::Error> {
+        if [from, &self.to, &self.subject].iter().any(|x| x.contains(['\r', '\n'])) {
+            return Err(anyhow!("Line break in an email header"));
+        }
+
+        let domain = address(from).rsplit('@').next().unwrap_or("localhost");
+        let headers = [
+            format!("From: {}", from),
+            format!("To: {}", self.to),
+            format!("Subject: {}", encode_header(&self.subject)),
+            format!("Date: {}", Utc::now().to_rfc2822()),
+            format!("Message-ID: <{}@{}>", generate_token(), domain),
+            String::from("MIME-Version: 1.0"),
+            String::from("Content-Type: text/plain; charset=utf-8"),
+            String::from("Content-Transfer-Encoding: 8bit"),
+        ];
+        let body = self.body.lines().collect::<Vec<_>>().join("\r\n");
+
+        Ok(format!("{}\r\n\r\n{}\r\n", headers.join("\r\n"), body))
+    }
+}
+
+/// Prints emails to stdout, for development
+pub struct StdoutMailer;
+
+impl Mailer for StdoutMailer {
+    fn send(&self, email: &Email) -> Result<(), anyhow::Error> {
+        let message = email.to_message(&MAIL_FROM)?;
+        println!("{}", message.replace("\r\n", "\n"));
+        Ok(())
+    }
+}
+
+/// Appends emails to an mbox file, for tests and offline environments
+pub struct FileMailer {
+    path: String,
+    lock: Mutex<()>,
+}
+
+impl Mailer for FileMailer {
+    fn send(&self, email: &Email) -> Result<(), anyhow::Error> {
+        let message = email.to_message(&MAIL_FROM)?.replace("\r\n", "\n");
+        let _guard = self.lock.lock().unwrap();
+
+        let mut file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        writeln!(file, "From {} {}", address(&MAIL_FROM), Utc::now().format("%a %b %e %H:%M:%S %Y"))?;
+        // mboxrd quoting, so a body line can't start a new message
+        for line in message.lines() {
+            if line.trim_start_matches('>').starts_with("From ") {
+                write!(file, ">")?;
+            }
+            writeln!(file, "{}", line)?;
+        }
+        writeln!(file)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_message() {
+        let email = Email {
+            to: String::from("valentin@example.com"),
+            subject: String::from("Vérification"),
+            body: String::from("Hello\nWorld"),
+        };
+        let message = email.to_message("kz-auth <no-reply@example.com>").unwrap();
+
+        assert!(message.starts_with("From: kz-auth <no-reply@example.com>\r\nTo: valentin@example.com\r\n"));
+        assert!(message.contains("Subject: =?utf-8?B?"));
+        assert!(message.contains("@example.com>\r\n"));
+        assert!(message.ends_with("\r\n\r\nHello\r\nWorld\r\n"));
+    }
+
+    #[test]
+    fn test_header_injection() {
+        let email = Email {
+            to: String::from("a@example.com\r\nBcc: b@example.com"),
+            subject: String::new(),
+            body: String::new(),
+        };
+        assert!(email.to_message("no-reply@example.com").is_err());
+    }
+
+    #[test]
+    fn test_address() {
+        assert_eq!(address("kz-auth <no-reply@example.com>"), "no-reply@example.com");
+        assert_eq!(address(" no-reply@example.com "), "no-reply@example.com");
+    }
+}
@@ -0,0 +1,222 @@
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+use anyhow::anyhow;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use openssl::ssl::{HandshakeError, SslConnector, SslMethod};
+
+use crate::local_env::*;
+
+use super::{address, Email, Mailer};
+
+/// Applies to the connection and to every reply
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum SmtpTls {
+    /// Plain text, only for a relay on localhost
+    None,
+    /// Upgrade a plain connection, usually on port 587
+    StartTls,
+    /// TLS from the start, usually on port 465
+    Tls,
+}
+
+trait Stream: Read + Write {}
+impl<T: Read + Write> Stream for T {}
+
+/// Minimal SMTP client (RFC 5321) with STARTTLS and AUTH PLAIN
+pub struct SmtpMailer {
+    host: String,
+    port: u16,
+    tls: SmtpTls,
+    credentials: Option<(String, String)>,
+}
+
+impl SmtpMailer {
+    pub fn from_config() -> SmtpMailer {
+        let tls = match SMTP_TLS.as_str() {
+            "none" => SmtpTls::None,
+            "starttls" => SmtpTls::StartTls,
+            "tls" => SmtpTls::Tls,
+            x => panic!("[{}] -- Unknown SMTP_TLS {}, expected none, starttls or tls", "Mailer", x),
+        };
+
+        SmtpMailer {
+            host: SMTP_HOST.to_string(),
+            port: *SMTP_PORT,
+            tls,
+            credentials: SMTP_USERNAME.clone().zip(SMTP_PASSWORD.clone()),
+        }
+    }
+}
+
+struct Connection {
+    reader: BufReader<Box<dyn Stream>>,
+}
+
+impl Connection {
+    /// Read a possibly multiline reply and check its code
+    fn expect(&mut self, codes: &[u16]) -> Result<String, anyhow::Error> {
+        let mut reply = String::new();
+        loop {
+            let mut line = String::new();
+            if self.reader.read_line(&mut line)? == 0 {
+                return Err(anyhow!("SMTP connection closed"));
+            }
+            reply.push_str(&line);
+            // "250-" continues the reply, "250 " ends it
+            if line.len() < 4 || line.as_bytes()[3] != b'-' {
+                break;
+            }
+        }
+
+        let code: u16 = reply.get(..3).and_then(|x| x.parse().ok()).ok_or_else(|| anyhow!("Invalid SMTP reply {}", reply.trim()))?;
+        if !codes.contains(&code) {
+            return Err(anyhow!("Unexpected SMTP reply {}", reply.trim()));
+        }
+        Ok(reply)
+    }
+
+    fn command(&mut self, command: &str, codes: &[u16]) -> Result<String, anyhow::Error> {
+        let stream = self.reader.get_mut();
+        stream.write_all(command.as_bytes())?;
+        stream.write_all(b"\r\n")?;
+        stream.flush()?;
+        self.expect(codes)
+    }
+}
+
+/// Lines starting with a dot are doubled so they don't end the DATA section
+fn dot_stuff(message: &str) -> String {
+    message.split("\r\n")
+        .map(|line| if line.starts_with('.') { format!(".{}", line) } else { line.to_string() })
+        .collect::<Vec<_>>()
+        .join("\r\n")
+}
+
+impl SmtpMailer {
+    fn connect(&self) -> Result<Box<dyn Stream>, anyhow::Error> {
+        let socket = (self.host.as_str(), self.port).to_socket_addrs()?
+            .next()
+            .ok_or_else(|| anyhow!("Can't resolve SMTP_HOST {}", self.host))?;
+        let stream = TcpStream::connect_timeout(&socket, TIMEOUT)?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        stream.set_write_timeout(Some(TIMEOUT))?;
+
+        match self.tls {
+            SmtpTls::Tls => self.wrap(Box::new(stream)),
+            _ => Ok(Box::new(stream)),
+        }
+    }
+
+    fn wrap(&self, stream: Box<dyn Stream>) -> Result<Box<dyn Stream>, anyhow::Error> {
+        let connector = SslConnector::builder(SslMethod::tls_client())?.build();
+        let stream = connector.connect(&self.host, stream)
+            .map_err(|e| {
+                let reason = match e {
+                    HandshakeError::SetupFailure(x) => x.to_string(),
+                    HandshakeError::Failure(x) | HandshakeError::WouldBlock(x) => x.error().to_string(),
+                };
+                anyhow!("TLS handshake with {} failed: {}", self.host, reason)
+            })?;
+        Ok(Box::new(stream))
+    }
+
+    fn deliver(&self, from: &str, to: &str, message: &str) -> Result<(), anyhow::Error> {
+        let mut conn = Connection { reader: BufReader::new(self.connect()?) };
+        conn.expect(&[220])?;
+        conn.command("EHLO localhost", &[250])?;
+
+        if self.tls == SmtpTls::StartTls {
+            conn.command("STARTTLS", &[220])?;
+            let stream = self.wrap(conn.reader.into_inner())?;
+            conn = Connection { reader: BufReader::new(stream) };
+            conn.command("EHLO localhost", &[250])?;
+        }
+
+        if let Some((username, password)) = self.credentials.as_ref() {
+            let token = STANDARD.encode(format!("\0{}\0{}", username, password));
+            conn.command(&format!("AUTH PLAIN {}", token), &[235])?;
+        }
+
+        conn.command(&format!("MAIL FROM:<{}>", address(from)), &[250])?;
+        conn.command(&format!("RCPT TO:<{}>", address(to)), &[250, 251])?;
+        conn.command("DATA", &[354])?;
+        conn.command(&format!("{}.", dot_stuff(message)), &[250])?;
+        // The message is accepted, a failing QUIT doesn't matter
+        let _ = conn.command("QUIT", &[221]);
+        Ok(())
+    }
+}
+
+impl Mailer for SmtpMailer {
+    fn send(&self, email: &Email) -> Result<(), anyhow::Error> {
+        let message = email.to_message(&MAIL_FROM)?;
+        self.deliver(&MAIL_FROM, &email.to, &message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+
+    #[test]
+    fn test_dot_stuff() {
+        assert_eq!(dot_stuff("a\r\n.b\r\n..\r\n"), "a\r\n..b\r\n...\r\n");
+    }
+
+    #[test]
+    fn test_conversation() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let server = thread::spawn(move || {
+            let (stream, _addr) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut writer = stream;
+            let mut received = Vec::new();
+            let mut data = false;
+            writer.write_all(b"220 test ESMTP\r\n").unwrap();
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                let line = line.trim_end().to_string();
+                let reply: &[u8] = match line.as_str() {
+                    "." if data => b"250 queued\r\n",
+                    _ if data => b"",
+                    "DATA" => b"354 go\r\n",
+                    "QUIT" => b"221 bye\r\n",
+                    x if x.starts_with("EHLO") => b"250-test\r\n250 AUTH PLAIN\r\n",
+                    x if x.starts_with("AUTH PLAIN") => b"235 ok\r\n",
+                    _ => b"250 ok\r\n",
+                };
+                data = (data || line == "DATA") && line != ".";
+                writer.write_all(reply).unwrap();
+                received.push(line.clone());
+                if line == "QUIT" {
+                    return received;
+                }
+            }
+        });
+
+        let mailer = SmtpMailer {
+            host: String::from("127.0.0.1"),
+            port,
+            tls: SmtpTls::None,
+            credentials: Some((String::from("user"), String::from("pass"))),
+        };
+        let message = "Subject: test\r\n\r\n.hidden\r\n";
+        mailer.deliver("kz-auth <no-reply@example.com>", "valentin@example.com", message).unwrap();
+
+        let received = server.join().unwrap();
+        assert_eq!(received[0], "EHLO localhost");
+        assert_eq!(received[1], format!("AUTH PLAIN {}", STANDARD.encode("\0user\0pass")));
+        assert_eq!(received[2], "MAIL FROM:<no-reply@example.com>");
+        assert_eq!(received[3], "RCPT TO:<valentin@example.com>");
+        assert!(received.contains(&String::from("..hidden")));
+        assert_eq!(received.last().unwrap(), "QUIT");
+    }
+}
@@ -1,30 +1,39 @@
-use actix_web::cookie::Key;
-use actix_web::{get, post, web, App, HttpResponse, HttpServer, Responder, guard, middleware, error};
-use actix_identity::IdentityMiddleware;
-use actix_session::{storage::RedisSessionStore, SessionMiddleware};
-use log::{error, warn, info, debug, trace, LevelFilter};
-use env_logger;
+use actix_web::{web, App, HttpResponse, HttpServer, middleware, error};
+use log::{error, info, LevelFilter};
 use dotenv::dotenv;
-use lazy_static::lazy_static;
 use openssl::ssl::{SslAcceptor, SslFiletype, SslMethod};
-use std::env;
 use std::io::Write;
-use std::fmt::{Display, Debug};
-use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
-use std::str::FromStr;
+use std::net::SocketAddrV4;
 use local_env::*;
 
+mod breached;
+mod cache;
+mod cli;
+mod crypto;
 mod hashing;
+mod jwt;
+mod mailer;
 mod database;
 mod schema;
+mod session_keys;
+mod session_store;
 mod models;
+mod password_policy;
+mod rate_limit;
+mod totp;
+mod webauthn;
 mod local_env;
 
 mod health;
+mod oauth;
+mod oidc;
 mod users;
 
 use users::users_config;
+use oauth::oauth_config;
+use oidc::oidc_config;
 
+#[allow(dead_code)]
 pub struct AppState {
     app_name: String,
 }
@@ -41,6 +50,24 @@ async fn main() -> std::io::Result<()> {
 
     local_env::check_vars();
 
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if !args.is_empty() {
+        return cli::run(&args);
+    }
+
+    jwt::check_keys();
+    oidc::check_config();
+    session_keys::check_keys();
+    crypto::check_key();
+    mailer::check_config();
+    hashing::check_params();
+    hashing::init_dummy_hash();
+    password_policy::check_config();
+    breached::check_config();
+    rate_limit::check_config();
+    users::session::check_index();
+    oauth::revocation::check_denylist();
+
     info!("[{}] -- Starting server..", "Main");
     info!("[{}] -- Host: {} Port {}", "Main", *HOST, *PORT);
 
@@ -53,9 +80,11 @@ async fn main() -> std::io::Result<()> {
         .unwrap();
     builder.set_certificate_chain_file("cert.pem").unwrap();
 
-    let secret_key = Key::generate(); // TODO: store key elsewhere
-    let redis_connection_string = format!("redis://{}:{}", &REDIS_HOST.as_str(), &REDIS_PORT.to_string());
-    let store = RedisSessionStore::new(redis_connection_string).await.unwrap();
+    let secret_key = session_keys::current();
+    let store = session_store::SessionBackend::from_config().await.map_err(|e| {
+        error!("[{}] -- Session store unavailable: {}", "Main", e);
+        std::io::Error::other(e.to_string())
+    })?;
 
     let socket = SocketAddrV4::new(*HOST, *PORT);
     HttpServer::new(move || {
@@ -72,11 +101,13 @@ async fn main() -> std::io::Result<()> {
         });
         
         App::new()
-            .wrap(IdentityMiddleware::default())
-            .wrap(SessionMiddleware::new(
+            .wrap(session_store::identity_middleware())
+            .wrap(middleware::from_fn(users::session::check_session))
+            .wrap(session_store::session_middleware(
                 store.clone(),
                 secret_key.clone()
             ))
+            .wrap(middleware::from_fn(session_keys::rotate_cookie))
             .wrap(middleware::Compress::default())
             .app_data(json_cfg)
             .app_data(web::Data::new(AppState {
@@ -86,6 +117,10 @@ async fn main() -> std::io::Result<()> {
             .service(
                 web::scope("/users").configure(users_config)
             )
+            .service(
+                web::scope("/oauth").configure(oauth_config)
+            )
+            .configure(|cfg| if *OIDC_ENABLED { oidc_config(cfg) })
     })
     .bind_openssl(socket, builder)?
     // .bind(socket)?
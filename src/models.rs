@@ -1,14 +1,14 @@
-use diesel::{Queryable, Insertable, AsChangeset};
-use chrono::{NaiveDateTime, DateTime, Utc};
+use diesel::{Queryable, Insertable};
+use chrono::NaiveDateTime;
 use serde::{Serialize, Deserialize};
 
 use super::schema::users;
 
 mod date_format {
-    use chrono::{NaiveDateTime, Utc, TimeZone};
+    use chrono::NaiveDateTime;
     use serde::{self, Deserialize, Serializer, Deserializer};
 
-    const FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+    pub const FORMAT: &str = "%Y-%m-%d %H:%M:%S";
 
     // The signature of a serialize_with function must follow the pattern:
     //
@@ -46,6 +46,36 @@ mod date_format {
     }
 }
 
+/// `date_format` for nullable columns
+mod optional_date_format {
+    use chrono::NaiveDateTime;
+    use serde::{self, Deserialize, Serializer, Deserializer};
+
+    pub fn serialize<S>(
+        date: &Option<NaiveDateTime>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        match date {
+            Some(x) => super::date_format::serialize(x, serializer),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D>(
+        deserializer: D,
+    ) -> Result<Option<NaiveDateTime>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        Option::<String>::deserialize(deserializer)?
+            .map(|s| NaiveDateTime::parse_from_str(&s, super::date_format::FORMAT).map_err(serde::de::Error::custom))
+            .transpose()
+    }
+}
+
 #[derive(Queryable, Insertable, Serialize, Deserialize, Debug)]
 #[allow(non_snake_case)]
 pub struct User {
@@ -57,5 +87,101 @@ pub struct User {
     #[serde(with = "date_format")]
     pub created_at: NaiveDateTime,
     #[serde(with = "date_format")]
-    pub updated_at: NaiveDateTime
-}
\ No newline at end of file
+    pub updated_at: NaiveDateTime,
+    #[serde(with = "optional_date_format", default)]
+    pub email_verified_at: Option<NaiveDateTime>,
+    #[serde(skip)]
+    pub failed_logins: i32,
+    #[serde(skip)]
+    pub locked_until: Option<NaiveDateTime>,
+    #[serde(default)]
+    pub is_admin: bool,
+    #[serde(skip)]
+    pub mfa_attempts: i32,
+    /// Sessions and access tokens issued before were revoked with the password
+    #[serde(skip)]
+    pub credentials_revoked_at: Option<NaiveDateTime>,
+}
+
+#[derive(Queryable, Debug)]
+#[allow(dead_code)]
+pub struct RefreshToken {
+    pub id: i32,
+    pub user_id: i32,
+    pub family_id: String,
+    pub token_hash: String,
+    pub created_at: NaiveDateTime,
+    pub expires_at: NaiveDateTime,
+    pub used_at: Option<NaiveDateTime>,
+    pub revoked_at: Option<NaiveDateTime>,
+    pub scope: Option<String>,
+    /// OAuth client the token was issued to, none for first party logins
+    pub client_id: Option<i32>,
+}
+
+#[derive(Queryable, Debug)]
+#[allow(dead_code)]
+pub struct OauthClient {
+    pub id: i32,
+    pub client_id: String,
+    pub name: String,
+    pub redirect_uris: Vec<String>,
+    pub scopes: Vec<String>,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+    pub client_secret: Option<String>,
+}
+
+#[derive(Queryable, Debug)]
+#[allow(dead_code)]
+pub struct AuthorizationCode {
+    pub id: i32,
+    pub code_hash: String,
+    pub client_id: i32,
+    pub user_id: i32,
+    pub redirect_uri: String,
+    pub scope: String,
+    pub code_challenge: String,
+    pub created_at: NaiveDateTime,
+    pub expires_at: NaiveDateTime,
+    pub used_at: Option<NaiveDateTime>,
+    pub nonce: Option<String>,
+}
+
+#[derive(Queryable, Debug)]
+#[allow(dead_code)]
+pub struct UserTotp {
+    pub id: i32,
+    pub user_id: i32,
+    pub secret: String,
+    pub created_at: NaiveDateTime,
+    pub confirmed_at: Option<NaiveDateTime>,
+    pub last_used_step: Option<i64>,
+}
+
+#[derive(Queryable, Debug)]
+#[allow(dead_code)]
+pub struct WebauthnCredential {
+    pub id: i32,
+    pub user_id: i32,
+    pub credential_id: String,
+    pub public_key: String,
+    pub algorithm: i32,
+    pub sign_count: i64,
+    pub name: Option<String>,
+    pub created_at: NaiveDateTime,
+    pub last_used_at: Option<NaiveDateTime>,
+}
+
+#[derive(Queryable, Debug)]
+#[allow(dead_code)]
+pub struct EmailOtp {
+    pub id: i32,
+    pub user_id: i32,
+    pub login: String,
+    pub code_hash: String,
+    pub attempts: i32,
+    pub created_at: NaiveDateTime,
+    pub expires_at: NaiveDateTime,
+    pub used_at: Option<NaiveDateTime>,
+}
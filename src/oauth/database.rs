@@ -0,0 +1,92 @@
+use diesel::{QueryDsl, RunQueryDsl};
+use diesel::ExpressionMethods;
+use chrono::NaiveDateTime;
+
+use crate::database::{
+    POOL, QueryResult
+};
+use crate::models::{
+    OauthClient,
+    AuthorizationCode
+};
+
+macro_rules! getConn {
+    () => {
+        &mut POOL.get().unwrap()
+    };
+}
+
+pub fn find_client(_client_id: &str) -> QueryResult<OauthClient> {
+    use crate::schema::oauth_clients::dsl::*;
+    let conn = getConn!();
+
+    oauth_clients
+        .filter(client_id.eq(_client_id))
+        .first::<OauthClient>(conn)
+}
+
+pub fn create_client(_client_id: &str, _name: &str, _redirect_uris: &[String], _scopes: &[String], _client_secret: Option<&str>) -> QueryResult<()> {
+    use crate::schema::oauth_clients::dsl::*;
+    let conn = getConn!();
+    diesel::insert_into(oauth_clients)
+        .values((
+            client_id.eq(_client_id),
+            name.eq(_name),
+            redirect_uris.eq(_redirect_uris),
+            scopes.eq(_scopes),
+            client_secret.eq(_client_secret),
+        ))
+        .execute(conn)?;
+
+    Ok(())
+}
+
+pub struct NewAuthorizationCode<'a> {
+    pub code_hash: &'a str,
+    pub client_id: i32,
+    pub user_id: i32,
+    pub redirect_uri: &'a str,
+    pub scope: &'a str,
+    pub code_challenge: &'a str,
+    pub nonce: Option<&'a str>,
+    pub expires_at: NaiveDateTime,
+}
+
+pub fn create_authorization_code(code: &NewAuthorizationCode) -> QueryResult<()> {
+    use crate::schema::oauth_authorization_codes::dsl::*;
+    let conn = getConn!();
+    diesel::insert_into(oauth_authorization_codes)
+        .values((
+            code_hash.eq(code.code_hash),
+            client_id.eq(code.client_id),
+            user_id.eq(code.user_id),
+            redirect_uri.eq(code.redirect_uri),
+            scope.eq(code.scope),
+            code_challenge.eq(code.code_challenge),
+            nonce.eq(code.nonce),
+            expires_at.eq(code.expires_at),
+        ))
+        .execute(conn)?;
+
+    Ok(())
+}
+
+pub fn find_authorization_code(_code_hash: &str) -> QueryResult<AuthorizationCode> {
+    use crate::schema::oauth_authorization_codes::dsl::*;
+    let conn = getConn!();
+
+    oauth_authorization_codes
+        .filter(code_hash.eq(_code_hash))
+        .first::<AuthorizationCode>(conn)
+}
+
+/// Mark an authorization code as used, returns false if it was already used
+pub fn use_authorization_code(code_id: i32, now: NaiveDateTime) -> QueryResult<bool> {
+    use crate::schema::oauth_authorization_codes::dsl::*;
+    let conn = getConn!();
+    let rows = diesel::update(oauth_authorization_codes.find(code_id).filter(used_at.is_null()))
+        .set(used_at.eq(now))
+        .execute(conn)?;
+
+    Ok(rows == 1)
+}
@@ -0,0 +1,638 @@
+use actix_identity::Identity;
+use actix_web::{middleware::from_fn, web, HttpResponse, HttpRequest, http::{header, StatusCode}};
+use base64::{engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD}, Engine};
+use chrono::{Duration, Utc};
+use log::{error, warn, info};
+use openssl::{memcmp, sha::sha256};
+use serde::{Deserialize, Serialize};
+
+use crate::hashing::{generate_hash, generate_token, hash_token, verify_password};
+use crate::jwt::{self, Claims, TokenResponse};
+use crate::local_env::{OAUTH_CODE_TTL, OIDC_ENABLED};
+use crate::models::{OauthClient, RefreshToken};
+use crate::rate_limit;
+use crate::users::{self, refresh::{self, RefreshError}};
+
+mod database;
+pub mod revocation;
+
+#[derive(Deserialize)]
+pub struct AuthorizeRequest {
+    response_type: String,
+    client_id: String,
+    redirect_uri: Option<String>,
+    scope: Option<String>,
+    state: Option<String>,
+    code_challenge: Option<String>,
+    code_challenge_method: Option<String>,
+    nonce: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct TokenRequest {
+    grant_type: String,
+    code: Option<String>,
+    redirect_uri: Option<String>,
+    client_id: Option<String>,
+    client_secret: Option<String>,
+    code_verifier: Option<String>,
+    refresh_token: Option<String>,
+    scope: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct TokenActionRequest {
+    /// token_type_hint is ignored, the token format tells access and refresh tokens apart
+    token: String,
+    client_id: Option<String>,
+    client_secret: Option<String>,
+}
+
+/// RFC 7662 introspection response, only `active` is set for inactive tokens
+#[derive(Serialize, Default)]
+struct Introspection {
+    active: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    scope: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    client_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sub: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    token_type: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    exp: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    iat: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    iss: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    aud: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    jti: Option<String>,
+}
+
+#[derive(Serialize)]
+struct OauthError {
+    error: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error_description: Option<&'static str>,
+}
+
+pub fn oauth_config(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::resource("/authorize")
+            .route(web::get().to(authorize))
+    );
+    cfg.service(
+        web::resource("/token")
+            .wrap(from_fn(rate_limit::limit))
+            .route(web::post().to(token))
+            .route(web::get().to(HttpResponse::MethodNotAllowed))
+    );
+    cfg.service(
+        web::resource("/introspect")
+            .route(web::post().to(introspect))
+            .route(web::get().to(HttpResponse::MethodNotAllowed))
+    );
+    cfg.service(
+        web::resource("/revoke")
+            .route(web::post().to(revoke))
+            .route(web::get().to(HttpResponse::MethodNotAllowed))
+    );
+}
+
+/// Access tokens are JWTs, refresh tokens are opaque hex strings
+fn is_jwt(token: &str) -> bool {
+    token.contains('.')
+}
+
+fn error_response(error: &'static str, description: &'static str) -> HttpResponse {
+    HttpResponse::BadRequest().json(OauthError {
+        error,
+        error_description: Some(description),
+    })
+}
+
+/// Send the user agent back to the client with the outcome of the authorization request
+fn redirect(redirect_uri: &str, params: &[(&str, &str)]) -> HttpResponse {
+    let query = serde_urlencoded::to_string(params).unwrap();
+    let separator = if redirect_uri.contains('?') { '&' } else { '?' };
+
+    HttpResponse::Found()
+        .insert_header((header::LOCATION, format!("{}{}{}", redirect_uri, separator, query)))
+        .finish()
+}
+
+fn redirect_error(redirect_uri: &str, error: &str, state: &Option<String>) -> HttpResponse {
+    match state {
+        Some(state) => redirect(redirect_uri, &[("error", error), ("state", state)]),
+        None => redirect(redirect_uri, &[("error", error)]),
+    }
+}
+
+/// Resolve the redirect URI of the request, it must exactly match one registered for the client
+fn resolve_redirect_uri<'a>(client: &'a OauthClient, requested: &'a Option<String>) -> Option<&'a str> {
+    match requested {
+        Some(uri) => client.redirect_uris.iter().find(|x| *x == uri).map(|x| x.as_str()),
+        None if client.redirect_uris.len() == 1 => Some(client.redirect_uris[0].as_str()),
+        None => None,
+    }
+}
+
+/// Requested scopes must all be allowed for the client, defaults to every allowed scope
+fn resolve_scope(client: &OauthClient, requested: &Option<String>) -> Option<String> {
+    match requested {
+        Some(scope) => {
+            let allowed = scope.split_whitespace().all(|x| client.scopes.iter().any(|y| y == x));
+            allowed.then(|| scope.split_whitespace().collect::<Vec<_>>().join(" "))
+        },
+        None => Some(client.scopes.join(" ")),
+    }
+}
+
+/// PKCE S256: BASE64URL(SHA256(code_verifier)) == code_challenge
+fn verify_pkce(code_verifier: &str, code_challenge: &str) -> bool {
+    if code_verifier.len() < 43 || code_verifier.len() > 128 {
+        return false;
+    }
+
+    let computed = URL_SAFE_NO_PAD.encode(sha256(code_verifier.as_bytes()));
+    computed.len() == code_challenge.len() && memcmp::eq(computed.as_bytes(), code_challenge.as_bytes())
+}
+
+pub async fn authorize(query: web::Query<AuthorizeRequest>, identity: Option<Identity>) -> HttpResponse {
+    info!("[{}] -- Authorization request from client {}", "OauthService::authorize", query.client_id);
+
+    let client = match database::find_client(&query.client_id) {
+        Ok(client) => client,
+        Err(e) => {
+            warn!("[{}] -- Unknown client {}: {}", "OauthService::authorize", query.client_id, e);
+            return error_response("invalid_request", "Unknown client_id");
+        }
+    };
+
+    // Never redirect to an URI that is not registered
+    let redirect_uri = match resolve_redirect_uri(&client, &query.redirect_uri) {
+        Some(x) => x,
+        None => {
+            warn!("[{}] -- Invalid redirect_uri for client {}", "OauthService::authorize", client.client_id);
+            return error_response("invalid_request", "Invalid redirect_uri");
+        }
+    };
+
+    if query.response_type != "code" {
+        return redirect_error(redirect_uri, "unsupported_response_type", &query.state);
+    }
+
+    let code_challenge = match (&query.code_challenge, query.code_challenge_method.as_deref()) {
+        (Some(challenge), Some("S256")) if challenge.len() == 43 => challenge,
+        _ => {
+            warn!("[{}] -- Missing or invalid PKCE challenge", "OauthService::authorize");
+            return redirect_error(redirect_uri, "invalid_request", &query.state);
+        }
+    };
+
+    let scope = match resolve_scope(&client, &query.scope) {
+        Some(x) => x,
+        None => {
+            warn!("[{}] -- Scope not allowed for client {}", "OauthService::authorize", client.client_id);
+            return redirect_error(redirect_uri, "invalid_scope", &query.state);
+        }
+    };
+
+    let user = match identity.map(|x| x.id()) {
+        Some(Ok(name)) => users::database::get_user(users::Mode::Username(name)).await,
+        _ => {
+            info!("[{}] -- No active session", "OauthService::authorize");
+            return redirect_error(redirect_uri, "login_required", &query.state);
+        }
+    };
+    let user = match user {
+        Ok(x) => x,
+        Err(e) => {
+            error!("[{}] -- Session user not found: {}", "OauthService::authorize", e);
+            return redirect_error(redirect_uri, "server_error", &query.state);
+        }
+    };
+
+    let code = generate_token();
+    let creation = database::create_authorization_code(&database::NewAuthorizationCode {
+        code_hash: &hash_token(&code),
+        client_id: client.id,
+        user_id: user.id,
+        redirect_uri,
+        scope: &scope,
+        code_challenge,
+        nonce: query.nonce.as_deref(),
+        expires_at: Utc::now().naive_utc() + Duration::seconds(*OAUTH_CODE_TTL),
+    });
+    if let Err(e) = creation {
+        error!("[{}] -- Authorization code creation failed: {}", "OauthService::authorize", e);
+        return redirect_error(redirect_uri, "server_error", &query.state);
+    }
+
+    info!("[{}] -- Authorization code issued to client {}", "OauthService::authorize", client.client_id);
+    match &query.state {
+        Some(state) => redirect(redirect_uri, &[("code", &code), ("state", state)]),
+        None => redirect(redirect_uri, &[("code", &code)]),
+    }
+}
+
+/// Register a new client, confidential clients get a generated secret returned in clear
+pub fn create_client(client_id: &str, name: &str, redirect_uris: &[String], scopes: &[String], confidential: bool) -> Result<Option<String>, diesel::result::Error> {
+    let secret = confidential.then(generate_token);
+    let secret_hash = secret.as_deref().map(generate_hash);
+
+    database::create_client(client_id, name, redirect_uris, scopes, secret_hash.as_deref())?;
+    info!("[{}] -- Created client {}", "OauthService::create_client", client_id);
+    Ok(secret)
+}
+
+fn invalid_client() -> HttpResponse {
+    HttpResponse::Unauthorized()
+        .insert_header((header::WWW_AUTHENTICATE, "Basic"))
+        .json(OauthError { error: "invalid_client", error_description: None })
+}
+
+/// client_secret_basic credentials from the Authorization header
+fn basic_credentials(req: &HttpRequest) -> Option<(String, String)> {
+    let encoded = req.headers()
+        .get(header::AUTHORIZATION)?
+        .to_str().ok()?
+        .strip_prefix("Basic ")?;
+    let decoded = String::from_utf8(STANDARD.decode(encoded).ok()?).ok()?;
+    let (client_id, client_secret) = decoded.split_once(':')?;
+
+    Some((client_id.to_string(), client_secret.to_string()))
+}
+
+/// Identify the calling client, confidential clients must present their secret
+/// either with HTTP Basic or in the request body
+fn authenticate_client(req: &HttpRequest, client_id: Option<&str>, client_secret: Option<&str>) -> Option<OauthClient> {
+    let (client_id, client_secret) = match basic_credentials(req) {
+        Some((id, secret)) => (id, Some(secret)),
+        None => (client_id?.to_string(), client_secret.map(String::from)),
+    };
+
+    let client = match database::find_client(&client_id) {
+        Ok(x) => x,
+        Err(e) => {
+            warn!("[{}] -- Unknown client {}: {}", "OauthService::authenticate_client", client_id, e);
+            return None;
+        }
+    };
+
+    match (&client.client_secret, client_secret) {
+        (None, _) => Some(client),
+        (Some(hash), Some(secret)) if verify_password(secret.as_bytes(), hash).is_ok() => Some(client),
+        _ => {
+            warn!("[{}] -- Client authentication failed for {}", "OauthService::authenticate_client", client_id);
+            None
+        }
+    }
+}
+
+fn token_response(response: TokenResponse) -> HttpResponse {
+    HttpResponse::Ok()
+        .insert_header((header::CACHE_CONTROL, "no-store"))
+        .json(response)
+}
+
+fn authorization_code_grant(req: &HttpRequest, form: &TokenRequest) -> HttpResponse {
+    let (code, redirect_uri, code_verifier) = match (&form.code, &form.redirect_uri, &form.code_verifier) {
+        (Some(a), Some(b), Some(c)) => (a, b, c),
+        _ => return error_response("invalid_request", "code, redirect_uri and code_verifier are required"),
+    };
+
+    let client = match authenticate_client(req, form.client_id.as_deref(), form.client_secret.as_deref()) {
+        Some(client) => client,
+        None => return invalid_client(),
+    };
+
+    let now = Utc::now().naive_utc();
+    let authorization = match database::find_authorization_code(&hash_token(code)) {
+        Ok(x) => x,
+        Err(e) => {
+            warn!("[{}] -- Unknown authorization code: {}", "OauthService::token", e);
+            return error_response("invalid_grant", "Invalid authorization code");
+        }
+    };
+
+    match database::use_authorization_code(authorization.id, now) {
+        Ok(true) => {},
+        Ok(false) => {
+            warn!("[{}] -- Authorization code replayed", "OauthService::token");
+            return error_response("invalid_grant", "Invalid authorization code");
+        },
+        Err(e) => {
+            error!("[{}] -- {}", "OauthService::token", e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    }
+
+    if authorization.expires_at < now
+        || authorization.client_id != client.id
+        || &authorization.redirect_uri != redirect_uri {
+        warn!("[{}] -- Authorization code expired or issued for another client", "OauthService::token");
+        return error_response("invalid_grant", "Invalid authorization code");
+    }
+
+    if !verify_pkce(code_verifier, &authorization.code_challenge) {
+        warn!("[{}] -- PKCE verification failed", "OauthService::token");
+        return error_response("invalid_grant", "Invalid code_verifier");
+    }
+
+    let subject = authorization.user_id.to_string();
+    let scope = Some(authorization.scope.as_str());
+    let openid = *OIDC_ENABLED && authorization.scope.split_whitespace().any(|x| x == "openid");
+    let tokens = refresh::issue(authorization.user_id, Some(client.id), None, scope)
+        .map_err(|e| e.to_string())
+        .and_then(|refresh_token| {
+            let access_token = jwt::issue_access_token(&subject, Some(&client.client_id), scope).map_err(|e| e.to_string())?;
+            let id_token = match openid {
+                true => Some(jwt::issue_id_token(&subject, &client.client_id, authorization.nonce.as_deref()).map_err(|e| e.to_string())?),
+                false => None,
+            };
+            Ok((access_token, refresh_token, id_token))
+        });
+
+    match tokens {
+        Ok((access_token, refresh_token, id_token)) => {
+            info!("[{}] -- Tokens issued to client {}", "OauthService::token", client.client_id);
+            token_response(
+                TokenResponse::bearer(access_token, Some(refresh_token))
+                    .with_scope(Some(authorization.scope))
+                    .with_id_token(id_token)
+            )
+        },
+        Err(e) => {
+            error!("[{}] -- Token issuance failed: {}", "OauthService::token", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+fn refresh_token_grant(req: &HttpRequest, form: &TokenRequest) -> HttpResponse {
+    let refresh_token = match &form.refresh_token {
+        Some(x) => x,
+        None => return error_response("invalid_request", "refresh_token is required"),
+    };
+
+    let client = match authenticate_client(req, form.client_id.as_deref(), form.client_secret.as_deref()) {
+        Some(client) => client,
+        None => return invalid_client(),
+    };
+
+    match refresh::exchange(refresh_token, Some(&client)) {
+        Ok(response) => token_response(response),
+        Err(RefreshError::Invalid) => error_response("invalid_grant", "Invalid refresh token"),
+        Err(RefreshError::Internal) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+/// Machine to machine: the client authenticates with its own credentials, no user involved
+fn client_credentials_grant(req: &HttpRequest, form: &TokenRequest) -> HttpResponse {
+    let client = match authenticate_client(req, form.client_id.as_deref(), form.client_secret.as_deref()) {
+        Some(client) => client,
+        None => return invalid_client(),
+    };
+
+    if client.client_secret.is_none() {
+        warn!("[{}] -- Public client {} can't use client_credentials", "OauthService::token", client.client_id);
+        return error_response("unauthorized_client", "client_credentials requires a confidential client");
+    }
+
+    let scope = match resolve_scope(&client, &form.scope) {
+        Some(x) => x,
+        None => {
+            warn!("[{}] -- Scope not allowed for client {}", "OauthService::token", client.client_id);
+            return error_response("invalid_scope", "Scope not allowed for this client");
+        }
+    };
+
+    match jwt::issue_client_access_token(&client.client_id, Some(&scope)) {
+        Ok(access_token) => {
+            info!("[{}] -- Access token issued to client {}", "OauthService::token", client.client_id);
+            token_response(TokenResponse::bearer(access_token, None).with_scope(Some(scope)))
+        },
+        Err(e) => {
+            error!("[{}] -- Access token signing failed: {}", "OauthService::token", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+pub async fn token(req: HttpRequest, form: web::Form<TokenRequest>) -> HttpResponse {
+    info!("[{}] -- Token request, grant type: {}", "OauthService::token", form.grant_type);
+
+    match form.grant_type.as_str() {
+        "authorization_code" => authorization_code_grant(&req, &form),
+        "refresh_token" => refresh_token_grant(&req, &form),
+        "client_credentials" => client_credentials_grant(&req, &form),
+        _ => error_response("unsupported_grant_type", "Unsupported grant_type"),
+    }
+}
+
+fn introspect_refresh_token(token: &str) -> Introspection {
+    let token = match users::database::find_refresh_token(&hash_token(token)) {
+        Ok(x) => x,
+        Err(_e) => return Introspection::default(),
+    };
+
+    let now = Utc::now().naive_utc();
+    if token.used_at.is_some() || token.revoked_at.is_some() || token.expires_at < now {
+        return Introspection::default();
+    }
+
+    Introspection {
+        active: true,
+        scope: token.scope,
+        sub: Some(token.user_id.to_string()),
+        token_type: Some("refresh_token"),
+        exp: Some(token.expires_at.and_utc().timestamp()),
+        iat: Some(token.created_at.and_utc().timestamp()),
+        ..Introspection::default()
+    }
+}
+
+/// Resource servers ask whether a token is still active, only confidential clients may call it
+pub async fn introspect(req: HttpRequest, form: web::Form<TokenActionRequest>) -> HttpResponse {
+    let client = match authenticate_client(&req, form.client_id.as_deref(), form.client_secret.as_deref()) {
+        Some(client) if client.client_secret.is_some() => client,
+        _ => return invalid_client(),
+    };
+    info!("[{}] -- Introspection requested by client {}", "OauthService::introspect", client.client_id);
+
+    let response = match is_jwt(&form.token) {
+        true => match revocation::active_access_token(&form.token) {
+            Some(claims) => Introspection {
+                active: true,
+                scope: claims.scope,
+                client_id: claims.client_id,
+                sub: Some(claims.sub),
+                token_type: Some("Bearer"),
+                exp: Some(claims.exp),
+                iat: Some(claims.iat),
+                iss: Some(claims.iss),
+                aud: Some(claims.aud),
+                jti: Some(claims.jti),
+            },
+            None => Introspection::default(),
+        },
+        false => introspect_refresh_token(&form.token),
+    };
+
+    HttpResponse::Ok()
+        .insert_header((header::CACHE_CONTROL, "no-store"))
+        .json(response)
+}
+
+/// Token named in a revocation request
+pub enum Revocable {
+    Access(Claims),
+    Refresh(RefreshToken),
+}
+
+impl Revocable {
+    pub fn find(token: &str) -> Option<Revocable> {
+        match is_jwt(token) {
+            true => jwt::decode_access_token(token).ok().map(Revocable::Access),
+            false => users::database::find_refresh_token(&hash_token(token)).ok().map(Revocable::Refresh),
+        }
+    }
+
+    /// Clients may only revoke their own tokens (RFC 7009 section 2.1)
+    fn issued_to(&self, client: &OauthClient) -> bool {
+        match self {
+            Revocable::Access(claims) => claims.client_id.as_deref() == Some(client.client_id.as_str()),
+            Revocable::Refresh(token) => token.client_id == Some(client.id),
+        }
+    }
+
+    /// First party tokens of the user, from /users/auth or /users/token/refresh
+    pub fn owned_by(&self, user_id: i32) -> bool {
+        match self {
+            Revocable::Access(claims) => claims.client_id.is_none() && claims.user_id() == Some(user_id),
+            Revocable::Refresh(token) => token.client_id.is_none() && token.user_id == user_id,
+        }
+    }
+
+    /// Deny an access token until it expires, or a refresh token along with its whole family
+    pub fn revoke(&self) -> Result<(), StatusCode> {
+        match self {
+            Revocable::Access(claims) => {
+                if let Err(e) = revocation::revoke_access_token(claims) {
+                    error!("[{}] -- Access token revocation failed: {}", "OauthService::revoke", e);
+                    return Err(StatusCode::SERVICE_UNAVAILABLE);
+                }
+                info!("[{}] -- Access token {} revoked", "OauthService::revoke", claims.jti);
+            },
+            Revocable::Refresh(token) => {
+                if let Err(e) = users::database::revoke_refresh_token_family(&token.family_id, Utc::now().naive_utc()) {
+                    error!("[{}] -- Refresh token revocation failed: {}", "OauthService::revoke", e);
+                    return Err(StatusCode::INTERNAL_SERVER_ERROR);
+                }
+                info!("[{}] -- Refresh token family {} revoked", "OauthService::revoke", token.family_id);
+            },
+        }
+        Ok(())
+    }
+}
+
+/// Revoke an access token, or a refresh token along with its whole family.
+/// Unknown or invalid tokens, and those of other clients, are ignored as required by RFC 7009.
+pub async fn revoke(req: HttpRequest, form: web::Form<TokenActionRequest>) -> HttpResponse {
+    let client = match authenticate_client(&req, form.client_id.as_deref(), form.client_secret.as_deref()) {
+        Some(client) => client,
+        None => return invalid_client(),
+    };
+    info!("[{}] -- Revocation requested by client {}", "OauthService::revoke", client.client_id);
+
+    match Revocable::find(&form.token) {
+        Some(token) if !token.issued_to(&client) => {
+            warn!("[{}] -- Token issued to another client, ignored", "OauthService::revoke");
+        },
+        Some(token) => {
+            if let Err(status) = token.revoke() {
+                return HttpResponse::build(status).finish();
+            }
+        },
+        None => {},
+    }
+
+    HttpResponse::Ok().finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verify_pkce() {
+        // RFC 7636, Appendix B
+        let verifier = "dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk";
+        let challenge = "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM";
+        assert!(verify_pkce(verifier, challenge));
+        assert!(!verify_pkce("dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXK", challenge));
+        assert!(!verify_pkce("short", challenge));
+    }
+
+    fn client(id: i32, client_id: &str) -> OauthClient {
+        let now = Utc::now().naive_utc();
+        OauthClient {
+            id,
+            client_id: client_id.to_string(),
+            name: client_id.to_string(),
+            redirect_uris: vec![],
+            scopes: vec![],
+            created_at: now,
+            updated_at: now,
+            client_secret: None,
+        }
+    }
+
+    #[test]
+    fn test_revoke_other_client() {
+        let (app, other) = (client(1, "app"), client(2, "other"));
+        let now = Utc::now().naive_utc();
+        let claims = |client_id: Option<&str>| Claims {
+            jti: generate_token(),
+            sub: String::from("42"),
+            iat: now.and_utc().timestamp(),
+            exp: now.and_utc().timestamp() + 60,
+            iss: String::new(),
+            aud: String::new(),
+            scope: None,
+            client_id: client_id.map(String::from),
+            client_credentials: false,
+        };
+        let refresh_token = |client_id: Option<i32>| RefreshToken {
+            id: 1,
+            user_id: 42,
+            family_id: generate_token(),
+            token_hash: hash_token("token"),
+            created_at: now,
+            expires_at: now,
+            used_at: None,
+            revoked_at: None,
+            scope: None,
+            client_id,
+        };
+
+        assert!(Revocable::Access(claims(Some("app"))).issued_to(&app));
+        assert!(!Revocable::Access(claims(Some("app"))).issued_to(&other));
+        assert!(!Revocable::Access(claims(None)).issued_to(&app));
+        assert!(Revocable::Refresh(refresh_token(Some(1))).issued_to(&app));
+        assert!(!Revocable::Refresh(refresh_token(Some(1))).issued_to(&other));
+        assert!(!Revocable::Refresh(refresh_token(None)).issued_to(&app));
+
+        // First party tokens are revoked by their user, through the session
+        assert!(Revocable::Access(claims(None)).owned_by(42));
+        assert!(!Revocable::Access(claims(None)).owned_by(7));
+        assert!(!Revocable::Access(claims(Some("app"))).owned_by(42));
+        assert!(Revocable::Refresh(refresh_token(None)).owned_by(42));
+        assert!(!Revocable::Refresh(refresh_token(None)).owned_by(7));
+        assert!(!Revocable::Refresh(refresh_token(Some(1))).owned_by(42));
+    }
+}
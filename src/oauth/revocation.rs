@@ -0,0 +1,117 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use chrono::Utc;
+use lazy_static::lazy_static;
+use log::{error, warn};
+use redis::{Commands, RedisResult};
+
+use crate::cache::get_connection;
+use crate::jwt::{self, Claims};
+use crate::local_env::REDIS_HOST;
+use crate::users::database;
+
+/// Where revoked access tokens are remembered
+enum Denylist {
+    /// Shared between replicas
+    Redis,
+    /// Without REDIS_HOST, jti to expiration. Only this node knows about them.
+    Memory(Mutex<HashMap<String, i64>>),
+}
+
+lazy_static! {
+    static ref DENYLIST: Denylist = match REDIS_HOST.as_ref() {
+        Some(_host) => Denylist::Redis,
+        None => Denylist::Memory(Mutex::new(HashMap::new())),
+    };
+}
+
+/// Warn at startup when revocations aren't shared
+pub fn check_denylist() {
+    if let Denylist::Memory(_revoked) = &*DENYLIST {
+        warn!("[{}] -- No REDIS_HOST, revoked access tokens are only known to this node", "OauthService::revocation");
+    }
+}
+
+fn revoked_key(jti: &str) -> String {
+    format!("kz-auth:revoked:{}", jti)
+}
+
+/// Remember a revoked access token until it would have expired anyway
+pub fn revoke_access_token(claims: &Claims) -> RedisResult<()> {
+    let ttl = claims.exp - Utc::now().timestamp();
+    if ttl <= 0 || claims.jti.is_empty() {
+        return Ok(());
+    }
+
+    match &*DENYLIST {
+        Denylist::Redis => {
+            let mut conn = get_connection()?;
+            conn.set_ex(revoked_key(&claims.jti), 1, ttl as usize)
+        },
+        Denylist::Memory(revoked) => {
+            let mut revoked = revoked.lock().unwrap();
+            let now = Utc::now().timestamp();
+            revoked.retain(|_jti, exp| *exp > now);
+            revoked.insert(claims.jti.clone(), claims.exp);
+            Ok(())
+        }
+    }
+}
+
+pub fn is_revoked(jti: &str) -> RedisResult<bool> {
+    match &*DENYLIST {
+        Denylist::Redis => {
+            let mut conn = get_connection()?;
+            conn.exists(revoked_key(jti))
+        },
+        Denylist::Memory(revoked) => Ok(revoked.lock().unwrap().contains_key(jti)),
+    }
+}
+
+/// Whether the token was issued before the credentials of its user were revoked.
+/// `iat` has no fraction, a token from the same second is refused.
+fn predates_revocation(claims: &Claims) -> Result<bool, diesel::result::Error> {
+    let user_id = match claims.user_id() {
+        Some(x) => x,
+        None => return Ok(false),
+    };
+    let revoked_at = database::get_credentials_revoked_at(user_id)?;
+    Ok(revoked_at.is_some_and(|x| claims.iat <= x.and_utc().timestamp()))
+}
+
+/// Decode an access token and make sure it has not been revoked, on its own
+/// or with the credentials of its user. Fails closed when the revocation
+/// store can't be reached.
+pub fn active_access_token(token: &str) -> Option<Claims> {
+    let claims = match jwt::decode_access_token(token) {
+        Ok(x) => x,
+        Err(e) => {
+            warn!("[{}] -- Invalid access token: {}", "OauthService::active_access_token", e);
+            return None;
+        }
+    };
+
+    match is_revoked(&claims.jti) {
+        Ok(false) => {},
+        Ok(true) => {
+            warn!("[{}] -- Revoked access token {}", "OauthService::active_access_token", claims.jti);
+            return None;
+        },
+        Err(e) => {
+            error!("[{}] -- Revocation store unavailable: {}", "OauthService::active_access_token", e);
+            return None;
+        }
+    }
+
+    match predates_revocation(&claims) {
+        Ok(false) => Some(claims),
+        Ok(true) => {
+            warn!("[{}] -- Access token {} issued before the credentials were revoked", "OauthService::active_access_token", claims.jti);
+            None
+        },
+        Err(e) => {
+            error!("[{}] -- {}", "OauthService::active_access_token", e);
+            None
+        }
+    }
+}
@@ -0,0 +1,149 @@
+use actix_web::{web, HttpResponse, HttpRequest, http::header};
+use log::{error, warn, info};
+use serde::Serialize;
+use serde_json::json;
+
+use crate::jwt;
+use crate::local_env::{JWT_ISSUER, OIDC_ENABLED, PUBLIC_URL};
+use crate::oauth::revocation;
+use crate::users::{self, Mode};
+
+#[derive(Serialize)]
+struct UserInfo {
+    sub: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    preferred_username: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    email: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    email_verified: Option<bool>,
+}
+
+/// Relying parties only accept an https issuer, without query or fragment
+fn is_valid_issuer(issuer: &str) -> bool {
+    issuer.strip_prefix("https://")
+        .is_some_and(|x| !x.is_empty() && !x.starts_with('/') && !x.contains(['?', '#']))
+}
+
+/// Fail fast on an issuer the discovery document can't advertise
+pub fn check_config() {
+    if *OIDC_ENABLED && !is_valid_issuer(&JWT_ISSUER) {
+        panic!("[{}] -- JWT_ISSUER must be an https URL with OpenID Connect, got {}", "Oidc", *JWT_ISSUER);
+    }
+}
+
+pub fn oidc_config(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::resource("/.well-known/openid-configuration")
+            .route(web::get().to(discovery))
+    );
+    cfg.service(
+        web::resource("/.well-known/jwks.json")
+            .route(web::get().to(jwks))
+    );
+    cfg.service(
+        web::resource("/userinfo")
+            .route(web::get().to(userinfo))
+            .route(web::post().to(userinfo))
+    );
+}
+
+pub async fn discovery() -> HttpResponse {
+    let base = PUBLIC_URL.trim_end_matches('/');
+
+    HttpResponse::Ok().json(json!({
+        "issuer": *JWT_ISSUER,
+        "authorization_endpoint": format!("{}/oauth/authorize", base),
+        "token_endpoint": format!("{}/oauth/token", base),
+        "userinfo_endpoint": format!("{}/userinfo", base),
+        "jwks_uri": format!("{}/.well-known/jwks.json", base),
+        "response_types_supported": ["code"],
+        "grant_types_supported": ["authorization_code", "refresh_token", "client_credentials"],
+        "subject_types_supported": ["public"],
+        "id_token_signing_alg_values_supported": [jwt::algorithm()],
+        "scopes_supported": ["openid", "profile", "email"],
+        "token_endpoint_auth_methods_supported": ["none", "client_secret_basic", "client_secret_post"],
+        "code_challenge_methods_supported": ["S256"],
+        "introspection_endpoint": format!("{}/oauth/introspect", base),
+        "revocation_endpoint": format!("{}/oauth/revoke", base),
+        "claims_supported": ["sub", "iss", "aud", "exp", "iat", "nonce", "preferred_username", "email", "email_verified"],
+    }))
+}
+
+pub async fn jwks() -> HttpResponse {
+    HttpResponse::Ok().json(jwt::jwks())
+}
+
+fn bearer_token(req: &HttpRequest) -> Option<&str> {
+    req.headers()
+        .get(header::AUTHORIZATION)?
+        .to_str().ok()?
+        .strip_prefix("Bearer ")
+}
+
+fn bearer_error(error: &str) -> HttpResponse {
+    HttpResponse::Unauthorized()
+        .insert_header((header::WWW_AUTHENTICATE, format!("Bearer error=\"{}\"", error)))
+        .finish()
+}
+
+pub async fn userinfo(req: HttpRequest) -> HttpResponse {
+    let claims = match bearer_token(&req).map(revocation::active_access_token) {
+        Some(Some(claims)) => claims,
+        Some(None) => return bearer_error("invalid_token"),
+        None => {
+            return HttpResponse::Unauthorized()
+                .insert_header((header::WWW_AUTHENTICATE, "Bearer"))
+                .finish();
+        }
+    };
+
+    let scopes: Vec<&str> = claims.scope.as_deref().unwrap_or("").split_whitespace().collect();
+    if !scopes.contains(&"openid") {
+        warn!("[{}] -- Access token without openid scope", "OidcService::userinfo");
+        return HttpResponse::Forbidden()
+            .insert_header((header::WWW_AUTHENTICATE, "Bearer error=\"insufficient_scope\""))
+            .finish();
+    }
+
+    // Tokens clients got for themselves have no user behind them
+    let user_id = match claims.user_id() {
+        Some(x) => x,
+        None => {
+            warn!("[{}] -- Access token without a user", "OidcService::userinfo");
+            return bearer_error("invalid_token");
+        }
+    };
+    let user = match users::database::get_user(Mode::Id(user_id)).await {
+        Ok(x) => x,
+        Err(e) => {
+            error!("[{}] -- Token subject not found: {}", "OidcService::userinfo", e);
+            return bearer_error("invalid_token");
+        }
+    };
+
+    info!("[{}] -- User info for user {}", "OidcService::userinfo", user.id);
+    let profile = scopes.contains(&"profile");
+    let email = scopes.contains(&"email");
+    HttpResponse::Ok().json(UserInfo {
+        sub: user.id.to_string(),
+        preferred_username: profile.then_some(user.name),
+        email: email.then_some(user.email),
+        email_verified: email.then_some(user.email_verified_at.is_some()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_valid_issuer() {
+        assert!(is_valid_issuer("https://auth.example.com"));
+        assert!(is_valid_issuer("https://example.com/auth"));
+        assert!(!is_valid_issuer("kz-auth"));
+        assert!(!is_valid_issuer("http://auth.example.com"));
+        assert!(!is_valid_issuer("https://"));
+        assert!(!is_valid_issuer("https://auth.example.com?tenant=1"));
+    }
+}
@@ -0,0 +1,332 @@
+use lazy_static::lazy_static;
+use serde::Serialize;
+
+use crate::breached;
+use crate::local_env::*;
+
+lazy_static! {
+    static ref POLICY: Policy = Policy::from_config();
+}
+
+/// Most used passwords and the words they are built from, by rank
+const COMMON: &[&str] = &[
+    "123456", "password", "12345678", "qwerty", "123456789", "12345", "1234", "111111", "1234567", "dragon",
+    "123123", "baseball", "abc123", "football", "monkey", "letmein", "696969", "shadow", "master", "666666",
+    "qwertyuiop", "123321", "mustang", "1234567890", "michael", "654321", "superman", "1qaz2wsx", "7777777", "121212",
+    "000000", "qazwsx", "123qwe", "killer", "trustno1", "jordan", "jennifer", "zxcvbnm", "asdfgh", "hunter",
+    "buster", "soccer", "harley", "batman", "andrew", "tigger", "sunshine", "iloveyou", "charlie", "robert",
+    "thomas", "hockey", "ranger", "daniel", "starwars", "112233", "george", "computer", "michelle", "jessica",
+    "pepper", "zxcvbn", "555555", "11111111", "131313", "freedom", "777777", "pass", "maggie", "159753",
+    "aaaaaa", "ginger", "princess", "joshua", "cheese", "amanda", "summer", "love", "ashley", "nicole",
+    "chelsea", "matthew", "access", "yankees", "987654321", "dallas", "austin", "thunder", "taylor", "matrix",
+    "admin", "welcome", "login", "hello", "secret", "azerty", "changeme", "winter", "spring", "autumn",
+    "flower", "orange", "banana", "purple", "silver", "golden", "diamond", "angel", "baby", "qwerty123",
+];
+
+/// Rows of a qwerty keyboard, for runs like "asdf"
+const KEYBOARD_ROWS: [&str; 4] = ["1234567890", "qwertyuiop", "asdfghjkl", "zxcvbnm"];
+
+/// log10 of the guesses per character outside of a known pattern, as in zxcvbn
+const BRUTE_FORCE: f64 = 1.0;
+
+/// Common substitutions, undone before looking up the dictionary
+const LEET: [(char, char); 9] = [('4', 'a'), ('@', 'a'), ('3', 'e'), ('1', 'i'), ('!', 'i'), ('0', 'o'), ('$', 's'), ('5', 's'), ('7', 't')];
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum CharClass {
+    Lowercase,
+    Uppercase,
+    Digit,
+    Symbol,
+}
+
+impl CharClass {
+    fn parse(name: &str) -> CharClass {
+        match name {
+            "lowercase" => CharClass::Lowercase,
+            "uppercase" => CharClass::Uppercase,
+            "digit" => CharClass::Digit,
+            "symbol" => CharClass::Symbol,
+            x => panic!("[{}] -- Unknown password class {}, expected lowercase, uppercase, digit or symbol", "PasswordPolicy", x),
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            CharClass::Lowercase => "lowercase",
+            CharClass::Uppercase => "uppercase",
+            CharClass::Digit => "digit",
+            CharClass::Symbol => "symbol",
+        }
+    }
+
+    fn of(c: char) -> CharClass {
+        if c.is_lowercase() {
+            CharClass::Lowercase
+        } else if c.is_uppercase() {
+            CharClass::Uppercase
+        } else if c.is_numeric() {
+            CharClass::Digit
+        } else {
+            CharClass::Symbol
+        }
+    }
+
+    /// Characters an attacker has to try for one position
+    fn cardinality(&self) -> f64 {
+        match self {
+            CharClass::Lowercase | CharClass::Uppercase => 26.0,
+            CharClass::Digit => 10.0,
+            CharClass::Symbol => 33.0,
+        }
+    }
+}
+
+/// A broken rule, returned to the client
+#[derive(Serialize, Debug)]
+pub struct Violation {
+    pub rule: &'static str,
+    pub message: String,
+}
+
+#[derive(Serialize, Debug)]
+pub struct PolicyError {
+    pub error: &'static str,
+    pub violations: Vec<Violation>,
+}
+
+pub struct Policy {
+    min_length: usize,
+    max_length: usize,
+    classes: Vec<CharClass>,
+    min_score: u8,
+}
+
+impl Policy {
+    fn from_config() -> Policy {
+        Policy {
+            min_length: *PASSWORD_MIN_LENGTH,
+            max_length: *PASSWORD_MAX_LENGTH,
+            classes: PASSWORD_REQUIRED_CLASSES.split(',')
+                .map(str::trim)
+                .filter(|x| !x.is_empty())
+                .map(CharClass::parse)
+                .collect(),
+            min_score: *PASSWORD_MIN_SCORE,
+        }
+    }
+
+    /// Every rule the password breaks, the username and email can't be part of it
+    fn violations(&self, password: &str, username: &str, email: &str) -> Vec<Violation> {
+        let mut violations = Vec::new();
+        let length = password.chars().count();
+
+        if length < self.min_length {
+            violations.push(Violation {
+                rule: "min_length",
+                message: format!("Must be at least {} characters long", self.min_length),
+            });
+        }
+        if length > self.max_length {
+            violations.push(Violation {
+                rule: "max_length",
+                message: format!("Must be at most {} characters long", self.max_length),
+            });
+            // Not worth scoring
+            return violations;
+        }
+
+        for class in self.classes.iter() {
+            if !password.chars().any(|x| CharClass::of(x) == *class) {
+                violations.push(Violation {
+                    rule: class.name(),
+                    message: format!("Must contain a {} character", class.name()),
+                });
+            }
+        }
+
+        let lowercase = password.to_lowercase();
+        let username = username.trim().to_lowercase();
+        if username.chars().count() >= 3 && lowercase.contains(&username) {
+            violations.push(Violation {
+                rule: "contains_username",
+                message: String::from("Must not contain the username"),
+            });
+        }
+        let email = email.trim().to_lowercase();
+        let local_part = email.split('@').next().unwrap_or_default();
+        if local_part.chars().count() >= 3 && lowercase.contains(local_part) {
+            violations.push(Violation {
+                rule: "contains_email",
+                message: String::from("Must not contain the email address"),
+            });
+        }
+
+        if breached::is_breached(password) {
+            violations.push(Violation {
+                rule: "breached",
+                message: String::from("Appears in known data breaches"),
+            });
+        }
+
+        let score = score(password);
+        if score < self.min_score {
+            violations.push(Violation {
+                rule: "strength",
+                message: format!("Too easy to guess, scored {} out of 4 and needs {}", score, self.min_score),
+            });
+        }
+
+        violations
+    }
+}
+
+/// Fail fast on an invalid configuration
+pub fn check_config() {
+    lazy_static::initialize(&POLICY);
+}
+
+/// Check a new password against the configured policy
+pub fn check(password: &str, username: &str, email: &str) -> Result<(), PolicyError> {
+    let violations = POLICY.violations(password, username, email);
+    if violations.is_empty() {
+        return Ok(());
+    }
+    Err(PolicyError { error: "Password rejected", violations })
+}
+
+/// log10 of the guesses needed for a known pattern between `start` and `end` (excluded)
+fn pattern_guesses(chars: &[char], lowercase: &[char], unleet: &[char], start: usize, end: usize) -> Option<f64> {
+    let length = end - start;
+    let mut best: Option<f64> = None;
+    let mut keep = |x: f64| best = Some(best.map_or(x, |y| y.min(x)));
+
+    // Dictionary, with a penalty for capitals and substitutions
+    for (word, substituted) in [(&lowercase[start..end], false), (&unleet[start..end], true)] {
+        let word: String = word.iter().collect();
+        if let Some(rank) = COMMON.iter().position(|x| *x == word) {
+            let mut guesses = ((rank + 1) as f64).log10();
+            if chars[start..end].iter().any(|x| x.is_uppercase()) {
+                guesses += 2f64.log10();
+            }
+            if substituted {
+                guesses += 2f64.log10();
+            }
+            keep(guesses);
+        }
+    }
+
+    if length < 3 {
+        return best;
+    }
+    let run = &lowercase[start..end];
+
+    // Repeated character
+    if run.iter().all(|x| *x == run[0]) {
+        keep((CharClass::of(run[0]).cardinality() * length as f64).log10());
+    }
+
+    // Sequence like "abcd" or "9876"
+    let step = run[1] as i64 - run[0] as i64;
+    if step.abs() == 1 && run.windows(2).all(|x| x[1] as i64 - x[0] as i64 == step) {
+        keep((CharClass::of(run[0]).cardinality() * length as f64 * 2.0).log10());
+    }
+
+    // Keyboard row, in either direction
+    let text: String = run.iter().collect();
+    let reversed: String = run.iter().rev().collect();
+    if KEYBOARD_ROWS.iter().any(|x| x.contains(&text) || x.contains(&reversed)) {
+        keep((KEYBOARD_ROWS.len() as f64 * 10.0 * length as f64 * 2.0).log10());
+    }
+
+    // Recent year
+    if length == 4 {
+        if let Ok(year) = text.parse::<u32>() {
+            if (1900..2100).contains(&year) {
+                keep(200f64.log10());
+            }
+        }
+    }
+
+    best
+}
+
+/// Strength from 0 to 4, after zxcvbn: the password is covered with the
+/// cheapest sequence of known patterns and brute forced characters, and the
+/// resulting guess count is bucketed.
+pub fn score(password: &str) -> u8 {
+    let chars: Vec<char> = password.chars().collect();
+    let lowercase: Vec<char> = chars.iter().flat_map(|x| x.to_lowercase()).collect();
+    if lowercase.len() != chars.len() {
+        // Case mapping changed the length, only brute force makes sense
+        return bucket(chars.len() as f64 * BRUTE_FORCE);
+    }
+    let unleet: Vec<char> = lowercase.iter()
+        .map(|x| LEET.iter().find(|(from, _)| from == x).map_or(*x, |(_, to)| *to))
+        .collect();
+
+    // best[i]: log10 of the guesses to cover the first i characters
+    let mut best = vec![0f64; chars.len() + 1];
+    for end in 1..=chars.len() {
+        best[end] = best[end - 1] + BRUTE_FORCE;
+        for start in 0..end {
+            if let Some(guesses) = pattern_guesses(&chars, &lowercase, &unleet, start, end) {
+                best[end] = best[end].min(best[start] + guesses);
+            }
+        }
+    }
+
+    bucket(best[chars.len()])
+}
+
+/// Same thresholds as zxcvbn, on log10 of the guesses
+fn bucket(guesses: f64) -> u8 {
+    match guesses {
+        x if x < 3.0 => 0,
+        x if x < 6.0 => 1,
+        x if x < 8.0 => 2,
+        x if x < 10.0 => 3,
+        _ => 4,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy() -> Policy {
+        Policy {
+            min_length: 8,
+            max_length: 64,
+            classes: vec![CharClass::Digit],
+            min_score: 3,
+        }
+    }
+
+    fn rules(password: &str) -> Vec<&'static str> {
+        policy().violations(password, "valentin", "val.k@example.com").iter().map(|x| x.rule).collect()
+    }
+
+    #[test]
+    fn test_score() {
+        assert_eq!(score("password"), 0);
+        assert_eq!(score("P4ssw0rd"), 0);
+        assert_eq!(score("aaaaaaaaaaaa"), 0);
+        assert_eq!(score("abcdefgh"), 0);
+        assert_eq!(score("qwertyuiop"), 0);
+        assert_eq!(score("12345678"), 0);
+        assert!(score("iloveyou2024") <= 1);
+        assert_eq!(score("correct horse battery staple"), 4);
+        assert_eq!(score("kX9#mQ2$vL7@"), 4);
+    }
+
+    #[test]
+    fn test_violations() {
+        assert_eq!(rules("short1"), vec!["min_length", "strength"]);
+        assert_eq!(rules("no digits in this long passphrase"), vec!["digit"]);
+        assert_eq!(rules("Valentin-rides-2-bikes"), vec!["contains_username"]);
+        assert_eq!(rules("val.k and 7 dwarves sing"), vec!["contains_email"]);
+        assert_eq!(rules(&"x1".repeat(40)), vec!["max_length"]);
+        assert!(rules("7 ducks cross the old bridge").is_empty());
+    }
+}
@@ -0,0 +1,208 @@
+use actix_web::{
+    body::{EitherBody, MessageBody},
+    dev::{Payload, ServiceRequest, ServiceResponse},
+    http::header,
+    middleware::Next,
+    web, HttpResponse
+};
+use chrono::Utc;
+use lazy_static::lazy_static;
+use log::{error, warn};
+use redis::{RedisResult, Script};
+
+use crate::cache::get_connection;
+use crate::hashing::hash_token;
+use crate::local_env::*;
+
+lazy_static! {
+    /// Refill every bucket, then take a token from each of them if they all
+    /// have one. Returns 0 when allowed, otherwise the wait in milliseconds.
+    /// KEYS: buckets, ARGV: now (ms), then capacity and rate (tokens/ms) per bucket
+    static ref TAKE: Script = Script::new(r"
+        local now = tonumber(ARGV[1])
+        local wait = 0
+        local tokens = {}
+        for i, key in ipairs(KEYS) do
+            local capacity = tonumber(ARGV[i * 2])
+            local rate = tonumber(ARGV[i * 2 + 1])
+            local bucket = redis.call('HMGET', key, 'tokens', 'ts')
+            local available = tonumber(bucket[1]) or capacity
+            local ts = tonumber(bucket[2]) or now
+            available = math.min(capacity, available + math.max(0, now - ts) * rate)
+            if available < 1 then
+                wait = math.max(wait, math.ceil((1 - available) / rate))
+            end
+            tokens[i] = available
+        end
+        if wait > 0 then
+            return wait
+        end
+        for i, key in ipairs(KEYS) do
+            local capacity = tonumber(ARGV[i * 2])
+            local rate = tonumber(ARGV[i * 2 + 1])
+            redis.call('HSET', key, 'tokens', tostring(tokens[i] - 1), 'ts', tostring(now))
+            redis.call('PEXPIRE', key, math.ceil(capacity / rate))
+        end
+        return 0
+    ");
+}
+
+/// Body fields holding the login of the request, in order of preference
+const LOGIN_FIELDS: [&str; 3] = ["login", "email", "username"];
+
+struct Bucket {
+    key: String,
+    capacity: u32,
+    per_minute: f64,
+}
+
+fn bucket_key(kind: &str, route: &str, value: &str) -> String {
+    format!("kz-auth:ratelimit:{}:{}:{}", kind, route, value)
+}
+
+/// Login named in a JSON body, hashed so addresses don't end up in Redis keys
+fn login_identifier(body: &[u8]) -> Option<String> {
+    let value: serde_json::Value = serde_json::from_slice(body).ok()?;
+    LOGIN_FIELDS.iter()
+        .find_map(|x| value.get(x)?.as_str())
+        .map(|x| x.trim().to_lowercase())
+        .filter(|x| !x.is_empty())
+        .map(|x| hash_token(&x))
+}
+
+/// Peer address, or the one reported by the proxy when it is trusted
+fn client_ip(req: &ServiceRequest) -> String {
+    if *RATE_LIMIT_TRUST_PROXY {
+        return req.connection_info().realip_remote_addr().unwrap_or_default().to_string();
+    }
+    req.peer_addr().map(|x| x.ip().to_string()).unwrap_or_default()
+}
+
+/// Read the JSON body and put it back for the handler
+async fn read_login(req: &mut ServiceRequest) -> Result<Option<String>, actix_web::Error> {
+    let is_json = req.headers().get(header::CONTENT_TYPE)
+        .and_then(|x| x.to_str().ok())
+        .is_some_and(|x| x.starts_with("application/json"));
+    if !is_json {
+        return Ok(None);
+    }
+
+    let body = req.extract::<web::Bytes>().await?;
+    let login = login_identifier(&body);
+    req.set_payload(Payload::from(body));
+    Ok(login)
+}
+
+/// A bucket with no capacity refuses everything, one that never refills divides by zero in TAKE
+fn check_bucket(name: &str, burst: u32, per_minute: f64) -> Result<(), String> {
+    if burst == 0 {
+        return Err(format!("{}_BURST must be at least 1", name));
+    }
+    if !per_minute.is_finite() || per_minute <= 0.0 {
+        return Err(format!("{}_PER_MINUTE must be greater than 0, got {}", name, per_minute));
+    }
+    Ok(())
+}
+
+/// Fail fast on limits the script can't apply
+pub fn check_config() {
+    if !*RATE_LIMIT_ENABLED {
+        warn!("[{}] -- Rate limiting is off, authentication routes are not throttled", "RateLimit");
+        return;
+    }
+    if REDIS_HOST.is_none() {
+        panic!("[{}] -- RATE_LIMIT_ENABLED needs REDIS_HOST, unset it to run without Redis", "RateLimit");
+    }
+    let buckets = [
+        ("RATE_LIMIT_IP", *RATE_LIMIT_IP_BURST, *RATE_LIMIT_IP_PER_MINUTE),
+        ("RATE_LIMIT_LOGIN", *RATE_LIMIT_LOGIN_BURST, *RATE_LIMIT_LOGIN_PER_MINUTE),
+    ];
+    for (name, burst, per_minute) in buckets {
+        if let Err(e) = check_bucket(name, burst, per_minute) {
+            panic!("[{}] -- {}", "RateLimit", e);
+        }
+    }
+}
+
+/// Take a token from every bucket, returns the seconds to wait if one is empty
+fn take(buckets: &[Bucket]) -> RedisResult<Option<u64>> {
+    let mut conn = get_connection()?;
+    let mut invocation = TAKE.prepare_invoke();
+    invocation.arg(Utc::now().timestamp_millis());
+    for bucket in buckets {
+        invocation.key(&bucket.key)
+            .arg(bucket.capacity)
+            .arg(bucket.per_minute / 60_000.0);
+    }
+
+    let wait: u64 = invocation.invoke(&mut *conn)?;
+    Ok((wait > 0).then(|| wait.div_ceil(1000)))
+}
+
+/// Throttle a route by client IP and by the login found in the body.
+/// Requests are refused with 503 when Redis is unavailable, unless RATE_LIMIT_FAIL_OPEN is set.
+pub async fn limit(mut req: ServiceRequest, next: Next<impl MessageBody>) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, actix_web::Error> {
+    if !*RATE_LIMIT_ENABLED {
+        return next.call(req).await.map(ServiceResponse::map_into_left_body);
+    }
+
+    let route = req.match_pattern().unwrap_or_else(|| req.path().to_string());
+    let route = format!("{}:{}", req.method(), route);
+    let mut buckets = vec![Bucket {
+        key: bucket_key("ip", &route, &client_ip(&req)),
+        capacity: *RATE_LIMIT_IP_BURST,
+        per_minute: *RATE_LIMIT_IP_PER_MINUTE,
+    }];
+    if let Some(login) = read_login(&mut req).await? {
+        buckets.push(Bucket {
+            key: bucket_key("login", &route, &login),
+            capacity: *RATE_LIMIT_LOGIN_BURST,
+            per_minute: *RATE_LIMIT_LOGIN_PER_MINUTE,
+        });
+    }
+
+    match take(&buckets) {
+        Ok(None) => {},
+        Ok(Some(retry_after)) => {
+            warn!("[{}] -- Too many requests on {}", "RateLimit", route);
+            let res = HttpResponse::TooManyRequests()
+                .insert_header((header::RETRY_AFTER, retry_after.max(1).to_string()))
+                .finish();
+            return Ok(req.into_response(res).map_into_right_body());
+        },
+        Err(e) if *RATE_LIMIT_FAIL_OPEN => {
+            error!("[{}] -- Rate limit unavailable, letting {} through: {}", "RateLimit", route, e);
+        },
+        Err(e) => {
+            error!("[{}] -- Rate limit unavailable, refusing {}: {}", "RateLimit", route, e);
+            let res = HttpResponse::ServiceUnavailable().finish();
+            return Ok(req.into_response(res).map_into_right_body());
+        }
+    }
+
+    next.call(req).await.map(ServiceResponse::map_into_left_body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_login_identifier() {
+        let login = login_identifier(br#"{"login": " Valentin@Example.com ", "password": "x"}"#);
+        assert_eq!(login, Some(hash_token("valentin@example.com")));
+        assert_eq!(login_identifier(br#"{"username": "val", "email": "val@example.com"}"#), Some(hash_token("val@example.com")));
+        assert_eq!(login_identifier(br#"{"token": "x"}"#), None);
+        assert_eq!(login_identifier(b"not json"), None);
+    }
+
+    #[test]
+    fn test_check_bucket() {
+        assert!(check_bucket("RATE_LIMIT_IP", 20, 10.0).is_ok());
+        assert!(check_bucket("RATE_LIMIT_LOGIN", 5, 0.5).is_ok());
+        assert!(check_bucket("RATE_LIMIT_IP", 0, 10.0).is_err());
+        assert!(check_bucket("RATE_LIMIT_LOGIN", 5, 0.0).is_err());
+        assert!(check_bucket("RATE_LIMIT_LOGIN", 5, -1.0).is_err());
+        assert!(check_bucket("RATE_LIMIT_LOGIN", 5, f64::NAN).is_err());
+    }
+}
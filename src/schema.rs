@@ -1,4 +1,4 @@
-use diesel::table;
+use diesel::{table, joinable, allow_tables_to_appear_in_same_query};
 
 table! {
     auth.users (id) {
@@ -8,5 +8,150 @@ table! {
         password -> Varchar,
         created_at -> Timestamp,
         updated_at -> Timestamp,
+        email_verified_at -> Nullable<Timestamp>,
+        failed_logins -> Int4,
+        locked_until -> Nullable<Timestamp>,
+        is_admin -> Bool,
+        mfa_attempts -> Int4,
+        credentials_revoked_at -> Nullable<Timestamp>,
     }
 }
+
+table! {
+    auth.refresh_tokens (id) {
+        id -> Int4,
+        user_id -> Int4,
+        family_id -> Varchar,
+        token_hash -> Varchar,
+        created_at -> Timestamp,
+        expires_at -> Timestamp,
+        used_at -> Nullable<Timestamp>,
+        revoked_at -> Nullable<Timestamp>,
+        scope -> Nullable<Varchar>,
+        client_id -> Nullable<Int4>,
+    }
+}
+
+table! {
+    auth.oauth_clients (id) {
+        id -> Int4,
+        client_id -> Varchar,
+        name -> Varchar,
+        redirect_uris -> Array<Text>,
+        scopes -> Array<Text>,
+        created_at -> Timestamp,
+        updated_at -> Timestamp,
+        client_secret -> Nullable<Varchar>,
+    }
+}
+
+table! {
+    auth.oauth_authorization_codes (id) {
+        id -> Int4,
+        code_hash -> Varchar,
+        client_id -> Int4,
+        user_id -> Int4,
+        redirect_uri -> Text,
+        scope -> Varchar,
+        code_challenge -> Varchar,
+        created_at -> Timestamp,
+        expires_at -> Timestamp,
+        used_at -> Nullable<Timestamp>,
+        nonce -> Nullable<Varchar>,
+    }
+}
+
+table! {
+    auth.user_totp (id) {
+        id -> Int4,
+        user_id -> Int4,
+        secret -> Varchar,
+        created_at -> Timestamp,
+        confirmed_at -> Nullable<Timestamp>,
+        last_used_step -> Nullable<Int8>,
+    }
+}
+
+table! {
+    auth.webauthn_credentials (id) {
+        id -> Int4,
+        user_id -> Int4,
+        credential_id -> Varchar,
+        public_key -> Text,
+        algorithm -> Int4,
+        sign_count -> Int8,
+        name -> Nullable<Varchar>,
+        created_at -> Timestamp,
+        last_used_at -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    auth.recovery_codes (id) {
+        id -> Int4,
+        user_id -> Int4,
+        code_hash -> Varchar,
+        created_at -> Timestamp,
+        used_at -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    auth.password_reset_tokens (id) {
+        id -> Int4,
+        user_id -> Int4,
+        token_hash -> Varchar,
+        created_at -> Timestamp,
+        expires_at -> Timestamp,
+        used_at -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    auth.magic_links (id) {
+        id -> Int4,
+        user_id -> Int4,
+        jti -> Varchar,
+        created_at -> Timestamp,
+        expires_at -> Timestamp,
+        used_at -> Nullable<Timestamp>,
+        revoked_at -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    auth.email_otps (id) {
+        id -> Int4,
+        user_id -> Int4,
+        login -> Varchar,
+        code_hash -> Varchar,
+        attempts -> Int4,
+        created_at -> Timestamp,
+        expires_at -> Timestamp,
+        used_at -> Nullable<Timestamp>,
+    }
+}
+
+joinable!(refresh_tokens -> users (user_id));
+joinable!(refresh_tokens -> oauth_clients (client_id));
+joinable!(oauth_authorization_codes -> oauth_clients (client_id));
+joinable!(oauth_authorization_codes -> users (user_id));
+joinable!(user_totp -> users (user_id));
+joinable!(webauthn_credentials -> users (user_id));
+joinable!(recovery_codes -> users (user_id));
+joinable!(password_reset_tokens -> users (user_id));
+joinable!(magic_links -> users (user_id));
+joinable!(email_otps -> users (user_id));
+
+allow_tables_to_appear_in_same_query!(
+    users,
+    refresh_tokens,
+    oauth_clients,
+    oauth_authorization_codes,
+    user_totp,
+    webauthn_credentials,
+    recovery_codes,
+    password_reset_tokens,
+    magic_links,
+    email_otps,
+);
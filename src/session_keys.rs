@@ -0,0 +1,194 @@
+use std::fs;
+use actix_web::{
+    body::MessageBody,
+    cookie::{Cookie, CookieJar, Key},
+    dev::{ServiceRequest, ServiceResponse},
+    http::header::{self, HeaderValue},
+    middleware::Next
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::{DateTime, Utc};
+use lazy_static::lazy_static;
+use log::{info, warn};
+
+use crate::local_env::*;
+use crate::session_store::set_cookie_attributes;
+
+lazy_static! {
+    static ref KEYS: SessionKeys = SessionKeys::load();
+}
+
+struct SessionKeys {
+    /// Signs and encrypts every new cookie
+    current: Key,
+    /// Accepted until `previous_until`, never without it. Cookies are re-encrypted with the current key
+    previous: Vec<Key>,
+    previous_until: Option<DateTime<Utc>>,
+}
+
+fn parse_key(name: &str, data: &[u8]) -> Key {
+    let text = String::from_utf8_lossy(data);
+    let bytes = STANDARD.decode(text.trim()).unwrap_or_else(|_e| data.to_vec());
+
+    Key::try_from(bytes.as_slice()).unwrap_or_else(|e| {
+        panic!("[{}] -- Invalid {}, at least 64 bytes are required: {}", "SessionKeys", name, e);
+    })
+}
+
+impl SessionKeys {
+    fn load() -> SessionKeys {
+        let current = if let Some(path) = SESSION_KEY_FILE.as_ref() {
+            let data = fs::read(path).unwrap_or_else(|e| {
+                panic!("[{}] -- Can't read SESSION_KEY_FILE {}: {}", "SessionKeys", path, e);
+            });
+            parse_key("SESSION_KEY_FILE", &data)
+        } else if let Some(key) = SESSION_KEY.as_ref() {
+            parse_key("SESSION_KEY", key.as_bytes())
+        } else if *SESSION_KEY_RANDOM {
+            warn!("[{}] -- SESSION_KEY_RANDOM is set, sessions won't survive a restart", "SessionKeys");
+            Key::generate()
+        } else {
+            panic!("[{}] -- SESSION_KEY or SESSION_KEY_FILE is required, create one with `kz-auth generate-session-key`", "SessionKeys");
+        };
+
+        let previous: Vec<Key> = SESSION_PREVIOUS_KEYS.iter()
+            .flat_map(|x| x.split(','))
+            .filter(|x| !x.trim().is_empty())
+            .map(|x| parse_key("SESSION_PREVIOUS_KEYS", x.as_bytes()))
+            .collect();
+        // Without a deadline a leaked old key would open sessions forever
+        if !previous.is_empty() && SESSION_PREVIOUS_KEYS_UNTIL.is_none() {
+            panic!("[{}] -- SESSION_PREVIOUS_KEYS needs SESSION_PREVIOUS_KEYS_UNTIL", "SessionKeys");
+        }
+
+        let previous_until = SESSION_PREVIOUS_KEYS_UNTIL.as_ref().map(|x| {
+            DateTime::parse_from_rfc3339(x).unwrap_or_else(|e| {
+                panic!("Can't parse SESSION_PREVIOUS_KEYS_UNTIL {}", e);
+            }).with_timezone(&Utc)
+        });
+
+        SessionKeys { current, previous, previous_until }
+    }
+
+    fn previous_keys(&self) -> &[Key] {
+        match self.previous_until {
+            Some(until) if Utc::now() <= until => &self.previous,
+            _ => &[],
+        }
+    }
+}
+
+pub fn check_keys() {
+    lazy_static::initialize(&KEYS);
+}
+
+/// Key given to `SessionMiddleware`
+pub fn current() -> Key {
+    KEYS.current.clone()
+}
+
+/// Random key, base64 encoded, suitable for SESSION_KEY
+pub fn generate() -> String {
+    STANDARD.encode(Key::generate().master())
+}
+
+fn decrypt(key: &Key, cookie: &Cookie<'static>) -> Option<String> {
+    let mut jar = CookieJar::new();
+    jar.add_original(cookie.clone());
+    jar.private(key).get(cookie.name()).map(|x| x.value().to_string())
+}
+
+fn encrypt(key: &Key, value: String) -> Cookie<'static> {
+    let mut jar = CookieJar::new();
+    jar.private_mut(key).add(Cookie::new(SESSION_COOKIE_NAME.to_string(), value));
+    jar.get(SESSION_COOKIE_NAME.as_str()).unwrap().clone()
+}
+
+/// Re-encrypt a cookie issued with a previous key, None when no rotation is needed
+fn rotate(cookie: &Cookie<'static>) -> Option<Cookie<'static>> {
+    if decrypt(&KEYS.current, cookie).is_some() {
+        return None;
+    }
+
+    KEYS.previous_keys().iter()
+        .find_map(|key| decrypt(key, cookie))
+        .map(|value| encrypt(&KEYS.current, value))
+}
+
+/// Accept session cookies signed with a previous key during the grace period.
+/// Must wrap `SessionMiddleware` so it sees the rotated cookie.
+pub async fn rotate_cookie(mut req: ServiceRequest, next: Next<impl MessageBody>) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let header = match req.headers().get(header::COOKIE).and_then(|x| x.to_str().ok()) {
+        Some(x) if !KEYS.previous_keys().is_empty() => x.to_string(),
+        _ => return next.call(req).await,
+    };
+
+    let mut rotated = None;
+    let segments: Vec<String> = header.split(';')
+        .map(|segment| {
+            let cookie = Cookie::parse_encoded(segment.trim().to_string()).ok()
+                .filter(|x| x.name() == SESSION_COOKIE_NAME.as_str())
+                .and_then(|x| rotate(&x));
+            match cookie {
+                Some(cookie) => {
+                    let segment = format!("{}={}", cookie.name(), cookie.value());
+                    rotated = Some(cookie);
+                    segment
+                },
+                None => segment.trim().to_string(),
+            }
+        })
+        .collect();
+
+    let mut cookie = match rotated {
+        Some(x) => x,
+        None => return next.call(req).await,
+    };
+
+    info!("[{}] -- Session cookie rotated to the current key", "SessionKeys");
+    req.headers_mut().insert(header::COOKIE, HeaderValue::from_str(&segments.join("; ")).unwrap());
+
+    let mut res = next.call(req).await?;
+
+    // Send the rotated cookie back unless the session middleware already did
+    let cookie_set = res.response().cookies().any(|x| x.name() == SESSION_COOKIE_NAME.as_str());
+    if !cookie_set {
+        set_cookie_attributes(&mut cookie);
+        res.response_mut().add_cookie(&cookie)?;
+    }
+
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    #[test]
+    fn test_reencrypt_with_current_key() {
+        let previous = Key::generate();
+        let current = Key::generate();
+
+        let cookie = encrypt(&previous, String::from("session"));
+        assert!(decrypt(&current, &cookie).is_none());
+
+        let value = decrypt(&previous, &cookie).unwrap();
+        assert_eq!(decrypt(&current, &encrypt(&current, value)).as_deref(), Some("session"));
+    }
+
+    #[test]
+    fn test_parse_key() {
+        let key = Key::generate();
+        let encoded = STANDARD.encode(key.master());
+        assert_eq!(parse_key("SESSION_KEY", encoded.as_bytes()).master(), key.master());
+    }
+
+    #[test]
+    fn test_previous_keys_expire() {
+        let keys = |previous_until| SessionKeys { current: Key::generate(), previous: vec![Key::generate()], previous_until };
+        assert_eq!(keys(Some(Utc::now() + Duration::hours(1))).previous_keys().len(), 1);
+        assert!(keys(Some(Utc::now() - Duration::seconds(1))).previous_keys().is_empty());
+        assert!(keys(None).previous_keys().is_empty());
+    }
+}
@@ -0,0 +1,234 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use actix_identity::IdentityMiddleware;
+use actix_session::{
+    config::{BrowserSession, PersistentSession},
+    SessionMiddleware
+};
+use actix_session::storage::{
+    CookieSessionStore,
+    LoadError,
+    RedisSessionStore,
+    SaveError,
+    SessionKey,
+    SessionStore,
+    UpdateError
+};
+use actix_web::cookie::{time::Duration, Cookie, Key, SameSite};
+use async_trait::async_trait;
+use log::{info, warn};
+
+use crate::hashing::generate_token;
+use crate::local_env::*;
+
+type SessionState = HashMap<String, String>;
+
+/// Session storage selected with SESSION_BACKEND
+pub enum SessionBackend {
+    /// Shared between replicas
+    Redis(RedisSessionStore),
+    /// Single node and development, sessions are lost on restart
+    Memory(MemorySessionStore),
+    /// Stateless, the whole state lives in the encrypted cookie
+    Cookie(CookieSessionStore),
+}
+
+impl Clone for SessionBackend {
+    fn clone(&self) -> Self {
+        match self {
+            SessionBackend::Redis(store) => SessionBackend::Redis(store.clone()),
+            SessionBackend::Memory(store) => SessionBackend::Memory(store.clone()),
+            SessionBackend::Cookie(_store) => SessionBackend::Cookie(CookieSessionStore::default()),
+        }
+    }
+}
+
+impl SessionBackend {
+    pub async fn from_config() -> Result<SessionBackend, anyhow::Error> {
+        info!("[{}] -- Session backend: {}", "SessionStore", *SESSION_BACKEND);
+
+        match SESSION_BACKEND.as_str() {
+            "redis" => {
+                let host = REDIS_HOST.as_ref().ok_or_else(|| anyhow::anyhow!("SESSION_BACKEND=redis needs REDIS_HOST"))?;
+                let redis_connection_string = format!("redis://{}:{}", host, &REDIS_PORT.to_string());
+                Ok(SessionBackend::Redis(RedisSessionStore::new(redis_connection_string).await?))
+            },
+            "memory" => Ok(SessionBackend::Memory(MemorySessionStore::default())),
+            "cookie" => Ok(SessionBackend::Cookie(CookieSessionStore::default())),
+            x => Err(anyhow::anyhow!("Unknown SESSION_BACKEND {}, expected redis, memory or cookie", x)),
+        }
+    }
+}
+
+/// Session middleware with the cookie attributes and lifetime from config
+pub fn session_middleware(store: SessionBackend, key: Key) -> SessionMiddleware<SessionBackend> {
+    if *SESSION_COOKIE_SAME_SITE == SameSite::None && !*SESSION_COOKIE_SECURE {
+        warn!("[{}] -- SameSite=None cookies are rejected by browsers unless they are Secure", "SessionStore");
+    }
+
+    let max_lifetime = Duration::seconds(*SESSION_MAX_LIFETIME as i64);
+    let builder = SessionMiddleware::builder(store, key)
+        .cookie_name(SESSION_COOKIE_NAME.to_string())
+        .cookie_domain(SESSION_COOKIE_DOMAIN.clone())
+        .cookie_path(SESSION_COOKIE_PATH.to_string())
+        .cookie_same_site(*SESSION_COOKIE_SAME_SITE)
+        .cookie_secure(*SESSION_COOKIE_SECURE)
+        .cookie_http_only(true);
+
+    if *SESSION_COOKIE_PERSISTENT {
+        builder.session_lifecycle(PersistentSession::default().session_ttl(max_lifetime)).build()
+    } else {
+        builder.session_lifecycle(BrowserSession::default().state_ttl(max_lifetime)).build()
+    }
+}
+
+/// Identity middleware enforcing the idle timeout and the absolute session lifetime
+pub fn identity_middleware() -> IdentityMiddleware {
+    let idle_timeout = match *SESSION_IDLE_TIMEOUT {
+        0 => None,
+        x => Some(std::time::Duration::from_secs(x)),
+    };
+
+    IdentityMiddleware::builder()
+        .visit_deadline(idle_timeout)
+        .login_deadline(Some(std::time::Duration::from_secs(*SESSION_MAX_LIFETIME)))
+        .build()
+}
+
+/// Same attributes as the cookies set by `session_middleware`
+pub fn set_cookie_attributes(cookie: &mut Cookie<'static>) {
+    if let Some(domain) = SESSION_COOKIE_DOMAIN.as_ref() {
+        cookie.set_domain(domain.clone());
+    }
+    cookie.set_path(SESSION_COOKIE_PATH.to_string());
+    cookie.set_secure(*SESSION_COOKIE_SECURE);
+    cookie.set_http_only(true);
+    cookie.set_same_site(*SESSION_COOKIE_SAME_SITE);
+    if *SESSION_COOKIE_PERSISTENT {
+        cookie.set_max_age(Duration::seconds(*SESSION_MAX_LIFETIME as i64));
+    }
+}
+
+#[async_trait(?Send)]
+impl SessionStore for SessionBackend {
+    async fn load(&self, session_key: &SessionKey) -> Result<Option<SessionState>, LoadError> {
+        match self {
+            SessionBackend::Redis(store) => store.load(session_key).await,
+            SessionBackend::Memory(store) => store.load(session_key).await,
+            SessionBackend::Cookie(store) => store.load(session_key).await,
+        }
+    }
+
+    async fn save(&self, session_state: SessionState, ttl: &Duration) -> Result<SessionKey, SaveError> {
+        match self {
+            SessionBackend::Redis(store) => store.save(session_state, ttl).await,
+            SessionBackend::Memory(store) => store.save(session_state, ttl).await,
+            SessionBackend::Cookie(store) => store.save(session_state, ttl).await,
+        }
+    }
+
+    async fn update(&self, session_key: SessionKey, session_state: SessionState, ttl: &Duration) -> Result<SessionKey, UpdateError> {
+        match self {
+            SessionBackend::Redis(store) => store.update(session_key, session_state, ttl).await,
+            SessionBackend::Memory(store) => store.update(session_key, session_state, ttl).await,
+            SessionBackend::Cookie(store) => store.update(session_key, session_state, ttl).await,
+        }
+    }
+
+    async fn update_ttl(&self, session_key: &SessionKey, ttl: &Duration) -> Result<(), anyhow::Error> {
+        match self {
+            SessionBackend::Redis(store) => store.update_ttl(session_key, ttl).await,
+            SessionBackend::Memory(store) => store.update_ttl(session_key, ttl).await,
+            SessionBackend::Cookie(store) => store.update_ttl(session_key, ttl).await,
+        }
+    }
+
+    async fn delete(&self, session_key: &SessionKey) -> Result<(), anyhow::Error> {
+        match self {
+            SessionBackend::Redis(store) => store.delete(session_key).await,
+            SessionBackend::Memory(store) => store.delete(session_key).await,
+            SessionBackend::Cookie(store) => store.delete(session_key).await,
+        }
+    }
+}
+
+/// In-process session storage, shared by the workers of a single node
+#[derive(Clone, Default)]
+pub struct MemorySessionStore {
+    sessions: Arc<Mutex<HashMap<String, (SessionState, Instant)>>>,
+}
+
+fn deadline(ttl: &Duration) -> Instant {
+    Instant::now() + std::time::Duration::from_secs(ttl.whole_seconds().max(0) as u64)
+}
+
+impl MemorySessionStore {
+    fn insert(&self, session_key: &str, session_state: SessionState, ttl: &Duration) {
+        let mut sessions = self.sessions.lock().unwrap();
+        // Expired sessions are only dropped when new state is written
+        let now = Instant::now();
+        sessions.retain(|_key, (_state, expires_at)| *expires_at > now);
+        sessions.insert(session_key.to_string(), (session_state, deadline(ttl)));
+    }
+}
+
+#[async_trait(?Send)]
+impl SessionStore for MemorySessionStore {
+    async fn load(&self, session_key: &SessionKey) -> Result<Option<SessionState>, LoadError> {
+        let sessions = self.sessions.lock().unwrap();
+        let state = sessions.get(session_key.as_ref())
+            .filter(|(_state, expires_at)| *expires_at > Instant::now())
+            .map(|(state, _expires_at)| state.clone());
+        Ok(state)
+    }
+
+    async fn save(&self, session_state: SessionState, ttl: &Duration) -> Result<SessionKey, SaveError> {
+        let session_key = generate_token();
+        self.insert(&session_key, session_state, ttl);
+        SessionKey::try_from(session_key).map_err(|e| SaveError::Other(e.into()))
+    }
+
+    async fn update(&self, session_key: SessionKey, session_state: SessionState, ttl: &Duration) -> Result<SessionKey, UpdateError> {
+        self.insert(session_key.as_ref(), session_state, ttl);
+        Ok(session_key)
+    }
+
+    async fn update_ttl(&self, session_key: &SessionKey, ttl: &Duration) -> Result<(), anyhow::Error> {
+        let mut sessions = self.sessions.lock().unwrap();
+        if let Some((_state, expires_at)) = sessions.get_mut(session_key.as_ref()) {
+            *expires_at = deadline(ttl);
+        }
+        Ok(())
+    }
+
+    async fn delete(&self, session_key: &SessionKey) -> Result<(), anyhow::Error> {
+        self.sessions.lock().unwrap().remove(session_key.as_ref());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[actix_web::test]
+    async fn test_memory_store() {
+        let store = MemorySessionStore::default();
+        let mut state = SessionState::new();
+        state.insert(String::from("user"), String::from("\"valentin\""));
+
+        let key = store.save(state.clone(), &Duration::minutes(1)).await.unwrap();
+        assert_eq!(store.load(&key).await.unwrap(), Some(state));
+
+        store.delete(&key).await.unwrap();
+        assert_eq!(store.load(&key).await.unwrap(), None);
+    }
+
+    #[actix_web::test]
+    async fn test_memory_store_expired() {
+        let store = MemorySessionStore::default();
+        let key = store.save(SessionState::new(), &Duration::ZERO).await.unwrap();
+        assert_eq!(store.load(&key).await.unwrap(), None);
+    }
+}
@@ -0,0 +1,131 @@
+use openssl::{
+    error::ErrorStack,
+    hash::MessageDigest,
+    memcmp,
+    pkey::PKey,
+    rand::rand_bytes,
+    sign::Signer
+};
+
+use crate::local_env::TOTP_ISSUER;
+
+/// RFC 6238 defaults, the only settings every authenticator app supports
+const PERIOD: i64 = 30;
+const DIGITS: u32 = 6;
+/// Steps accepted on each side of the current one, to absorb clock drift
+const WINDOW: i64 = 1;
+const SECRET_LEN: usize = 20;
+
+const BASE32_ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+/// RFC 4648 base32, without padding as expected in otpauth URIs
+pub fn base32_encode(data: &[u8]) -> String {
+    let mut output = String::with_capacity(data.len().div_ceil(5) * 8);
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+
+    for byte in data {
+        buffer = (buffer << 8) | *byte as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            output.push(BASE32_ALPHABET[((buffer >> bits) & 0x1f) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        output.push(BASE32_ALPHABET[((buffer << (5 - bits)) & 0x1f) as usize] as char);
+    }
+    output
+}
+
+/// Random secret, to be base32 encoded for the authenticator app
+pub fn generate_secret() -> Vec<u8> {
+    let mut secret = vec![0u8; SECRET_LEN];
+    rand_bytes(&mut secret).unwrap();
+    secret
+}
+
+/// RFC 4226 HOTP with HMAC-SHA1
+fn hotp(secret: &[u8], counter: u64, digits: u32) -> Result<String, ErrorStack> {
+    let key = PKey::hmac(secret)?;
+    let mut signer = Signer::new(MessageDigest::sha1(), &key)?;
+    signer.update(&counter.to_be_bytes())?;
+    let mac = signer.sign_to_vec()?;
+
+    let offset = (mac[mac.len() - 1] & 0x0f) as usize;
+    let binary = u32::from_be_bytes([mac[offset] & 0x7f, mac[offset + 1], mac[offset + 2], mac[offset + 3]]);
+    Ok(format!("{:0width$}", binary % 10u32.pow(digits), width = digits as usize))
+}
+
+/// Time step of a unix timestamp
+pub fn step(timestamp: i64) -> i64 {
+    timestamp.div_euclid(PERIOD)
+}
+
+/// Step matching the code within the drift window, if any
+pub fn verify(secret: &[u8], code: &str, timestamp: i64) -> Result<Option<i64>, ErrorStack> {
+    let code = code.trim();
+    if code.len() != DIGITS as usize {
+        return Ok(None);
+    }
+
+    let current = step(timestamp);
+    for candidate in (current - WINDOW)..=(current + WINDOW) {
+        let expected = hotp(secret, candidate as u64, DIGITS)?;
+        if memcmp::eq(expected.as_bytes(), code.as_bytes()) {
+            return Ok(Some(candidate));
+        }
+    }
+    Ok(None)
+}
+
+fn percent_encode(value: &str) -> String {
+    value.bytes()
+        .map(|x| match x {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (x as char).to_string(),
+            _ => format!("%{:02X}", x),
+        })
+        .collect()
+}
+
+/// Provisioning URI, usually shown as a QR code
+pub fn otpauth_uri(account: &str, secret: &str) -> String {
+    format!(
+        "otpauth://totp/{}:{}?secret={}&issuer={}&algorithm=SHA1&digits={}&period={}",
+        percent_encode(&TOTP_ISSUER),
+        percent_encode(account),
+        secret,
+        percent_encode(&TOTP_ISSUER),
+        DIGITS,
+        PERIOD
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_base32() {
+        assert_eq!(base32_encode(b"foobar"), "MZXW6YTBOI");
+        assert_eq!(base32_encode(b"12345678901234567890"), "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ");
+    }
+
+    #[test]
+    fn test_rfc6238_vectors() {
+        let secret = b"12345678901234567890";
+        assert_eq!(hotp(secret, step(59) as u64, 8).unwrap(), "94287082");
+        assert_eq!(hotp(secret, step(1111111109) as u64, 8).unwrap(), "07081804");
+        assert_eq!(hotp(secret, step(2000000000) as u64, 8).unwrap(), "69279037");
+    }
+
+    #[test]
+    fn test_verify_window() {
+        let secret = b"12345678901234567890";
+        // 287082 is the 6 digits code of step 1
+        assert_eq!(verify(secret, "287082", 59).unwrap(), Some(1));
+        assert_eq!(verify(secret, "287082", 89).unwrap(), Some(1));
+        assert_eq!(verify(secret, "287082", 120).unwrap(), None);
+        assert_eq!(verify(secret, "28708", 59).unwrap(), None);
+    }
+}
@@ -1,5 +1,5 @@
-use log::{error, warn, info, debug, trace, LevelFilter};
-use diesel::{QueryDsl, RunQueryDsl};
+use log::info;
+use diesel::{BoolExpressionMethods, Connection, OptionalExtension, QueryDsl, RunQueryDsl};
 use diesel::ExpressionMethods;
 // use dotenv::dotenv;
 
@@ -7,8 +7,13 @@ use crate::database::{
     POOL, QueryResult
 };
 use crate::models::{
-    User
+    User,
+    RefreshToken,
+    UserTotp,
+    WebauthnCredential,
+    EmailOtp
 };
+use chrono::NaiveDateTime;
 
 use super::{Mode, CreateUser};
 
@@ -37,6 +42,21 @@ fn find_user_by_email(_email: &String) -> QueryResult<User> {
         .first::<User>(conn)
 }
 
+/// User whose name or email is the login, names take precedence
+fn find_user_by_login(login: &String) -> QueryResult<User> {
+    use crate::schema::users::dsl::*;
+    let conn = getConn!();
+
+    let mut found = users
+        .filter(name.eq(login).or(email.eq(login)))
+        .limit(2)
+        .load::<User>(conn)?;
+    match found.iter().position(|x| &x.name == login) {
+        Some(i) => Ok(found.swap_remove(i)),
+        None => found.pop().ok_or(diesel::result::Error::NotFound),
+    }
+}
+
 fn find_user_by_id(user_id: &i32) -> QueryResult<User> {
     use crate::schema::users::dsl::*;
     let conn = getConn!();
@@ -58,13 +78,17 @@ pub async fn get_user(user: Mode) -> Result<User, diesel::result::Error> {
             info!("[{}] -- Search user using email: {}", "UserService::get_user", &x);
             Ok(find_user_by_email(&x)?)
         },
+        Mode::Login(x) => {
+            info!("[{}] -- Search user using login: {}", "UserService::get_user", &x);
+            Ok(find_user_by_login(&x)?)
+        },
     }
 }
 
-pub fn create_user(body: &CreateUser, pwd: String) -> Result<(), diesel::result::Error> {
+pub fn create_user(body: &CreateUser, pwd: String) -> Result<User, diesel::result::Error> {
     use crate::schema::users::dsl::*;
     let conn = getConn!();
-    let _rows_inserted = diesel::insert_into(users)
+    let user = diesel::insert_into(users)
         .values((
             name.eq(&body.username),
             email.eq(&body.email),
@@ -72,10 +96,107 @@ pub fn create_user(body: &CreateUser, pwd: String) -> Result<(), diesel::result:
             // created_at.eq(diesel::dsl::now),
             // updated_at.eq(diesel::dsl::now),
         ))
-        .execute(conn)?;
+        .get_result::<User>(conn)?;
 
     info!("[{}] -- Created user with email {}", "UserService::create_user", body.email);
 
+    Ok(user)
+}
+
+/// Mark the address as verified, as long as it is still the user's one
+pub fn verify_email(user_id: i32, address: &str, now: NaiveDateTime) -> QueryResult<bool> {
+    use crate::schema::users::dsl::*;
+    let conn = getConn!();
+    let rows = diesel::update(users)
+        .filter(id.eq(user_id).and(email.eq(address)).and(email_verified_at.is_null()))
+        .set(email_verified_at.eq(now))
+        .execute(conn)?;
+
+    Ok(rows > 0)
+}
+
+/// Count a wrong password, the account locks until `lock_until` once the
+/// threshold is reached. Returns true if this attempt locked it.
+pub fn record_failed_login(user_id: i32, threshold: i32, lock_until: NaiveDateTime) -> QueryResult<bool> {
+    use crate::schema::users::dsl::*;
+    let conn = getConn!();
+    conn.transaction(|conn| {
+        let failed = diesel::update(users.find(user_id))
+            .set(failed_logins.eq(failed_logins + 1))
+            .returning(failed_logins)
+            .get_result::<i32>(conn)?;
+        if failed < threshold {
+            return Ok(false);
+        }
+
+        diesel::update(users.find(user_id))
+            .set((failed_logins.eq(0), locked_until.eq(lock_until)))
+            .execute(conn)?;
+        Ok(true)
+    })
+}
+
+/// Clear the failed attempts and any lock
+pub fn unlock_user(user_id: i32) -> QueryResult<bool> {
+    use crate::schema::users::dsl::*;
+    let conn = getConn!();
+    let rows = diesel::update(users.find(user_id))
+        .set((failed_logins.eq(0), locked_until.eq(None::<NaiveDateTime>)))
+        .execute(conn)?;
+
+    Ok(rows == 1)
+}
+
+pub fn set_admin(username: &str, admin: bool) -> QueryResult<bool> {
+    use crate::schema::users::dsl::*;
+    let conn = getConn!();
+    let rows = diesel::update(users.filter(name.eq(username)))
+        .set(is_admin.eq(admin))
+        .execute(conn)?;
+
+    Ok(rows == 1)
+}
+
+/// Start counting second factor attempts again, after a password check
+pub fn reset_mfa_attempts(user_id: i32) -> QueryResult<()> {
+    use crate::schema::users::dsl::*;
+    let conn = getConn!();
+    diesel::update(users.find(user_id))
+        .set(mfa_attempts.eq(0))
+        .execute(conn)?;
+
+    Ok(())
+}
+
+/// Count a second factor attempt, returns false once `max_attempts` were made
+pub fn count_mfa_attempt(user_id: i32, max_attempts: i32) -> QueryResult<bool> {
+    use crate::schema::users::dsl::*;
+    let conn = getConn!();
+    let rows = diesel::update(users.find(user_id).filter(mfa_attempts.lt(max_attempts)))
+        .set(mfa_attempts.eq(mfa_attempts + 1))
+        .execute(conn)?;
+
+    Ok(rows == 1)
+}
+
+/// Replace a hash with one using the current parameters, unless the password changed meanwhile
+pub fn rehash_password(user_id: i32, old_hash: &str, new_hash: &str) -> QueryResult<bool> {
+    use crate::schema::users::dsl::*;
+    let conn = getConn!();
+    let rows = diesel::update(users.find(user_id).filter(password.eq(old_hash)))
+        .set(password.eq(new_hash))
+        .execute(conn)?;
+
+    Ok(rows == 1)
+}
+
+pub fn update_password(user_id: i32, pwd: &str, now: NaiveDateTime) -> QueryResult<()> {
+    use crate::schema::users::dsl::*;
+    let conn = getConn!();
+    diesel::update(users.find(user_id))
+        .set((password.eq(pwd), updated_at.eq(now)))
+        .execute(conn)?;
+
     Ok(())
 }
 
@@ -86,6 +207,394 @@ pub async fn get_users() -> Result<Vec<User>, diesel::result::Error> {
     Ok(list)
 }
 
+pub fn create_refresh_token(_user_id: i32, _client_id: Option<i32>, _family_id: &str, _token_hash: &str, _scope: Option<&str>, _expires_at: NaiveDateTime) -> QueryResult<()> {
+    use crate::schema::refresh_tokens::dsl::*;
+    let conn = getConn!();
+    diesel::insert_into(refresh_tokens)
+        .values((
+            user_id.eq(_user_id),
+            client_id.eq(_client_id),
+            family_id.eq(_family_id),
+            token_hash.eq(_token_hash),
+            scope.eq(_scope),
+            expires_at.eq(_expires_at),
+        ))
+        .execute(conn)?;
+
+    Ok(())
+}
+
+pub fn find_refresh_token(_token_hash: &str) -> QueryResult<RefreshToken> {
+    use crate::schema::refresh_tokens::dsl::*;
+    let conn = getConn!();
+
+    refresh_tokens
+        .filter(token_hash.eq(_token_hash))
+        .first::<RefreshToken>(conn)
+}
+
+/// Mark a refresh token as used, returns false if it was already used
+pub fn use_refresh_token(token_id: i32, now: NaiveDateTime) -> QueryResult<bool> {
+    use crate::schema::refresh_tokens::dsl::*;
+    let conn = getConn!();
+    let rows = diesel::update(refresh_tokens.find(token_id).filter(used_at.is_null()))
+        .set(used_at.eq(now))
+        .execute(conn)?;
+
+    Ok(rows == 1)
+}
+
+pub fn revoke_refresh_token_family(_family_id: &str, now: NaiveDateTime) -> QueryResult<usize> {
+    use crate::schema::refresh_tokens::dsl::*;
+    let conn = getConn!();
+    diesel::update(refresh_tokens.filter(family_id.eq(_family_id)).filter(revoked_at.is_null()))
+        .set(revoked_at.eq(now))
+        .execute(conn)
+}
+
+/// Revoke every refresh token of the user, returns how many were still active
+pub fn revoke_refresh_tokens(_user_id: i32, now: NaiveDateTime) -> QueryResult<usize> {
+    use crate::schema::refresh_tokens::dsl::*;
+    let conn = getConn!();
+    diesel::update(refresh_tokens.filter(user_id.eq(_user_id)).filter(revoked_at.is_null()))
+        .set(revoked_at.eq(now))
+        .execute(conn)
+}
+
+/// Refuse the sessions and access tokens issued until now
+pub fn revoke_credentials(user_id: i32, now: NaiveDateTime) -> QueryResult<()> {
+    use crate::schema::users::dsl::*;
+    let conn = getConn!();
+    diesel::update(users.find(user_id))
+        .set(credentials_revoked_at.eq(now))
+        .execute(conn)?;
+
+    Ok(())
+}
+
+pub fn get_credentials_revoked_at(user_id: i32) -> QueryResult<Option<NaiveDateTime>> {
+    use crate::schema::users::dsl::*;
+    let conn = getConn!();
+    users.find(user_id)
+        .select(credentials_revoked_at)
+        .first(conn)
+}
+
+/// Store a reset token, the previous unused ones stop working
+pub fn create_password_reset_token(_user_id: i32, _token_hash: &str, _expires_at: NaiveDateTime) -> QueryResult<()> {
+    use crate::schema::password_reset_tokens::dsl::*;
+    let conn = getConn!();
+    conn.transaction(|conn| {
+        diesel::delete(password_reset_tokens.filter(user_id.eq(_user_id)).filter(used_at.is_null()))
+            .execute(conn)?;
+        diesel::insert_into(password_reset_tokens)
+            .values((
+                user_id.eq(_user_id),
+                token_hash.eq(_token_hash),
+                expires_at.eq(_expires_at),
+            ))
+            .execute(conn)?;
+        Ok(())
+    })
+}
+
+/// Owner of a reset token that can still be used
+pub fn find_password_reset_user(_token_hash: &str, now: NaiveDateTime) -> QueryResult<Option<User>> {
+    use crate::schema::password_reset_tokens::dsl::*;
+    use crate::schema::users;
+    let conn = getConn!();
+
+    password_reset_tokens
+        .inner_join(users::table)
+        .filter(token_hash.eq(_token_hash))
+        .filter(used_at.is_null())
+        .filter(expires_at.gt(now))
+        .select(users::all_columns)
+        .first::<User>(conn)
+        .optional()
+}
+
+/// Consume a valid reset token and set the new password hash, returns the user
+pub fn reset_password(_token_hash: &str, pwd: &str, now: NaiveDateTime) -> QueryResult<Option<User>> {
+    use crate::schema::password_reset_tokens::dsl as tokens;
+    use crate::schema::users::dsl::*;
+    let conn = getConn!();
+    conn.transaction(|conn| {
+        let owner = diesel::update(tokens::password_reset_tokens
+                .filter(tokens::token_hash.eq(_token_hash))
+                .filter(tokens::used_at.is_null())
+                .filter(tokens::expires_at.gt(now)))
+            .set(tokens::used_at.eq(now))
+            .returning(tokens::user_id)
+            .get_result::<i32>(conn)
+            .optional()?;
+
+        match owner {
+            Some(owner) => diesel::update(users.find(owner))
+                .set((password.eq(pwd), updated_at.eq(now), credentials_revoked_at.eq(now)))
+                .get_result::<User>(conn)
+                .map(Some),
+            None => Ok(None),
+        }
+    })
+}
+
+pub fn create_magic_link(_user_id: i32, _jti: &str, _expires_at: NaiveDateTime) -> QueryResult<()> {
+    use crate::schema::magic_links::dsl::*;
+    let conn = getConn!();
+    diesel::insert_into(magic_links)
+        .values((
+            user_id.eq(_user_id),
+            jti.eq(_jti),
+            expires_at.eq(_expires_at),
+        ))
+        .execute(conn)?;
+
+    Ok(())
+}
+
+/// Mark a link as used, returns false if it was used, revoked, expired or belongs to someone else
+pub fn use_magic_link(_user_id: i32, _jti: &str, now: NaiveDateTime) -> QueryResult<bool> {
+    use crate::schema::magic_links::dsl::*;
+    let conn = getConn!();
+    let rows = diesel::update(magic_links
+            .filter(jti.eq(_jti))
+            .filter(user_id.eq(_user_id))
+            .filter(used_at.is_null())
+            .filter(revoked_at.is_null())
+            .filter(expires_at.gt(now)))
+        .set(used_at.eq(now))
+        .execute(conn)?;
+
+    Ok(rows == 1)
+}
+
+/// Revoke the links that can still be used, returns how many
+pub fn revoke_magic_links(_user_id: i32, now: NaiveDateTime) -> QueryResult<usize> {
+    use crate::schema::magic_links::dsl::*;
+    let conn = getConn!();
+    diesel::update(magic_links
+            .filter(user_id.eq(_user_id))
+            .filter(used_at.is_null())
+            .filter(revoked_at.is_null())
+            .filter(expires_at.gt(now)))
+        .set(revoked_at.eq(now))
+        .execute(conn)
+}
+
+/// Store a login code, replacing the ones the user didn't use
+pub fn create_email_otp(_user_id: i32, _login: &str, _code_hash: &str, _expires_at: NaiveDateTime) -> QueryResult<()> {
+    use crate::schema::email_otps::dsl::*;
+    let conn = getConn!();
+    conn.transaction(|conn| {
+        diesel::delete(email_otps.filter(user_id.eq(_user_id)).filter(used_at.is_null()))
+            .execute(conn)?;
+        diesel::insert_into(email_otps)
+            .values((
+                user_id.eq(_user_id),
+                login.eq(_login),
+                code_hash.eq(_code_hash),
+                expires_at.eq(_expires_at),
+            ))
+            .execute(conn)?;
+        Ok(())
+    })
+}
+
+/// Latest code sent for this login that can still be used
+pub fn find_email_otp(_login: &str, now: NaiveDateTime) -> QueryResult<Option<EmailOtp>> {
+    use crate::schema::email_otps::dsl::*;
+    let conn = getConn!();
+
+    email_otps
+        .filter(login.eq(_login))
+        .filter(used_at.is_null())
+        .filter(expires_at.gt(now))
+        .order(created_at.desc())
+        .first::<EmailOtp>(conn)
+        .optional()
+}
+
+/// Count a guess, returns false once the attempts are exhausted
+pub fn count_email_otp_attempt(otp_id: i32, max_attempts: i32) -> QueryResult<bool> {
+    use crate::schema::email_otps::dsl::*;
+    let conn = getConn!();
+    let rows = diesel::update(email_otps.find(otp_id).filter(attempts.lt(max_attempts)))
+        .set(attempts.eq(attempts + 1))
+        .execute(conn)?;
+
+    Ok(rows == 1)
+}
+
+/// Mark a code as used, returns false if it was already used
+pub fn use_email_otp(otp_id: i32, now: NaiveDateTime) -> QueryResult<bool> {
+    use crate::schema::email_otps::dsl::*;
+    let conn = getConn!();
+    let rows = diesel::update(email_otps.find(otp_id).filter(used_at.is_null()))
+        .set(used_at.eq(now))
+        .execute(conn)?;
+
+    Ok(rows == 1)
+}
+
+pub fn find_totp(_user_id: i32) -> QueryResult<Option<UserTotp>> {
+    use crate::schema::user_totp::dsl::*;
+    let conn = getConn!();
+
+    user_totp
+        .filter(user_id.eq(_user_id))
+        .first::<UserTotp>(conn)
+        .optional()
+}
+
+/// Store a new secret, replacing an enrollment that was never confirmed
+pub fn create_totp(_user_id: i32, _secret: &str) -> QueryResult<()> {
+    use crate::schema::user_totp::dsl::*;
+    let conn = getConn!();
+    diesel::delete(user_totp.filter(user_id.eq(_user_id)).filter(confirmed_at.is_null()))
+        .execute(conn)?;
+    diesel::insert_into(user_totp)
+        .values((
+            user_id.eq(_user_id),
+            secret.eq(_secret),
+        ))
+        .execute(conn)?;
+
+    Ok(())
+}
+
+/// Record the step of an accepted code, returns false if it, or a later one, was already used
+pub fn use_totp_step(_user_id: i32, step: i64) -> QueryResult<bool> {
+    use crate::schema::user_totp::dsl::*;
+    let conn = getConn!();
+    let rows = diesel::update(
+        user_totp
+            .filter(user_id.eq(_user_id))
+            .filter(last_used_step.is_null().or(last_used_step.lt(step)))
+    )
+        .set(last_used_step.eq(step))
+        .execute(conn)?;
+
+    Ok(rows == 1)
+}
+
+pub fn confirm_totp(_user_id: i32, now: NaiveDateTime) -> QueryResult<()> {
+    use crate::schema::user_totp::dsl::*;
+    let conn = getConn!();
+    diesel::update(user_totp.filter(user_id.eq(_user_id)).filter(confirmed_at.is_null()))
+        .set(confirmed_at.eq(now))
+        .execute(conn)?;
+
+    Ok(())
+}
+
+pub fn delete_totp(_user_id: i32) -> QueryResult<usize> {
+    use crate::schema::user_totp::dsl::*;
+    let conn = getConn!();
+    diesel::delete(user_totp.filter(user_id.eq(_user_id))).execute(conn)
+}
+
+pub fn find_webauthn_credentials(_user_id: i32) -> QueryResult<Vec<WebauthnCredential>> {
+    use crate::schema::webauthn_credentials::dsl::*;
+    let conn = getConn!();
+
+    webauthn_credentials
+        .filter(user_id.eq(_user_id))
+        .order(created_at.asc())
+        .load::<WebauthnCredential>(conn)
+}
+
+pub fn find_webauthn_credential(_credential_id: &str) -> QueryResult<Option<WebauthnCredential>> {
+    use crate::schema::webauthn_credentials::dsl::*;
+    let conn = getConn!();
+
+    webauthn_credentials
+        .filter(credential_id.eq(_credential_id))
+        .first::<WebauthnCredential>(conn)
+        .optional()
+}
+
+pub fn create_webauthn_credential(_user_id: i32, _credential_id: &str, _public_key: &str, _algorithm: i32, _sign_count: i64, _name: Option<&str>) -> QueryResult<()> {
+    use crate::schema::webauthn_credentials::dsl::*;
+    let conn = getConn!();
+    diesel::insert_into(webauthn_credentials)
+        .values((
+            user_id.eq(_user_id),
+            credential_id.eq(_credential_id),
+            public_key.eq(_public_key),
+            algorithm.eq(_algorithm),
+            sign_count.eq(_sign_count),
+            name.eq(_name),
+        ))
+        .execute(conn)?;
+
+    Ok(())
+}
+
+pub fn use_webauthn_credential(credential: i32, _sign_count: i64, now: NaiveDateTime) -> QueryResult<()> {
+    use crate::schema::webauthn_credentials::dsl::*;
+    let conn = getConn!();
+    diesel::update(webauthn_credentials.find(credential))
+        .set((
+            sign_count.eq(_sign_count),
+            last_used_at.eq(now),
+        ))
+        .execute(conn)?;
+
+    Ok(())
+}
+
+pub fn delete_webauthn_credential(_user_id: i32, credential: i32) -> QueryResult<usize> {
+    use crate::schema::webauthn_credentials::dsl::*;
+    let conn = getConn!();
+    diesel::delete(webauthn_credentials.find(credential).filter(user_id.eq(_user_id))).execute(conn)
+}
+
+/// Replace every recovery code of the user
+pub fn create_recovery_codes(_user_id: i32, hashes: &[String]) -> QueryResult<()> {
+    use crate::schema::recovery_codes::dsl::*;
+    let conn = getConn!();
+    conn.transaction(|conn| {
+        diesel::delete(recovery_codes.filter(user_id.eq(_user_id))).execute(conn)?;
+        let rows: Vec<_> = hashes.iter()
+            .map(|x| (user_id.eq(_user_id), code_hash.eq(x)))
+            .collect();
+        diesel::insert_into(recovery_codes).values(&rows).execute(conn)?;
+        Ok(())
+    })
+}
+
+pub fn count_recovery_codes(_user_id: i32) -> QueryResult<i64> {
+    use crate::schema::recovery_codes::dsl::*;
+    let conn = getConn!();
+
+    recovery_codes
+        .filter(user_id.eq(_user_id))
+        .filter(used_at.is_null())
+        .count()
+        .get_result(conn)
+}
+
+/// Mark the unused recovery code of the user with this hash as used, returns false if there is none
+pub fn use_recovery_code(_user_id: i32, _code_hash: &str, now: NaiveDateTime) -> QueryResult<bool> {
+    use crate::schema::recovery_codes::dsl::*;
+    let conn = getConn!();
+    let rows = diesel::update(recovery_codes
+        .filter(user_id.eq(_user_id))
+        .filter(code_hash.eq(_code_hash))
+        .filter(used_at.is_null()))
+        .set(used_at.eq(now))
+        .execute(conn)?;
+
+    Ok(rows > 0)
+}
+
+pub fn delete_recovery_codes(_user_id: i32) -> QueryResult<usize> {
+    use crate::schema::recovery_codes::dsl::*;
+    let conn = getConn!();
+    diesel::delete(recovery_codes.filter(user_id.eq(_user_id))).execute(conn)
+}
+
 #[test]
 fn test_find_user() {
     // dotenv().ok();
@@ -0,0 +1,148 @@
+use actix_session::Session;
+use actix_web::{rt, web, HttpRequest, HttpResponse};
+use chrono::{Duration, Utc};
+use log::{error, info, warn};
+use openssl::rand::rand_bytes;
+use serde::Deserialize;
+
+use crate::database::QueryResult;
+use crate::hashing::{generate_hash, verify_password};
+use crate::local_env::*;
+use crate::mailer::{self, Email};
+use crate::models::User;
+
+use super::{database, mfa, Mode};
+
+#[derive(Deserialize)]
+pub struct CodeRequest {
+    /// Username or email
+    pub login: String,
+}
+
+#[derive(Deserialize)]
+pub struct VerifyRequest {
+    /// Same login as the one the code was requested with
+    pub login: String,
+    pub code: String,
+    /// Same as `AuthRequest::token`
+    #[serde(default)]
+    pub token: bool,
+}
+
+/// 6 random digits, without modulo bias
+fn generate_code() -> String {
+    let mut buf = [0u8; 4];
+    loop {
+        rand_bytes(&mut buf).unwrap();
+        let value = u32::from_be_bytes(buf);
+        // Largest multiple of 10^6 that fits in a u32
+        if value < 4_294_000_000 {
+            return format!("{:06}", value % 1_000_000);
+        }
+    }
+}
+
+/// Store the code hashed and email it
+async fn send_code(user: User, login: String) -> Result<(), anyhow::Error> {
+    let code = generate_code();
+    let expires_at = Utc::now().naive_utc() + Duration::seconds(*EMAIL_OTP_TTL);
+    let user_id = user.id;
+    let code_hash = generate_hash(&code);
+    web::block(move || database::create_email_otp(user_id, &login, &code_hash, expires_at)).await??;
+
+    mailer::send(Email {
+        to: user.email,
+        subject: format!("Your login code: {}", code),
+        body: format!(
+            "Hello {},\n\nYour login code is:\n\n{}\n\nIt expires in {} minutes. If you didn't ask for it, you can ignore this email.\n",
+            user.name, code, *EMAIL_OTP_TTL / 60,
+        ),
+    }).await
+}
+
+/// Email a login code, the response is the same for unknown logins
+pub async fn send(body: web::Json<CodeRequest>) -> HttpResponse {
+    let login = body.into_inner().login;
+
+    rt::spawn(async move {
+        match database::get_user(Mode::Login(login.clone())).await {
+            Ok(user) => {
+                let name = user.name.clone();
+                match send_code(user, login).await {
+                    Ok(()) => info!("[{}] -- Login code sent to user {}", "UserService::email_otp", name),
+                    Err(e) => error!("[{}] -- Login code not sent: {}", "UserService::email_otp", e),
+                }
+            },
+            Err(_e) => info!("[{}] -- Unknown login", "UserService::email_otp"),
+        }
+    });
+
+    HttpResponse::Accepted().finish()
+}
+
+/// Check a code against the latest one sent for the login, returns its owner
+fn use_code(login: &str, code: &str) -> QueryResult<Option<i32>> {
+    let now = Utc::now().naive_utc();
+    let otp = match database::find_email_otp(login, now)? {
+        Some(x) => x,
+        None => return Ok(None),
+    };
+
+    // The attempt is counted before the check so parallel guesses can't exceed the limit
+    if !database::count_email_otp_attempt(otp.id, *EMAIL_OTP_MAX_ATTEMPTS)? {
+        warn!("[{}] -- Too many attempts", "UserService::email_otp_login");
+        return Ok(None);
+    }
+    if !verify_password(code.trim().as_bytes(), &otp.code_hash).unwrap_or(false) {
+        return Ok(None);
+    }
+
+    Ok(database::use_email_otp(otp.id, now)?.then_some(otp.user_id))
+}
+
+/// Log in with a code from `send`, second factors still apply
+pub async fn login(req: HttpRequest, sess: Session, body: web::Json<VerifyRequest>) -> HttpResponse {
+    let body = body.into_inner();
+    let (login, code) = (body.login.clone(), body.code.clone());
+
+    let user_id = match web::block(move || use_code(&login, &code)).await {
+        Ok(Ok(Some(x))) => x,
+        Ok(Ok(None)) => {
+            warn!("[{}] -- Invalid code", "UserService::email_otp_login");
+            return HttpResponse::Unauthorized().finish();
+        },
+        Ok(Err(e)) => {
+            error!("[{}] -- {}", "UserService::email_otp_login", e);
+            return HttpResponse::InternalServerError().finish();
+        },
+        Err(e) => {
+            error!("[{}] -- {}", "UserService::email_otp_login", e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+
+    match database::get_user(Mode::Id(user_id)).await {
+        Ok(user) => {
+            info!("[{}] -- User authenticated", "UserService::email_otp_login");
+            mfa::password_login(&req, &sess, user, body.token)
+        },
+        Err(e) => {
+            error!("[{}] -- {}", "UserService::email_otp_login", e);
+            HttpResponse::Unauthorized().finish()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_generate_code() {
+        for _ in 0..100 {
+            let code = generate_code();
+            assert_eq!(code.len(), 6);
+            assert!(code.chars().all(|x| x.is_ascii_digit()));
+        }
+    }
+}
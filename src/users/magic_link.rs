@@ -0,0 +1,136 @@
+use actix_identity::Identity;
+use actix_session::Session;
+use actix_web::{rt, web, HttpRequest, HttpResponse};
+use chrono::{DateTime, Utc};
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::jwt;
+use crate::local_env::*;
+use crate::mailer::{self, Email};
+use crate::models::User;
+
+use super::{current_user, database, mfa, Mode};
+
+/// Audience suffix of the login tokens
+const PURPOSE: &str = "magic-link";
+
+#[derive(Deserialize)]
+pub struct MagicLinkRequest {
+    pub email: String,
+}
+
+#[derive(Deserialize)]
+pub struct ConsumeRequest {
+    pub token: String,
+}
+
+#[derive(Serialize)]
+struct Revoked {
+    revoked: usize,
+}
+
+/// Sign a login link, its jti is recorded so it can only be used once
+async fn send_link(user: User) -> Result<(), anyhow::Error> {
+    let (token, claims) = jwt::issue_email_token(&user.id.to_string(), &user.email, PURPOSE, *MAGIC_LINK_TTL)?;
+    let expires_at = DateTime::from_timestamp(claims.exp, 0).unwrap_or_default().naive_utc();
+    let user_id = user.id;
+    web::block(move || database::create_magic_link(user_id, &claims.jti, expires_at)).await??;
+
+    // The link opens a page that posts the token: mail scanners following
+    // links would otherwise burn it before the user clicks
+    let link = format!("{}/magic-link?token={}", PUBLIC_URL.trim_end_matches('/'), token);
+    mailer::send(Email {
+        to: user.email,
+        subject: String::from("Your login link"),
+        body: format!(
+            "Hello {},\n\nFollow this link to log in:\n\n{}\n\nThe link can be used once and expires in {} minutes. If you didn't ask for it, you can ignore this email.\n",
+            user.name, link, *MAGIC_LINK_TTL / 60,
+        ),
+    }).await
+}
+
+/// Email a login link, the response is the same for unknown addresses
+pub async fn send(body: web::Json<MagicLinkRequest>) -> HttpResponse {
+    let email = body.into_inner().email;
+
+    rt::spawn(async move {
+        match database::get_user(Mode::Email(email)).await {
+            Ok(user) => {
+                let name = user.name.clone();
+                match send_link(user).await {
+                    Ok(()) => info!("[{}] -- Magic link sent to user {}", "UserService::magic_link", name),
+                    Err(e) => error!("[{}] -- Magic link not sent: {}", "UserService::magic_link", e),
+                }
+            },
+            Err(_e) => info!("[{}] -- Unknown email", "UserService::magic_link"),
+        }
+    });
+
+    HttpResponse::Accepted().finish()
+}
+
+/// Log in with a link from `send`, second factors still apply
+pub async fn login(req: HttpRequest, sess: Session, body: web::Json<ConsumeRequest>) -> HttpResponse {
+    let claims = match jwt::decode_email_token(&body.token, PURPOSE) {
+        Ok(x) => x,
+        Err(e) => {
+            warn!("[{}] -- Invalid token: {}", "UserService::magic_link_login", e);
+            return HttpResponse::Unauthorized().finish();
+        }
+    };
+    let user_id: i32 = match claims.sub.parse() {
+        Ok(x) => x,
+        Err(_e) => return HttpResponse::Unauthorized().finish(),
+    };
+
+    match database::use_magic_link(user_id, &claims.jti, Utc::now().naive_utc()) {
+        Ok(true) => {},
+        Ok(false) => {
+            warn!("[{}] -- Link used, revoked or expired", "UserService::magic_link_login");
+            return HttpResponse::Unauthorized().finish();
+        },
+        Err(e) => {
+            error!("[{}] -- {}", "UserService::magic_link_login", e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    }
+
+    match database::get_user(Mode::Id(user_id)).await {
+        // The link proves access to the address it was sent to, not to a new one
+        Ok(user) if user.email == claims.email => {
+            info!("[{}] -- User authenticated", "UserService::magic_link_login");
+            mfa::password_login(&req, &sess, user, false)
+        },
+        Ok(_) => {
+            warn!("[{}] -- Address changed since the link was sent", "UserService::magic_link_login");
+            HttpResponse::Unauthorized().finish()
+        },
+        Err(e) => {
+            error!("[{}] -- {}", "UserService::magic_link_login", e);
+            HttpResponse::Unauthorized().finish()
+        }
+    }
+}
+
+/// Revoke the links of the logged in user that were not used yet
+pub async fn revoke(user: Option<Identity>) -> HttpResponse {
+    let user = match current_user(&user).await {
+        Some(x) => x,
+        None => {
+            error!("[{}] -- Unauthorized", "UserService::magic_link_revoke");
+            return HttpResponse::Unauthorized().finish();
+        }
+    };
+
+    match database::revoke_magic_links(user.id, Utc::now().naive_utc()) {
+        Ok(revoked) => {
+            info!("[{}] -- {} magic links revoked for user {}", "UserService::magic_link_revoke", revoked, user.name);
+            HttpResponse::Ok().json(Revoked { revoked })
+        },
+        Err(e) => {
+            error!("[{}] -- {}", "UserService::magic_link_revoke", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
@@ -0,0 +1,120 @@
+use actix_session::Session;
+use actix_web::{HttpRequest, HttpResponse};
+use chrono::Utc;
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::database::QueryResult;
+use crate::models::User;
+
+use super::{database, login, Mode};
+
+/// Key of the pending login in the session, set between the password and the second factor
+const PENDING_KEY: &str = "mfa_pending";
+/// Time allowed to present the second factor, in seconds
+const PENDING_TTL: i64 = 300;
+/// Codes accepted before the password must be entered again
+const MAX_ATTEMPTS: i32 = 5;
+
+#[derive(Serialize, Deserialize)]
+pub struct PendingLogin {
+    pub user_id: i32,
+    /// Same as `AuthRequest::token`
+    token: bool,
+    expires_at: i64,
+}
+
+#[derive(Serialize)]
+struct Challenge {
+    mfa_required: bool,
+    methods: Vec<&'static str>,
+}
+
+/// Second factors enrolled by the user
+pub fn methods(user_id: i32) -> QueryResult<Vec<&'static str>> {
+    let mut methods = Vec::new();
+    if database::find_totp(user_id)?.is_some_and(|x| x.confirmed_at.is_some()) {
+        methods.push("totp");
+    }
+    if !database::find_webauthn_credentials(user_id)?.is_empty() {
+        methods.push("webauthn");
+    }
+    // Recovery codes only stand in for another factor
+    if !methods.is_empty() && database::count_recovery_codes(user_id)? > 0 {
+        methods.push("recovery_code");
+    }
+    Ok(methods)
+}
+
+/// Open the session after a password check, or ask for a second factor if the user enrolled one
+pub fn password_login(req: &HttpRequest, sess: &Session, user: User, token: bool) -> HttpResponse {
+    let methods = match methods(user.id) {
+        Ok(x) => x,
+        Err(e) => {
+            error!("[{}] -- {}", "UserService::mfa", e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+    if methods.is_empty() {
+        return login(req, user, token);
+    }
+
+    let pending = PendingLogin {
+        user_id: user.id,
+        token,
+        expires_at: Utc::now().timestamp() + PENDING_TTL,
+    };
+    if let Err(e) = database::reset_mfa_attempts(user.id) {
+        error!("[{}] -- {}", "UserService::mfa", e);
+        return HttpResponse::InternalServerError().finish();
+    }
+    if let Err(e) = sess.insert(PENDING_KEY, &pending) {
+        error!("[{}] -- {}", "UserService::mfa", e);
+        return HttpResponse::InternalServerError().finish();
+    }
+
+    info!("[{}] -- Second factor required for user {}", "UserService::mfa", user.name);
+    HttpResponse::Ok().json(Challenge { mfa_required: true, methods })
+}
+
+/// Login waiting for a second factor, if it hasn't expired
+pub fn pending(sess: &Session) -> Option<PendingLogin> {
+    sess.get::<PendingLogin>(PENDING_KEY).ok()
+        .flatten()
+        .filter(|x| x.expires_at > Utc::now().timestamp())
+}
+
+/// Pending login for an attempt at the second factor, counted before the factor is
+/// checked so parallel guesses can't exceed MAX_ATTEMPTS. The count is kept by user,
+/// replaying an older session cookie doesn't reset it. None once they are used up.
+pub fn attempt(sess: &Session) -> Option<PendingLogin> {
+    let pending = pending(sess)?;
+    match database::count_mfa_attempt(pending.user_id, MAX_ATTEMPTS) {
+        Ok(true) => Some(pending),
+        Ok(false) => {
+            warn!("[{}] -- Too many wrong codes for user {}", "UserService::mfa", pending.user_id);
+            sess.remove(PENDING_KEY);
+            None
+        },
+        Err(e) => {
+            error!("[{}] -- Attempt not counted: {}", "UserService::mfa", e);
+            None
+        }
+    }
+}
+
+/// Open the session once the second factor is verified
+pub async fn complete(req: &HttpRequest, sess: &Session, pending: PendingLogin) -> HttpResponse {
+    sess.remove(PENDING_KEY);
+
+    match database::get_user(Mode::Id(pending.user_id)).await {
+        Ok(user) => {
+            info!("[{}] -- Second factor verified", "UserService::mfa");
+            login(req, user, pending.token)
+        },
+        Err(e) => {
+            error!("[{}] -- {}", "UserService::mfa", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
@@ -1,23 +1,43 @@
 use actix_identity::Identity;
 use actix_session::Session;
-use actix_web::{get, post, web, App, HttpResponse, HttpServer, Responder, HttpRequest, HttpMessage};
-use log::{error, warn, info, debug, trace, LevelFilter};
+use actix_web::{middleware::from_fn, rt, web, HttpResponse, Responder, HttpRequest};
+use chrono::{Duration, NaiveDateTime, Utc};
+use log::{error, warn, info};
 use serde::{Deserialize, Serialize};
 use crate::hashing::{
     generate_hash,
+    needs_rehash,
+    verify_dummy,
     verify_password
 };
-
-mod database;
-mod session;
+use crate::jwt::{self, TokenResponse};
+use crate::local_env::{LOCKOUT_DURATION, LOCKOUT_THRESHOLD, REQUIRE_VERIFIED_EMAIL};
+use crate::models::User;
+use crate::password_policy;
+use crate::rate_limit;
+
+pub mod database;
+pub mod email_otp;
+pub mod magic_link;
+pub mod mfa;
+pub mod password;
+pub mod recovery;
+pub mod refresh;
+pub mod session;
+pub mod totp;
+pub mod verification;
+pub mod webauthn;
 
 pub enum Mode {
     Id(i32),
     Username(String),
     Email(String),
+    /// Username or email
+    Login(String),
 }
 
 #[derive(Deserialize)]
+#[allow(dead_code)]
 pub struct UserIdentifier {
     id: Option<i32>,
     username: Option<String>,
@@ -37,6 +57,9 @@ struct ResUser {
 pub struct AuthRequest {
     pub login: String,
     pub password: String,
+    /// Respond with a signed access token and a refresh token instead of the user
+    #[serde(default)]
+    pub token: bool,
 }
 
 #[derive(Deserialize, Debug)]
@@ -49,14 +72,157 @@ pub struct CreateUser {
 pub fn users_config(cfg: &mut web::ServiceConfig) {
     cfg.service(
         web::resource("/auth")
+            .wrap(from_fn(rate_limit::limit))
             .route(web::post().to(auth))
             .route(web::get().to(HttpResponse::MethodNotAllowed))
     );
+    cfg.service(
+        web::resource("/auth/totp")
+            .wrap(from_fn(rate_limit::limit))
+            .route(web::post().to(totp::login))
+            .route(web::get().to(HttpResponse::MethodNotAllowed))
+    );
+    cfg.service(
+        web::resource("/auth/recovery")
+            .wrap(from_fn(rate_limit::limit))
+            .route(web::post().to(recovery::login))
+            .route(web::get().to(HttpResponse::MethodNotAllowed))
+    );
+    cfg.service(
+        web::resource("/auth/magic-link")
+            .wrap(from_fn(rate_limit::limit))
+            .route(web::post().to(magic_link::send))
+            .route(web::get().to(HttpResponse::MethodNotAllowed))
+    );
+    cfg.service(
+        web::resource("/auth/magic-link/verify")
+            .wrap(from_fn(rate_limit::limit))
+            .route(web::post().to(magic_link::login))
+            .route(web::get().to(HttpResponse::MethodNotAllowed))
+    );
+    cfg.service(
+        web::resource("/auth/email-code")
+            .wrap(from_fn(rate_limit::limit))
+            .route(web::post().to(email_otp::send))
+            .route(web::get().to(HttpResponse::MethodNotAllowed))
+    );
+    cfg.service(
+        web::resource("/auth/email-code/verify")
+            .wrap(from_fn(rate_limit::limit))
+            .route(web::post().to(email_otp::login))
+            .route(web::get().to(HttpResponse::MethodNotAllowed))
+    );
+    cfg.service(
+        web::resource("/auth/webauthn/options")
+            .wrap(from_fn(rate_limit::limit))
+            .route(web::post().to(webauthn::login_options))
+    );
+    cfg.service(
+        web::resource("/auth/webauthn")
+            .wrap(from_fn(rate_limit::limit))
+            .route(web::post().to(webauthn::login_finish))
+            .route(web::get().to(HttpResponse::MethodNotAllowed))
+    );
+    cfg.service(
+        web::resource("/verify-email")
+            .route(web::get().to(verification::verify_link))
+            .route(web::post().to(verification::verify))
+    );
+    cfg.service(
+        web::resource("/verify-email/resend")
+            .wrap(from_fn(rate_limit::limit))
+            .route(web::post().to(verification::resend))
+            .route(web::get().to(HttpResponse::MethodNotAllowed))
+    );
+    cfg.service(
+        web::resource("/password/forgot")
+            .wrap(from_fn(rate_limit::limit))
+            .route(web::post().to(password::forgot))
+            .route(web::get().to(HttpResponse::MethodNotAllowed))
+    );
+    cfg.service(
+        web::resource("/password/reset")
+            .wrap(from_fn(rate_limit::limit))
+            .route(web::post().to(password::reset))
+            .route(web::get().to(HttpResponse::MethodNotAllowed))
+    );
+    cfg.service(
+        web::resource("/token/refresh")
+            .wrap(from_fn(rate_limit::limit))
+            .route(web::post().to(refresh::refresh))
+            .route(web::get().to(HttpResponse::MethodNotAllowed))
+    );
+    cfg.service(
+        web::resource("/token/revoke")
+            .route(web::post().to(refresh::revoke))
+            .route(web::get().to(HttpResponse::MethodNotAllowed))
+    );
+    cfg.service(
+        web::resource("/logout")
+            .route(web::post().to(logout))
+            .route(web::get().to(HttpResponse::MethodNotAllowed))
+    );
+    cfg.service(
+        web::resource("/logout-all")
+            .route(web::post().to(logout_all))
+            .route(web::get().to(HttpResponse::MethodNotAllowed))
+    );
+    cfg.service(
+        web::resource("/me/sessions")
+            .route(web::get().to(list_sessions))
+    );
+    cfg.service(
+        web::resource("/me/sessions/{sid}")
+            .route(web::delete().to(revoke_session))
+    );
+    cfg.service(
+        web::resource("/me/password")
+            .wrap(from_fn(rate_limit::limit))
+            .route(web::post().to(password::change))
+    );
+    cfg.service(
+        web::resource("/me/magic-links")
+            .route(web::delete().to(magic_link::revoke))
+    );
+    cfg.service(
+        web::resource("/me/totp")
+            .route(web::post().to(totp::enroll))
+            .route(web::delete().to(totp::disable))
+    );
+    cfg.service(
+        web::resource("/me/totp/confirm")
+            .route(web::post().to(totp::confirm))
+    );
+    cfg.service(
+        web::resource("/me/recovery-codes")
+            .route(web::get().to(recovery::remaining))
+            .route(web::post().to(recovery::regenerate))
+    );
+    cfg.service(
+        web::resource("/me/webauthn")
+            .route(web::get().to(webauthn::list_credentials))
+    );
+    cfg.service(
+        web::resource("/me/webauthn/register/options")
+            .route(web::post().to(webauthn::register_options))
+    );
+    cfg.service(
+        web::resource("/me/webauthn/register")
+            .route(web::post().to(webauthn::register))
+    );
+    cfg.service(
+        web::resource("/me/webauthn/{id}")
+            .route(web::delete().to(webauthn::delete_credential))
+    );
     cfg.service(
         web::resource("/list")
             .route(web::get().to(list))
             .route(web::post().to(HttpResponse::MethodNotAllowed))
     );
+    cfg.service(
+        web::resource("/{id}/unlock")
+            .route(web::post().to(unlock))
+    );
     cfg.service(
         web::resource("/{id}")
             .route(web::get().to(get_user))
@@ -65,6 +231,7 @@ pub fn users_config(cfg: &mut web::ServiceConfig) {
     );
     cfg.service(
         web::resource("/")
+            .wrap(from_fn(rate_limit::limit))
             .route(web::post().to(create))
     );
 }
@@ -83,69 +250,285 @@ fn auth_user(provided_password: &[u8], password: &str) -> Result<bool, argon2::p
     Ok(res)
 }
 
-pub async fn auth(_req: HttpRequest, body: web::Json<AuthRequest>, sess: Session) -> impl Responder {
+/// Open a session for an authenticated user, optionally returning an access token
+fn login(req: &HttpRequest, user: User, token: bool) -> HttpResponse {
+    if *REQUIRE_VERIFIED_EMAIL && user.email_verified_at.is_none() {
+        warn!("[{}] -- Email not verified for user {}", "UserService::auth", user.name);
+        return HttpResponse::Forbidden().body("Email not verified");
+    }
+
+    info!("[{}] -- Session creation..", "UserService::auth");
+    if let Err(e) = session::create_session(req, user.name.clone()) {
+        error!("[{}] -- Session creation failed: {}", "UserService::auth", e);
+        return match e.downcast_ref::<session::IndexError>() {
+            Some(x) => HttpResponse::build(x.status()).finish(),
+            None => HttpResponse::InternalServerError().finish(),
+        };
+    }
+    info!("[{}] -- Session created", "UserService::auth");
+
+    if !token {
+        return HttpResponse::Ok().json(user);
+    }
+
+    let refresh_token = match refresh::issue(user.id, None, None, None) {
+        Ok(x) => x,
+        Err(e) => {
+            error!("[{}] -- Refresh token creation failed: {}", "UserService::auth", e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+
+    match jwt::issue_access_token(&user.id.to_string(), None, None) {
+        Ok(access_token) => {
+            info!("[{}] -- Access token issued", "UserService::auth");
+            HttpResponse::Ok().json(TokenResponse::bearer(access_token, Some(refresh_token)))
+        },
+        Err(e) => {
+            error!("[{}] -- Access token signing failed: {}", "UserService::auth", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+/// Why a login was refused. Only logged, the client always gets `login_failed`.
+#[derive(Debug, PartialEq)]
+enum LoginFailure {
+    UnknownUser,
+    Locked,
+    WrongPassword,
+    InvalidHash,
+}
+
+/// Same response whatever the reason, so it doesn't tell whether the account exists or is locked
+fn login_failed(failure: LoginFailure) -> HttpResponse {
+    error!("[{}] -- User authentication failed: {:?}", "UserService::auth", failure);
+    HttpResponse::Unauthorized().finish()
+}
+
+/// Check the password of the user matching the login, if any. Every outcome
+/// costs one Argon2 verification and nothing else before the response.
+/// Accounts still on a hash weaker than the current parameters answer faster
+/// than unknown logins, until their next successful login rehashes them.
+fn check_password(user: Option<&User>, password: &str, now: NaiveDateTime) -> Result<(), LoginFailure> {
+    let user = match user {
+        Some(x) => x,
+        None => {
+            verify_dummy(password.as_bytes());
+            return Err(LoginFailure::UnknownUser);
+        }
+    };
+
+    // The password is checked even while locked so the timing doesn't give it away either
+    let res = auth_user(password.as_bytes(), &user.password);
+    if user.locked_until.is_some_and(|x| x > now) {
+        return Err(LoginFailure::Locked);
+    }
+
+    match res {
+        Ok(true) => Ok(()),
+        // A mismatch is reported as an error by argon2
+        Ok(false) | Err(argon2::password_hash::Error::Password) => Err(LoginFailure::WrongPassword),
+        Err(e) => {
+            error!("[{}] -- Stored hash can't be checked: {}", "UserService::auth", e);
+            Err(LoginFailure::InvalidHash)
+        }
+    }
+}
+
+/// Count a wrong password against the account, locking it at LOCKOUT_THRESHOLD.
+/// Recorded after the response so the extra query doesn't show in its timing.
+fn failed_login(user: User, now: NaiveDateTime) {
+    if *LOCKOUT_THRESHOLD <= 0 {
+        return;
+    }
+
+    let lock_until = now + Duration::seconds(*LOCKOUT_DURATION);
+    rt::spawn(async move {
+        match web::block(move || database::record_failed_login(user.id, *LOCKOUT_THRESHOLD, lock_until)).await {
+            Ok(Ok(true)) => warn!("[{}] -- Account locked for {}s for user {}", "UserService::auth", *LOCKOUT_DURATION, user.name),
+            Ok(Ok(false)) => {},
+            Ok(Err(e)) => error!("[{}] -- Failed attempt not recorded: {}", "UserService::auth", e),
+            Err(e) => error!("[{}] -- Failed attempt not recorded: {}", "UserService::auth", e),
+        }
+    });
+}
+
+/// Store the password again with the current Argon2 parameters
+async fn rehash(user: &User, password: String) {
+    let (user_id, current) = (user.id, user.password.clone());
+    let saved = web::block(move || {
+        database::rehash_password(user_id, &current, &generate_hash(&password))
+    }).await;
+
+    match saved {
+        Ok(Ok(true)) => info!("[{}] -- Password rehashed with the current parameters", "UserService::auth"),
+        Ok(Ok(false)) => {},
+        Ok(Err(e)) => error!("[{}] -- Rehash not saved: {}", "UserService::auth", e),
+        Err(e) => error!("[{}] -- Rehash not saved: {}", "UserService::auth", e),
+    }
+}
+
+pub async fn auth(req: HttpRequest, body: web::Json<AuthRequest>, sess: Session) -> impl Responder {
     info!("[{}] -- Authenticating user", "UserService::auth");
-    let mut user = database::get_user(Mode::Username(body.login.clone())).await;
-    match user {
-        Ok(user) => {
-            let res = auth_user(&body.password.as_bytes(), &user.password);
-            match res {
-                Ok(true) => {
-                    info!("[{}] -- User authenticated", "UserService::auth");
-                    info!("[{}] -- Session creation..", "UserService::auth");
-
-                    match session::create_session(&_req.extensions(), user.name.clone()) {
-                        Ok(()) => {
-                            info!("[{}] -- Session created", "UserService::auth");
-                            HttpResponse::Ok().json(user)
-                        },
-                        Err(e) => {
-                            error!("[{}] -- Session creation failed: {}", "UserService::auth", e);
-                            HttpResponse::InternalServerError().finish()
-                        }
-                    }
-                },
-                Ok(false) => {
-                    error!("[{}] -- User authentication failed", "UserService::auth");
-                    HttpResponse::Unauthorized().finish()
-                },
-                Err(e) => {
-                    error!("[{}] -- User authentication failed: {}", "UserService::auth", e);
-                    HttpResponse::Unauthorized().finish()
-                }
-            }
+    let user = database::get_user(Mode::Login(body.login.clone())).await.ok();
+    let now = Utc::now().naive_utc();
+
+    let user = match (check_password(user.as_ref(), &body.password, now), user) {
+        (Ok(()), Some(user)) => user,
+        (Err(LoginFailure::WrongPassword), Some(user)) => {
+            failed_login(user, now);
+            return login_failed(LoginFailure::WrongPassword);
+        },
+        (Err(failure), _) => return login_failed(failure),
+        (Ok(()), None) => return login_failed(LoginFailure::UnknownUser),
+    };
+
+    info!("[{}] -- User authenticated", "UserService::auth");
+    if user.failed_logins > 0 || user.locked_until.is_some() {
+        if let Err(e) = database::unlock_user(user.id) {
+            error!("[{}] -- Failed attempts not cleared: {}", "UserService::auth", e);
         }
-        Err(err) => {
-            warn!("[{}] -- User authentication failed using username: {}", "UserService::auth", err);
-            user = database::get_user(Mode::Email(body.login.clone())).await;
-            match user {
-                Ok(user) => {
-                    let res = auth_user(&body.password.as_bytes(), &user.password);
-                    match res {
-                        Ok(true) => {
-                            info!("[{}] -- User authenticated", "UserService::auth");
-                            HttpResponse::Ok().json(user) // TODO
-                        },
-                        Ok(false) => {
-                            error!("[{}] -- User authentication failed", "UserService::auth");
-                            HttpResponse::Unauthorized().finish()
-                        },
-                        Err(e) => {
-                            error!("[{}] -- User authentication failed: {}", "UserService::auth", e);
-                            HttpResponse::Unauthorized().finish()
-                        }
-                    }
-                },
-                Err(e) => {
-                    error!("[{}] -- User authentication failed: {}", "UserService::auth", err);
-                    error!("[{}] -- User not found", "UserService::auth");
-                    HttpResponse::Unauthorized().finish()
-                }
-            }
+    }
+    if needs_rehash(&user.password) {
+        rehash(&user, body.password.clone()).await;
+    }
+    mfa::password_login(&req, &sess, user, body.token)
+}
+
+/// Clear the lock and failed attempts of a user, for admins
+pub async fn unlock(user: Option<Identity>, info: web::Path<UserIdentifier>) -> HttpResponse {
+    match current_user(&user).await {
+        Some(x) if x.is_admin => {},
+        Some(x) => {
+            warn!("[{}] -- User {} is not an admin", "UserService::unlock", x.name);
+            return HttpResponse::Forbidden().finish();
+        },
+        None => {
+            error!("[{}] -- Unauthorized", "UserService::unlock");
+            return HttpResponse::Unauthorized().finish();
+        }
+    }
+
+    let user_id = match get_id_from_req(info) {
+        Ok(x) => x,
+        Err(e) => {
+            error!("[{}] -- {}", "UserService::unlock", e);
+            return HttpResponse::BadRequest().body(e);
+        }
+    };
+
+    match database::unlock_user(user_id) {
+        Ok(true) => {
+            info!("[{}] -- User {} unlocked", "UserService::unlock", user_id);
+            HttpResponse::NoContent().finish()
+        },
+        Ok(false) => HttpResponse::NotFound().finish(),
+        Err(e) => {
+            error!("[{}] -- {}", "UserService::unlock", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+
+pub async fn logout(user: Option<Identity>, sess: Session) -> HttpResponse {
+    let user = match user {
+        Some(x) => x,
+        None => {
+            error!("[{}] -- Unauthorized", "UserService::logout");
+            return HttpResponse::Unauthorized().finish();
+        }
+    };
+
+    if let Err(e) = session::end_session(&sess) {
+        error!("[{}] -- Session index update failed: {}", "UserService::logout", e);
+        return HttpResponse::ServiceUnavailable().finish();
+    }
+    user.logout();
+
+    info!("[{}] -- User logged out", "UserService::logout");
+    HttpResponse::Ok().finish()
+}
+
+/// End every session of the user, on all devices
+pub async fn logout_all(user: Option<Identity>) -> HttpResponse {
+    let (user, name) = match identity_name(&user).zip(user) {
+        Some((name, user)) => (user, name),
+        None => {
+            error!("[{}] -- Unauthorized", "UserService::logout_all");
+            return HttpResponse::Unauthorized().finish();
+        }
+    };
+
+    if let Err(e) = session::end_all_sessions(&name) {
+        error!("[{}] -- Session index update failed: {}", "UserService::logout_all", e);
+        return HttpResponse::build(e.status()).finish();
+    }
+    user.logout();
+
+    info!("[{}] -- All sessions ended for user {}", "UserService::logout_all", name);
+    HttpResponse::Ok().finish()
+}
+
+/// Name of the logged in user, if any
+fn identity_name(user: &Option<Identity>) -> Option<String> {
+    user.as_ref().and_then(|x| x.id().ok())
+}
+
+/// Logged in user, loaded from the database
+async fn current_user(user: &Option<Identity>) -> Option<User> {
+    let name = identity_name(user)?;
+    database::get_user(Mode::Username(name)).await
+        .map_err(|e| error!("[{}] -- {}", "UserService::current_user", e))
+        .ok()
+}
+
+pub async fn list_sessions(user: Option<Identity>, sess: Session) -> HttpResponse {
+    let name = match identity_name(&user) {
+        Some(x) => x,
+        None => {
+            error!("[{}] -- Unauthorized", "UserService::list_sessions");
+            return HttpResponse::Unauthorized().finish();
+        }
+    };
+
+    match session::list_sessions(&name, &sess) {
+        Ok(sessions) => {
+            info!("[{}] -- Found {} sessions", "UserService::list_sessions", sessions.len());
+            HttpResponse::Ok().json(sessions)
+        },
+        Err(e) => {
+            error!("[{}] -- {}", "UserService::list_sessions", e);
+            HttpResponse::build(e.status()).finish()
         }
     }
 }
 
+pub async fn revoke_session(user: Option<Identity>, sid: web::Path<String>, sess: Session) -> HttpResponse {
+    let name = match identity_name(&user) {
+        Some(x) => x,
+        None => {
+            error!("[{}] -- Unauthorized", "UserService::revoke_session");
+            return HttpResponse::Unauthorized().finish();
+        }
+    };
+
+    match session::revoke_session(&name, &sid) {
+        Ok(true) => {
+            info!("[{}] -- Session revoked for user {}", "UserService::revoke_session", name);
+            if session::current_sid(&sess).as_deref() == Some(sid.as_str()) {
+                sess.purge();
+            }
+            HttpResponse::NoContent().finish()
+        },
+        Ok(false) => HttpResponse::NotFound().finish(),
+        Err(e) => {
+            error!("[{}] -- {}", "UserService::revoke_session", e);
+            HttpResponse::build(e.status()).finish()
+        }
+    }
+}
 
 pub async fn get_user(_req: HttpRequest, info: web::Path<UserIdentifier>) -> HttpResponse {
     info!("[{}] -- Search user", "UserService::get_user");
@@ -252,15 +635,29 @@ pub async fn create(_data: web::Data<crate::AppState>, _req: HttpRequest, body:
     }
 
     let user_creation = web::block(move || {
+        password_policy::check(&body.password, &body.username, &body.email)?;
         let password = generate_hash(body.password.as_str());
 
-        database::create_user(&body.0, password)
+        Ok::<_, password_policy::PolicyError>(database::create_user(&body.0, password))
     }).await;
 
+    let user_creation = match user_creation {
+        Ok(Ok(x)) => Ok(x),
+        Ok(Err(e)) => {
+            warn!("[{}] -- Password rejected by the policy", "UserService::create");
+            return HttpResponse::BadRequest().json(e);
+        },
+        Err(e) => Err(e),
+    };
+
     match user_creation {
         Ok(user) => {
             match user {
-                Ok(_user) => {
+                Ok(user) => {
+                    // The account exists either way, a new link can be requested
+                    if let Err(e) = verification::send(&user).await {
+                        error!("[{}] -- Verification email not sent: {}", "UserService::create", e);
+                    }
                     HttpResponse::Ok().finish()
                 },
                 Err(e) => {
@@ -307,13 +704,57 @@ mod tests {
         test,
     };
 
+    fn user(password: &str, locked_until: Option<NaiveDateTime>) -> User {
+        let now = Utc::now().naive_utc();
+        User {
+            id: 1,
+            name: String::from("valentin"),
+            email: String::from("valentin@example.com"),
+            password: generate_hash(password),
+            created_at: now,
+            updated_at: now,
+            email_verified_at: None,
+            failed_logins: 0,
+            locked_until,
+            is_admin: false,
+            mfa_attempts: 0,
+            credentials_revoked_at: None,
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_login_failures_look_alike() {
+        let now = Utc::now().naive_utc();
+        let locked = Some(now + Duration::minutes(5));
+        let mut invalid = user("password", None);
+        invalid.password = invalid.password.replace("$argon2id$", "$scrypt$");
+
+        let failures = [
+            (check_password(None, "password", now), LoginFailure::UnknownUser),
+            (check_password(Some(&user("password", locked)), "password", now), LoginFailure::Locked),
+            (check_password(Some(&user("password", locked)), "wrong", now), LoginFailure::Locked),
+            (check_password(Some(&user("password", None)), "wrong", now), LoginFailure::WrongPassword),
+            (check_password(Some(&invalid), "password", now), LoginFailure::InvalidHash),
+        ];
+        for (res, expected) in failures {
+            let failure = res.unwrap_err();
+            assert_eq!(failure, expected);
+
+            let resp = login_failed(failure);
+            assert_eq!(resp.status(), http::StatusCode::UNAUTHORIZED);
+            assert!(resp.headers().is_empty());
+            assert!(actix_web::body::to_bytes(resp.into_body()).await.unwrap().is_empty());
+        }
+        assert!(check_password(Some(&user("password", None)), "password", now).is_ok());
+    }
+
     #[actix_web::test]
-    async fn test_index_ok() {
+    async fn test_list_unauthorized() {
         let req = test::TestRequest::get()
             .insert_header(ContentType::plaintext())
             .to_http_request();
-        let resp = list(req).await;
-        assert_eq!(resp.status(), http::StatusCode::OK);
+        let resp = list(req, None).await;
+        assert_eq!(resp.status(), http::StatusCode::UNAUTHORIZED);
     }
     // #[actix_web::test]
     // async fn test_index_not_ok() {
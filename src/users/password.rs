@@ -0,0 +1,228 @@
+use actix_identity::Identity;
+use actix_session::Session;
+use actix_web::{rt, web, HttpResponse};
+use chrono::{Duration, Utc};
+use log::{error, info, warn};
+use serde::Deserialize;
+
+use crate::hashing::{generate_hash, generate_token, hash_token, verify_password};
+use crate::local_env::*;
+use crate::mailer::{self, Email};
+use crate::models::User;
+use crate::password_policy::{self, PolicyError};
+
+use super::{current_user, database, session, Mode};
+use super::session::IndexError;
+
+#[derive(Deserialize)]
+pub struct ForgotRequest {
+    pub email: String,
+}
+
+#[derive(Deserialize)]
+pub struct ResetRequest {
+    pub token: String,
+    pub password: String,
+}
+
+#[derive(Deserialize)]
+pub struct ChangeRequest {
+    pub current_password: String,
+    pub new_password: String,
+    /// End the sessions opened on other devices
+    #[serde(default)]
+    pub revoke_other_sessions: bool,
+}
+
+/// Why a new password was not stored
+enum Refused {
+    Policy(PolicyError),
+    InvalidToken,
+    WrongPassword,
+    Database(diesel::result::Error),
+}
+
+impl From<diesel::result::Error> for Refused {
+    fn from(e: diesel::result::Error) -> Self {
+        Refused::Database(e)
+    }
+}
+
+/// Store a reset token and email it, only its hash is kept
+async fn send_reset(user: User) -> Result<(), anyhow::Error> {
+    let token = generate_token();
+    let expires_at = Utc::now().naive_utc() + Duration::seconds(*PASSWORD_RESET_TTL);
+    let user_id = user.id;
+    let token_hash = hash_token(&token);
+    web::block(move || database::create_password_reset_token(user_id, &token_hash, expires_at)).await??;
+
+    let link = format!("{}/reset-password?token={}", PUBLIC_URL.trim_end_matches('/'), token);
+    mailer::send(Email {
+        to: user.email,
+        subject: String::from("Reset your password"),
+        body: format!(
+            "Hello {},\n\nA password reset was requested for your account. Follow this link to choose a new password:\n\n{}\n\nThe link can be used once and expires in {} minutes. If you didn't ask for it, you can ignore this email.\n",
+            user.name, link, *PASSWORD_RESET_TTL / 60,
+        ),
+    }).await
+}
+
+/// Email a reset link. The lookup and the delivery run in the background so
+/// the response is the same, in content and in time, for unknown addresses.
+pub async fn forgot(body: web::Json<ForgotRequest>) -> HttpResponse {
+    let email = body.into_inner().email;
+
+    rt::spawn(async move {
+        match database::get_user(Mode::Email(email)).await {
+            Ok(user) => {
+                let name = user.name.clone();
+                match send_reset(user).await {
+                    Ok(()) => info!("[{}] -- Reset link sent to user {}", "UserService::forgot_password", name),
+                    Err(e) => error!("[{}] -- Reset link not sent: {}", "UserService::forgot_password", e),
+                }
+            },
+            Err(_e) => info!("[{}] -- Unknown email", "UserService::forgot_password"),
+        }
+    });
+
+    HttpResponse::Accepted().finish()
+}
+
+/// Set a new password with a token from `forgot`, every session of the user ends
+pub async fn reset(body: web::Json<ResetRequest>) -> HttpResponse {
+    let body = body.into_inner();
+    let now = Utc::now().naive_utc();
+    let token_hash = hash_token(&body.token);
+
+    let user = web::block(move || {
+        // The policy needs the user, the token is only consumed below
+        let user = database::find_password_reset_user(&token_hash, now)?.ok_or(Refused::InvalidToken)?;
+        password_policy::check(&body.password, &user.name, &user.email).map_err(Refused::Policy)?;
+        database::reset_password(&token_hash, &generate_hash(&body.password), now)?.ok_or(Refused::InvalidToken)
+    }).await;
+
+    let user = match user {
+        Ok(Ok(x)) => x,
+        Ok(Err(Refused::Policy(e))) => {
+            warn!("[{}] -- Password rejected by the policy", "UserService::reset_password");
+            return HttpResponse::BadRequest().json(e);
+        },
+        Ok(Err(Refused::InvalidToken | Refused::WrongPassword)) => {
+            warn!("[{}] -- Unknown, used or expired token", "UserService::reset_password");
+            return HttpResponse::BadRequest().body("Invalid or expired token");
+        },
+        Ok(Err(Refused::Database(e))) => {
+            error!("[{}] -- {}", "UserService::reset_password", e);
+            return HttpResponse::InternalServerError().finish();
+        },
+        Err(e) => {
+            error!("[{}] -- {}", "UserService::reset_password", e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+
+    // Whoever knew the old password may still hold a session or a token.
+    // Access tokens and cookie sessions are refused from credentials_revoked_at.
+    let mut revoked = true;
+    match session::end_all_sessions(&user.name) {
+        Ok(()) | Err(IndexError::Unsupported) => {},
+        Err(e) => {
+            error!("[{}] -- {}", "UserService::reset_password", e);
+            revoked = false;
+        }
+    }
+    if let Err(e) = database::revoke_refresh_tokens(user.id, now) {
+        error!("[{}] -- Refresh token revocation failed: {}", "UserService::reset_password", e);
+        revoked = false;
+    }
+    if let Err(e) = database::revoke_magic_links(user.id, now) {
+        error!("[{}] -- Magic link revocation failed: {}", "UserService::reset_password", e);
+        revoked = false;
+    }
+    if !revoked {
+        return HttpResponse::ServiceUnavailable().body("Password reset but the sessions could not all be ended");
+    }
+
+    info!("[{}] -- Password reset for user {}", "UserService::reset_password", user.name);
+    HttpResponse::NoContent().finish()
+}
+
+/// Change the password of the logged in user, the current one is required
+pub async fn change(user: Option<Identity>, sess: Session, body: web::Json<ChangeRequest>) -> HttpResponse {
+    let user = match current_user(&user).await {
+        Some(x) => x,
+        None => {
+            error!("[{}] -- Unauthorized", "UserService::change_password");
+            return HttpResponse::Unauthorized().finish();
+        }
+    };
+
+    let body = body.into_inner();
+    let revoke_other_sessions = body.revoke_other_sessions;
+    let (user_id, hash) = (user.id, user.password);
+    let (name, email) = (user.name.clone(), user.email);
+    let now = Utc::now().naive_utc();
+    let changed = web::block(move || {
+        password_policy::check(&body.new_password, &name, &email).map_err(Refused::Policy)?;
+        if !verify_password(body.current_password.as_bytes(), &hash).unwrap_or(false) {
+            return Err(Refused::WrongPassword);
+        }
+        database::update_password(user_id, &generate_hash(&body.new_password), now)?;
+        Ok(())
+    }).await;
+
+    match changed {
+        Ok(Ok(())) => {},
+        Ok(Err(Refused::Policy(e))) => {
+            warn!("[{}] -- Password rejected by the policy", "UserService::change_password");
+            return HttpResponse::BadRequest().json(e);
+        },
+        Ok(Err(Refused::WrongPassword | Refused::InvalidToken)) => {
+            warn!("[{}] -- Wrong current password for user {}", "UserService::change_password", user.name);
+            return HttpResponse::Forbidden().body("Wrong current password");
+        },
+        Ok(Err(Refused::Database(e))) => {
+            error!("[{}] -- {}", "UserService::change_password", e);
+            return HttpResponse::InternalServerError().finish();
+        },
+        Err(e) => {
+            error!("[{}] -- {}", "UserService::change_password", e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    }
+
+    let mut revoked = true;
+    if let Err(e) = session::rotate_session(&sess) {
+        error!("[{}] -- Session rotation failed: {}", "UserService::change_password", e);
+        revoked = false;
+    }
+    if revoke_other_sessions {
+        match session::end_other_sessions(&user.name, &sess) {
+            Ok(x) => info!("[{}] -- {} other sessions ended", "UserService::change_password", x),
+            Err(IndexError::Unsupported) => {},
+            Err(e) => {
+                error!("[{}] -- {}", "UserService::change_password", e);
+                revoked = false;
+            }
+        }
+        // Refuses the cookie sessions and the access tokens, this one is kept
+        if let Err(e) = session::renew_authentication(&sess, now) {
+            error!("[{}] -- {}", "UserService::change_password", e);
+            revoked = false;
+        }
+        if let Err(e) = database::revoke_credentials(user.id, now) {
+            error!("[{}] -- Credential revocation failed: {}", "UserService::change_password", e);
+            revoked = false;
+        }
+        if let Err(e) = database::revoke_refresh_tokens(user.id, now) {
+            error!("[{}] -- Refresh token revocation failed: {}", "UserService::change_password", e);
+            revoked = false;
+        }
+    }
+    if !revoked {
+        return HttpResponse::ServiceUnavailable().body("Password changed but the sessions could not all be ended");
+    }
+
+    info!("[{}] -- Password changed for user {}", "UserService::change_password", user.name);
+    HttpResponse::NoContent().finish()
+}
@@ -0,0 +1,196 @@
+use std::error::Error;
+use actix_identity::Identity;
+use actix_session::Session;
+use actix_web::{web, HttpRequest, HttpResponse};
+use chrono::Utc;
+use log::{error, info, warn};
+use openssl::rand::rand_bytes;
+use serde::{Deserialize, Serialize};
+
+use crate::crypto;
+use crate::database::QueryResult;
+
+use super::{current_user, database, mfa};
+
+/// Codes in a set, a new set replaces the previous one
+const RECOVERY_CODES: usize = 10;
+/// Random bytes per code
+const CODE_LEN: usize = 10;
+
+type RecoveryResult<T> = Result<T, Box<dyn Error + Send + Sync>>;
+
+#[derive(Deserialize)]
+pub struct RecoveryRequest {
+    pub code: String,
+}
+
+#[derive(Serialize)]
+pub struct RecoveryCodes {
+    /// Only shown once, they are stored hashed
+    pub recovery_codes: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct Remaining {
+    remaining: i64,
+}
+
+/// 80 random bits as 20 hex characters, grouped by 5 to be easier to copy
+fn generate_code() -> String {
+    let mut buf = [0u8; CODE_LEN];
+    rand_bytes(&mut buf).unwrap();
+    let code = hex::encode(buf);
+    code.as_bytes()
+        .chunks(5)
+        .map(|x| String::from_utf8_lossy(x).into_owned())
+        .collect::<Vec<String>>()
+        .join("-")
+}
+
+/// Codes are accepted with or without the dash, in any case
+fn normalize(code: &str) -> String {
+    code.chars()
+        .filter(|x| x.is_ascii_alphanumeric())
+        .map(|x| x.to_ascii_lowercase())
+        .collect()
+}
+
+/// Codes are bound to their owner so a hash can't be moved to another account
+fn hash_code(user_id: i32, code: &str) -> RecoveryResult<String> {
+    let hash = crypto::hash(normalize(code).as_bytes(), &format!("recovery:{}", user_id))
+        .ok_or("No MFA_ENCRYPTION_KEY, recovery codes can't be hashed")??;
+    Ok(hash)
+}
+
+/// Generate a new set of codes, invalidating the previous one
+pub fn generate(user_id: i32) -> RecoveryResult<Vec<String>> {
+    let codes: Vec<String> = (0..RECOVERY_CODES).map(|_| generate_code()).collect();
+    let hashes = codes.iter()
+        .map(|x| hash_code(user_id, x))
+        .collect::<RecoveryResult<Vec<String>>>()?;
+
+    database::create_recovery_codes(user_id, &hashes)?;
+    Ok(codes)
+}
+
+/// Codes for a user enrolling a second factor, unless unused ones remain
+pub fn issue_if_missing(user_id: i32) -> RecoveryResult<Option<RecoveryCodes>> {
+    if database::count_recovery_codes(user_id)? > 0 {
+        return Ok(None);
+    }
+    Ok(Some(RecoveryCodes { recovery_codes: generate(user_id)? }))
+}
+
+/// Drop the codes once the user has no second factor left
+pub fn clear_if_unused(user_id: i32) -> QueryResult<()> {
+    if mfa::methods(user_id)?.is_empty() {
+        database::delete_recovery_codes(user_id)?;
+    }
+    Ok(())
+}
+
+/// Consume a code, returns false if it doesn't match an unused one
+fn use_code(user_id: i32, code: &str) -> RecoveryResult<bool> {
+    Ok(database::use_recovery_code(user_id, &hash_code(user_id, code)?, Utc::now().naive_utc())?)
+}
+
+/// Unused codes, for the UI to warn when they are running low
+pub async fn remaining(user: Option<Identity>) -> HttpResponse {
+    let user = match current_user(&user).await {
+        Some(x) => x,
+        None => {
+            error!("[{}] -- Unauthorized", "UserService::recovery_remaining");
+            return HttpResponse::Unauthorized().finish();
+        }
+    };
+
+    match database::count_recovery_codes(user.id) {
+        Ok(remaining) => HttpResponse::Ok().json(Remaining { remaining }),
+        Err(e) => {
+            error!("[{}] -- {}", "UserService::recovery_remaining", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+pub async fn regenerate(user: Option<Identity>) -> HttpResponse {
+    let user = match current_user(&user).await {
+        Some(x) => x,
+        None => {
+            error!("[{}] -- Unauthorized", "UserService::recovery_regenerate");
+            return HttpResponse::Unauthorized().finish();
+        }
+    };
+
+    match mfa::methods(user.id) {
+        Ok(methods) if methods.is_empty() => {
+            return HttpResponse::BadRequest().body("No second factor enrolled");
+        },
+        Ok(_) => {},
+        Err(e) => {
+            error!("[{}] -- {}", "UserService::recovery_regenerate", e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    }
+
+    match web::block(move || generate(user.id)).await {
+        Ok(Ok(recovery_codes)) => {
+            info!("[{}] -- Recovery codes regenerated", "UserService::recovery_regenerate");
+            HttpResponse::Ok().json(RecoveryCodes { recovery_codes })
+        },
+        Ok(Err(e)) => {
+            error!("[{}] -- {}", "UserService::recovery_regenerate", e);
+            HttpResponse::InternalServerError().finish()
+        },
+        Err(e) => {
+            error!("[{}] -- {}", "UserService::recovery_regenerate", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+/// Second step of `users::auth` with a recovery code instead of the enrolled factor
+pub async fn login(req: HttpRequest, sess: Session, body: web::Json<RecoveryRequest>) -> HttpResponse {
+    let pending = match mfa::attempt(&sess) {
+        Some(x) => x,
+        None => {
+            error!("[{}] -- No pending login", "UserService::recovery_login");
+            return HttpResponse::Unauthorized().finish();
+        }
+    };
+
+    let user_id = pending.user_id;
+    let code = body.code.clone();
+    match web::block(move || use_code(user_id, &code)).await {
+        Ok(Ok(true)) => {
+            info!("[{}] -- Recovery code used by user {}", "UserService::recovery_login", user_id);
+            mfa::complete(&req, &sess, pending).await
+        },
+        Ok(Ok(false)) => {
+            warn!("[{}] -- Invalid recovery code", "UserService::recovery_login");
+            HttpResponse::Unauthorized().finish()
+        },
+        Ok(Err(e)) => {
+            error!("[{}] -- {}", "UserService::recovery_login", e);
+            HttpResponse::InternalServerError().finish()
+        },
+        Err(e) => {
+            error!("[{}] -- {}", "UserService::recovery_login", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize() {
+        let code = generate_code();
+        assert_eq!(code.len(), 23);
+        assert_eq!(normalize(&code).len(), CODE_LEN * 2);
+        assert_eq!(normalize(&code.to_uppercase()), normalize(&code));
+        assert_eq!(normalize(" ABCDE-12345 "), "abcde12345");
+    }
+}
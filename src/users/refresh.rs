@@ -0,0 +1,134 @@
+use actix_identity::Identity;
+use actix_web::{web, HttpResponse};
+use chrono::{Duration, Utc};
+use log::{error, warn, info};
+use serde::Deserialize;
+
+use crate::hashing::{generate_token, hash_token};
+use crate::jwt::{self, TokenResponse};
+use crate::local_env::REFRESH_TOKEN_TTL;
+use crate::models::OauthClient;
+use crate::oauth::Revocable;
+
+use super::{current_user, database};
+
+#[derive(Deserialize)]
+pub struct RefreshRequest {
+    pub refresh_token: String,
+}
+
+#[derive(Deserialize)]
+pub struct RevokeRequest {
+    /// Access or refresh token
+    pub token: String,
+}
+
+pub enum RefreshError {
+    /// Unknown, expired, revoked or reused token
+    Invalid,
+    Internal,
+}
+
+/// Store a new refresh token for the user and return it in clear, `client_id` is
+/// the OAuth client it is issued to. A new family is started unless the token is
+/// the rotation of an existing one.
+pub fn issue(user_id: i32, client_id: Option<i32>, family_id: Option<&str>, scope: Option<&str>) -> Result<String, diesel::result::Error> {
+    let token = generate_token();
+    let family_id = family_id.map(String::from).unwrap_or_else(generate_token);
+    let expires_at = Utc::now().naive_utc() + Duration::seconds(*REFRESH_TOKEN_TTL);
+
+    database::create_refresh_token(user_id, client_id, &family_id, &hash_token(&token), scope, expires_at)?;
+    Ok(token)
+}
+
+/// Consume a refresh token and return a new access token along with its rotation.
+/// The token must have been issued to `client`, none for first party logins.
+pub fn exchange(refresh_token: &str, client: Option<&OauthClient>) -> Result<TokenResponse, RefreshError> {
+    let now = Utc::now().naive_utc();
+
+    let token = match database::find_refresh_token(&hash_token(refresh_token)) {
+        Ok(token) => token,
+        Err(e) => {
+            warn!("[{}] -- Unknown refresh token: {}", "UserService::refresh", e);
+            return Err(RefreshError::Invalid);
+        }
+    };
+
+    // Checked first so another client can't burn the token either (RFC 6749 section 6)
+    if token.client_id != client.map(|x| x.id) {
+        warn!("[{}] -- Refresh token issued to another client", "UserService::refresh");
+        return Err(RefreshError::Invalid);
+    }
+
+    // A rotated token presented again means it leaked: kill the whole family
+    let first_use = token.used_at.is_none() && match database::use_refresh_token(token.id, now) {
+        Ok(x) => x,
+        Err(e) => {
+            error!("[{}] -- {}", "UserService::refresh", e);
+            return Err(RefreshError::Internal);
+        }
+    };
+    if !first_use {
+        warn!("[{}] -- Refresh token reused, revoking family {}", "UserService::refresh", token.family_id);
+        if let Err(e) = database::revoke_refresh_token_family(&token.family_id, now) {
+            error!("[{}] -- Family revocation failed: {}", "UserService::refresh", e);
+        }
+        return Err(RefreshError::Invalid);
+    }
+
+    if token.revoked_at.is_some() || token.expires_at < now {
+        warn!("[{}] -- Refresh token revoked or expired", "UserService::refresh");
+        return Err(RefreshError::Invalid);
+    }
+
+    let scope = token.scope.as_deref();
+    let refresh_token = issue(token.user_id, token.client_id, Some(&token.family_id), scope).map_err(|e| {
+        error!("[{}] -- Refresh token creation failed: {}", "UserService::refresh", e);
+        RefreshError::Internal
+    })?;
+
+    let access_token = jwt::issue_access_token(&token.user_id.to_string(), client.map(|x| x.client_id.as_str()), scope).map_err(|e| {
+        error!("[{}] -- Access token signing failed: {}", "UserService::refresh", e);
+        RefreshError::Internal
+    })?;
+
+    info!("[{}] -- Access token refreshed", "UserService::refresh");
+    Ok(TokenResponse::bearer(access_token, Some(refresh_token)).with_scope(token.scope))
+}
+
+pub async fn refresh(body: web::Json<RefreshRequest>) -> HttpResponse {
+    info!("[{}] -- Refreshing access token", "UserService::refresh");
+
+    match exchange(&body.refresh_token, None) {
+        Ok(response) => HttpResponse::Ok().json(response),
+        Err(RefreshError::Invalid) => HttpResponse::Unauthorized().finish(),
+        Err(RefreshError::Internal) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+/// Revoke a token issued by /auth or /token/refresh, the session of the user
+/// authenticates the request. Tokens of other users or of OAuth clients are
+/// ignored, like /oauth/revoke does.
+pub async fn revoke(user: Option<Identity>, body: web::Json<RevokeRequest>) -> HttpResponse {
+    let user = match current_user(&user).await {
+        Some(x) => x,
+        None => {
+            error!("[{}] -- Unauthorized", "UserService::revoke_token");
+            return HttpResponse::Unauthorized().finish();
+        }
+    };
+
+    match Revocable::find(&body.token) {
+        Some(token) if !token.owned_by(user.id) => {
+            warn!("[{}] -- Token not issued to user {}, ignored", "UserService::revoke_token", user.name);
+        },
+        Some(token) => {
+            if let Err(status) = token.revoke() {
+                return HttpResponse::build(status).finish();
+            }
+        },
+        None => {},
+    }
+
+    HttpResponse::Ok().finish()
+}
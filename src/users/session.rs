@@ -1,6 +1,29 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::sync::Mutex;
 use actix_identity::Identity;
-use actix_session::{Session, SessionInsertError, SessionGetError};
-use actix_web::{HttpRequest, dev::Extensions};
+use actix_session::{Session, SessionExt};
+use actix_web::{
+    HttpRequest,
+    HttpMessage,
+    HttpResponse,
+    body::{EitherBody, MessageBody},
+    dev::{ServiceRequest, ServiceResponse},
+    http::{header, StatusCode},
+    middleware::Next
+};
+use chrono::{DateTime, NaiveDateTime, Utc};
+use lazy_static::lazy_static;
+use log::{error, info, warn};
+use redis::Commands;
+use serde::Serialize;
+
+use crate::cache::get_connection;
+use crate::hashing::generate_token;
+use crate::local_env::{SESSION_BACKEND, SESSION_MAX_LIFETIME};
+
+use super::{database, Mode};
 
 // pub fn is_authenticated(session: &Session) -> Result<bool, SessionGetError> {
 //     let res = session.get::<bool>("authenticated")?;
@@ -8,7 +31,406 @@ use actix_web::{HttpRequest, dev::Extensions};
 //     Ok(val)
 // }
 
-pub fn create_session(extensions: &Extensions, id: String) -> Result<(), SessionInsertError> {
-    Identity::login(extensions, id)?;
+/// Keys stored in the session next to the identity
+const SID_KEY: &str = "sid";
+const USER_KEY: &str = "user";
+/// When the user logged in, in milliseconds
+const AUTH_AT_KEY: &str = "auth_at";
+
+#[derive(Serialize)]
+pub struct SessionInfo {
+    pub id: String,
+    pub created_at: String,
+    pub last_seen: String,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    /// The session used to make the request
+    pub current: bool,
+}
+
+/// Redis set holding the ids of every session opened by a user
+fn index_key(user: &str) -> String {
+    format!("kz-auth:sessions:{}", user)
+}
+
+/// Redis hash holding the metadata of a session
+fn metadata_key(sid: &str) -> String {
+    format!("kz-auth:session:{}", sid)
+}
+
+fn format_timestamp(value: Option<&String>) -> String {
+    value.and_then(|x| x.parse().ok())
+        .and_then(|x| DateTime::<Utc>::from_timestamp(x, 0))
+        .map(|x| x.naive_utc().to_string())
+        .unwrap_or_default()
+}
+
+#[derive(Debug)]
+pub enum IndexError {
+    /// The cookie backend keeps no server side state, its sessions can't be listed or ended remotely
+    Unsupported,
+    /// Redis can't be reached, sessions are refused rather than let through
+    Unavailable(redis::RedisError),
+}
+
+impl fmt::Display for IndexError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            IndexError::Unsupported => write!(f, "Sessions are not indexed with the cookie backend"),
+            IndexError::Unavailable(e) => write!(f, "Session index unavailable: {}", e),
+        }
+    }
+}
+
+impl Error for IndexError {}
+
+impl From<redis::RedisError> for IndexError {
+    fn from(e: redis::RedisError) -> Self {
+        IndexError::Unavailable(e)
+    }
+}
+
+impl IndexError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            IndexError::Unsupported => StatusCode::NOT_IMPLEMENTED,
+            IndexError::Unavailable(_e) => StatusCode::SERVICE_UNAVAILABLE,
+        }
+    }
+}
+
+/// Sessions of every user, with their metadata
+type MemoryIndex = HashMap<String, HashMap<String, HashMap<String, String>>>;
+
+/// Where the sessions of each user are recorded, follows SESSION_BACKEND
+enum SessionIndex {
+    Redis,
+    /// Lost on restart, like the sessions of the memory backend
+    Memory(Mutex<MemoryIndex>),
+    Disabled,
+}
+
+lazy_static! {
+    static ref INDEX: SessionIndex = match SESSION_BACKEND.as_str() {
+        "redis" => SessionIndex::Redis,
+        "memory" => SessionIndex::Memory(Mutex::new(HashMap::new())),
+        _ => SessionIndex::Disabled,
+    };
+}
+
+/// Warn at startup when sessions can't be ended from another device
+pub fn check_index() {
+    if let SessionIndex::Disabled = *INDEX {
+        warn!("[{}] -- The cookie backend can't list or end sessions remotely, they last until they expire", "UserService::session");
+    }
+}
+
+/// Drop the sessions older than SESSION_MAX_LIFETIME, as Redis does with expire
+fn purge_expired(index: &mut MemoryIndex) {
+    let oldest = Utc::now().timestamp() - *SESSION_MAX_LIFETIME as i64;
+    for sessions in index.values_mut() {
+        sessions.retain(|_sid, metadata| {
+            metadata.get("created_at").and_then(|x| x.parse::<i64>().ok()).is_some_and(|x| x > oldest)
+        });
+    }
+    index.retain(|_user, sessions| !sessions.is_empty());
+}
+
+/// Record a session in the user index, entries expire with the session (SESSION_MAX_LIFETIME)
+fn add(user: &str, sid: &str, metadata: &[(&str, &String)]) -> Result<(), IndexError> {
+    match &*INDEX {
+        SessionIndex::Redis => {
+            let mut conn = get_connection()?;
+            conn.sadd::<_, _, ()>(index_key(user), sid)?;
+            conn.expire::<_, ()>(index_key(user), *SESSION_MAX_LIFETIME as usize)?;
+            conn.hset_multiple::<_, _, _, ()>(metadata_key(sid), metadata)?;
+            conn.expire::<_, ()>(metadata_key(sid), *SESSION_MAX_LIFETIME as usize)?;
+        },
+        SessionIndex::Memory(index) => {
+            let mut index = index.lock().unwrap();
+            purge_expired(&mut index);
+            let metadata = metadata.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+            index.entry(user.to_string()).or_default().insert(sid.to_string(), metadata);
+        },
+        SessionIndex::Disabled => {},
+    }
+    Ok(())
+}
+
+/// Move the metadata of a session to a new id
+fn rename(user: &str, old: &str, sid: &str) -> Result<(), IndexError> {
+    match &*INDEX {
+        SessionIndex::Redis => {
+            let mut conn = get_connection()?;
+            conn.srem::<_, _, ()>(index_key(user), old)?;
+            conn.sadd::<_, _, ()>(index_key(user), sid)?;
+            if conn.exists::<_, bool>(metadata_key(old))? {
+                conn.rename::<_, ()>(metadata_key(old), metadata_key(sid))?;
+            }
+        },
+        SessionIndex::Memory(index) => {
+            let mut index = index.lock().unwrap();
+            let sessions = index.entry(user.to_string()).or_default();
+            let metadata = sessions.remove(old).unwrap_or_default();
+            sessions.insert(sid.to_string(), metadata);
+        },
+        SessionIndex::Disabled => {},
+    }
+    Ok(())
+}
+
+/// Ids of the sessions of a user
+fn members(user: &str) -> Result<Vec<String>, IndexError> {
+    match &*INDEX {
+        SessionIndex::Redis => Ok(get_connection()?.smembers(index_key(user))?),
+        SessionIndex::Memory(index) => {
+            let index = index.lock().unwrap();
+            Ok(index.get(user).map(|x| x.keys().cloned().collect()).unwrap_or_default())
+        },
+        SessionIndex::Disabled => Err(IndexError::Unsupported),
+    }
+}
+
+/// Remove a session from the user index, returns false if it wasn't there
+fn remove(user: &str, sid: &str) -> Result<bool, IndexError> {
+    match &*INDEX {
+        SessionIndex::Redis => {
+            let mut conn = get_connection()?;
+            let removed: usize = conn.srem(index_key(user), sid)?;
+            conn.del::<_, ()>(metadata_key(sid))?;
+            Ok(removed == 1)
+        },
+        SessionIndex::Memory(index) => {
+            let mut index = index.lock().unwrap();
+            Ok(index.get_mut(user).and_then(|x| x.remove(sid)).is_some())
+        },
+        SessionIndex::Disabled => Err(IndexError::Unsupported),
+    }
+}
+
+/// Login and record the session in the user index
+pub fn create_session(req: &HttpRequest, id: String) -> Result<(), Box<dyn Error>> {
+    let session = req.get_session();
+    let sid = generate_token();
+    let now = Utc::now().timestamp().to_string();
+    let ip = req.connection_info().realip_remote_addr().unwrap_or_default().to_string();
+    let user_agent = req.headers()
+        .get(header::USER_AGENT)
+        .and_then(|x| x.to_str().ok())
+        .unwrap_or_default()
+        .to_string();
+
+    // A session missing from the index would be purged on its next request
+    add(&id, &sid, &[
+        ("created_at", &now),
+        ("last_seen", &now),
+        ("ip", &ip),
+        ("user_agent", &user_agent),
+    ])?;
+
+    session.insert(SID_KEY, &sid)?;
+    session.insert(USER_KEY, &id)?;
+    session.insert(AUTH_AT_KEY, Utc::now().timestamp_millis())?;
+    Identity::login(&req.extensions(), id)?;
+    Ok(())
+}
+
+/// Remove the current session from the user index
+pub fn end_session(session: &Session) -> Result<(), Box<dyn Error>> {
+    if let (Some(user), Some(sid)) = (session.get::<String>(USER_KEY)?, session.get::<String>(SID_KEY)?) {
+        match remove(&user, &sid) {
+            // Logging out clears the cookie, there is nothing else to end
+            Ok(_) | Err(IndexError::Unsupported) => {},
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(())
+}
+
+/// Give the current session a new id, in the cookie and in the user index.
+/// Called when the credentials change so a stolen id stops working.
+pub fn rotate_session(session: &Session) -> Result<(), Box<dyn Error>> {
+    session.renew();
+
+    let (user, old) = match (session.get::<String>(USER_KEY)?, session.get::<String>(SID_KEY)?) {
+        (Some(user), Some(sid)) => (user, sid),
+        _ => return Ok(()),
+    };
+    let sid = generate_token();
+    rename(&user, &old, &sid)?;
+
+    session.insert(SID_KEY, &sid)?;
+    Ok(())
+}
+
+/// Keep the current session when the credentials of the user are revoked at `now`
+pub fn renew_authentication(session: &Session, now: NaiveDateTime) -> Result<(), Box<dyn Error>> {
+    session.insert(AUTH_AT_KEY, now.and_utc().timestamp_millis())?;
+    Ok(())
+}
+
+/// Drop every session of the user but the current one, returns how many ended
+pub fn end_other_sessions(user: &str, session: &Session) -> Result<usize, IndexError> {
+    let current = current_sid(session);
+    let mut ended = 0;
+    for sid in members(user)?.iter().filter(|x| Some(x.as_str()) != current.as_deref()) {
+        if remove(user, sid)? {
+            ended += 1;
+        }
+    }
+    Ok(ended)
+}
+
+/// Drop every session of the user, they are purged the next time they are used
+pub fn end_all_sessions(user: &str) -> Result<(), IndexError> {
+    match &*INDEX {
+        SessionIndex::Redis => {
+            let mut conn = get_connection()?;
+            let sids: Vec<String> = conn.smembers(index_key(user))?;
+            for sid in sids {
+                conn.del::<_, ()>(metadata_key(&sid))?;
+            }
+            conn.del::<_, ()>(index_key(user))?;
+        },
+        SessionIndex::Memory(index) => {
+            index.lock().unwrap().remove(user);
+        },
+        SessionIndex::Disabled => return Err(IndexError::Unsupported),
+    }
     Ok(())
 }
+
+/// Remove one session of the user from another device, returns false if it doesn't exist
+pub fn revoke_session(user: &str, sid: &str) -> Result<bool, IndexError> {
+    remove(user, sid)
+}
+
+/// Id of the session in the user index
+pub fn current_sid(session: &Session) -> Option<String> {
+    session.get::<String>(SID_KEY).ok().flatten()
+}
+
+/// Metadata of one session
+fn metadata(sid: &str, user: &str) -> Result<HashMap<String, String>, IndexError> {
+    match &*INDEX {
+        SessionIndex::Redis => Ok(get_connection()?.hgetall(metadata_key(sid))?),
+        SessionIndex::Memory(index) => {
+            let index = index.lock().unwrap();
+            Ok(index.get(user).and_then(|x| x.get(sid)).cloned().unwrap_or_default())
+        },
+        SessionIndex::Disabled => Err(IndexError::Unsupported),
+    }
+}
+
+pub fn list_sessions(user: &str, session: &Session) -> Result<Vec<SessionInfo>, IndexError> {
+    let current = current_sid(session);
+    let sids = members(user)?;
+
+    let mut sessions = Vec::with_capacity(sids.len());
+    for sid in sids {
+        let metadata = metadata(&sid, user)?;
+        sessions.push(SessionInfo {
+            created_at: format_timestamp(metadata.get("created_at")),
+            last_seen: format_timestamp(metadata.get("last_seen")),
+            ip: metadata.get("ip").filter(|x| !x.is_empty()).cloned(),
+            user_agent: metadata.get("user_agent").filter(|x| !x.is_empty()).cloned(),
+            current: current.as_ref() == Some(&sid),
+            id: sid,
+        });
+    }
+    sessions.sort_by(|a, b| b.last_seen.cmp(&a.last_seen));
+
+    Ok(sessions)
+}
+
+/// Check the session is still in the user index and record the visit
+fn touch_session(user: &str, sid: &str) -> Result<bool, IndexError> {
+    let now = Utc::now().timestamp();
+    match &*INDEX {
+        SessionIndex::Redis => {
+            let mut conn = get_connection()?;
+            let active: bool = conn.sismember(index_key(user), sid)?;
+            if active {
+                conn.hset::<_, _, _, ()>(metadata_key(sid), "last_seen", now)?;
+            }
+            Ok(active)
+        },
+        SessionIndex::Memory(index) => {
+            let mut index = index.lock().unwrap();
+            match index.get_mut(user).and_then(|x| x.get_mut(sid)) {
+                Some(metadata) => {
+                    metadata.insert(String::from("last_seen"), now.to_string());
+                    Ok(true)
+                },
+                None => Ok(false),
+            }
+        },
+        SessionIndex::Disabled => Ok(true),
+    }
+}
+
+/// Whether the session was opened before the credentials of the user were revoked.
+/// The cookie backend can't end sessions, they are refused this way instead.
+async fn predates_revocation(user: String, auth_at: i64) -> Result<bool, diesel::result::Error> {
+    let user = database::get_user(Mode::Username(user)).await?;
+    Ok(user.credentials_revoked_at.is_some_and(|x| auth_at < x.and_utc().timestamp_millis()))
+}
+
+/// Purge sessions that were ended from another device before the identity is read.
+/// Requests with a session are refused while the index can't be checked.
+pub async fn check_session(req: ServiceRequest, next: Next<impl MessageBody>) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, actix_web::Error> {
+    let session = req.get_session();
+
+    if let (Ok(Some(user)), Ok(Some(sid))) = (session.get::<String>(USER_KEY), session.get::<String>(SID_KEY)) {
+        match touch_session(&user, &sid) {
+            Ok(true) => {},
+            Ok(false) => {
+                info!("[{}] -- Session ended for user {}", "UserService::check_session", user);
+                session.purge();
+            },
+            Err(e) => {
+                error!("[{}] -- {}", "UserService::check_session", e);
+                let res = HttpResponse::build(e.status()).finish();
+                return Ok(req.into_response(res).map_into_right_body());
+            }
+        }
+
+        if let SessionIndex::Disabled = *INDEX {
+            // Sessions from before AUTH_AT_KEY count as opened at 0
+            let auth_at = session.get::<i64>(AUTH_AT_KEY).ok().flatten().unwrap_or(0);
+            match predates_revocation(user.clone(), auth_at).await {
+                Ok(false) => {},
+                Ok(true) => {
+                    info!("[{}] -- Session revoked for user {}", "UserService::check_session", user);
+                    session.purge();
+                },
+                Err(e) => {
+                    error!("[{}] -- {}", "UserService::check_session", e);
+                    let res = HttpResponse::ServiceUnavailable().finish();
+                    return Ok(req.into_response(res).map_into_right_body());
+                }
+            }
+        }
+    }
+
+    next.call(req).await.map(ServiceResponse::map_into_left_body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_purge_expired() {
+        let now = Utc::now().timestamp();
+        let session = |created_at: i64| HashMap::from([(String::from("created_at"), created_at.to_string())]);
+        let mut index = MemoryIndex::new();
+        index.entry(String::from("valentin")).or_default().insert(String::from("old"), session(now - *SESSION_MAX_LIFETIME as i64 - 1));
+        index.entry(String::from("valentin")).or_default().insert(String::from("new"), session(now));
+        index.entry(String::from("gone")).or_default().insert(String::from("old"), session(0));
+
+        purge_expired(&mut index);
+        assert_eq!(index.len(), 1);
+        assert_eq!(index["valentin"].keys().collect::<Vec<_>>(), vec!["new"]);
+    }
+}
@@ -0,0 +1,191 @@
+use std::error::Error;
+use actix_identity::Identity;
+use actix_session::Session;
+use actix_web::{web, HttpRequest, HttpResponse};
+use chrono::Utc;
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{crypto, totp};
+
+use super::{current_user, database, mfa, recovery};
+
+#[derive(Deserialize)]
+pub struct CodeRequest {
+    pub code: String,
+}
+
+#[derive(Serialize)]
+struct Enrollment {
+    /// Base32, for manual entry
+    secret: String,
+    otpauth_uri: String,
+}
+
+/// Secrets are bound to their owner so they can't be swapped between rows
+fn context(user_id: i32) -> String {
+    format!("totp:{}", user_id)
+}
+
+/// Check a code against the stored secret, each time step is only accepted once.
+/// With `confirmed_only` a secret that was never confirmed is ignored.
+fn check_code(user_id: i32, code: &str, confirmed_only: bool) -> Result<bool, Box<dyn Error + Send + Sync>> {
+    let row = match database::find_totp(user_id)? {
+        Some(x) if x.confirmed_at.is_some() || !confirmed_only => x,
+        _ => return Ok(false),
+    };
+    let secret = crypto::decrypt(&row.secret, &context(user_id))
+        .ok_or("TOTP secret can't be decrypted, check MFA_ENCRYPTION_KEY")?;
+
+    match totp::verify(&secret, code, Utc::now().timestamp())? {
+        Some(step) => Ok(database::use_totp_step(user_id, step)?),
+        None => Ok(false),
+    }
+}
+
+/// Start an enrollment, the secret is only active once confirmed with a first code
+pub async fn enroll(user: Option<Identity>) -> HttpResponse {
+    let user = match current_user(&user).await {
+        Some(x) => x,
+        None => {
+            error!("[{}] -- Unauthorized", "UserService::totp_enroll");
+            return HttpResponse::Unauthorized().finish();
+        }
+    };
+
+    match database::find_totp(user.id) {
+        Ok(Some(x)) if x.confirmed_at.is_some() => {
+            warn!("[{}] -- TOTP already enabled for user {}", "UserService::totp_enroll", user.name);
+            return HttpResponse::Conflict().body("TOTP already enabled");
+        },
+        Ok(_) => {},
+        Err(e) => {
+            error!("[{}] -- {}", "UserService::totp_enroll", e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    }
+
+    let secret = totp::generate_secret();
+    let sealed = match crypto::encrypt(&secret, &context(user.id)) {
+        Some(Ok(x)) => x,
+        Some(Err(e)) => {
+            error!("[{}] -- {}", "UserService::totp_enroll", e);
+            return HttpResponse::InternalServerError().finish();
+        },
+        None => {
+            error!("[{}] -- No MFA_ENCRYPTION_KEY, can't store the secret", "UserService::totp_enroll");
+            return HttpResponse::ServiceUnavailable().finish();
+        }
+    };
+
+    if let Err(e) = database::create_totp(user.id, &sealed) {
+        error!("[{}] -- {}", "UserService::totp_enroll", e);
+        return HttpResponse::InternalServerError().finish();
+    }
+
+    info!("[{}] -- TOTP enrollment started for user {}", "UserService::totp_enroll", user.name);
+    let secret = totp::base32_encode(&secret);
+    HttpResponse::Ok().json(Enrollment {
+        otpauth_uri: totp::otpauth_uri(&user.name, &secret),
+        secret,
+    })
+}
+
+/// Activate TOTP, recovery codes are returned if the user has none left
+pub async fn confirm(user: Option<Identity>, body: web::Json<CodeRequest>) -> HttpResponse {
+    let user = match current_user(&user).await {
+        Some(x) => x,
+        None => {
+            error!("[{}] -- Unauthorized", "UserService::totp_confirm");
+            return HttpResponse::Unauthorized().finish();
+        }
+    };
+
+    match database::find_totp(user.id) {
+        Ok(Some(x)) if x.confirmed_at.is_some() => return HttpResponse::Conflict().body("TOTP already enabled"),
+        Ok(Some(_)) => {},
+        Ok(None) => return HttpResponse::NotFound().finish(),
+        Err(e) => {
+            error!("[{}] -- {}", "UserService::totp_confirm", e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    }
+
+    let confirmed = check_code(user.id, &body.code, false)
+        .and_then(|valid| {
+            if !valid {
+                return Ok(None);
+            }
+            database::confirm_totp(user.id, Utc::now().naive_utc())?;
+            Ok(Some(recovery::issue_if_missing(user.id)?))
+        });
+    match confirmed {
+        Ok(Some(recovery_codes)) => {
+            info!("[{}] -- TOTP enabled for user {}", "UserService::totp_confirm", user.name);
+            match recovery_codes {
+                Some(x) => HttpResponse::Ok().json(x),
+                None => HttpResponse::NoContent().finish(),
+            }
+        },
+        Ok(None) => HttpResponse::BadRequest().body("Invalid code"),
+        Err(e) => {
+            error!("[{}] -- {}", "UserService::totp_confirm", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+/// Remove the second factor, a current code is required
+pub async fn disable(user: Option<Identity>, body: web::Json<CodeRequest>) -> HttpResponse {
+    let user = match current_user(&user).await {
+        Some(x) => x,
+        None => {
+            error!("[{}] -- Unauthorized", "UserService::totp_disable");
+            return HttpResponse::Unauthorized().finish();
+        }
+    };
+
+    let disabled = check_code(user.id, &body.code, false)
+        .and_then(|valid| {
+            if valid {
+                database::delete_totp(user.id)?;
+                recovery::clear_if_unused(user.id)?;
+            }
+            Ok(valid)
+        });
+    match disabled {
+        Ok(true) => {
+            info!("[{}] -- TOTP disabled for user {}", "UserService::totp_disable", user.name);
+            HttpResponse::NoContent().finish()
+        },
+        Ok(false) => HttpResponse::BadRequest().body("Invalid code"),
+        Err(e) => {
+            error!("[{}] -- {}", "UserService::totp_disable", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+/// Second step of `users::auth` for users with TOTP enabled
+pub async fn login(req: HttpRequest, sess: Session, body: web::Json<CodeRequest>) -> HttpResponse {
+    let pending = match mfa::attempt(&sess) {
+        Some(x) => x,
+        None => {
+            error!("[{}] -- No pending login", "UserService::totp_login");
+            return HttpResponse::Unauthorized().finish();
+        }
+    };
+
+    // A secret still being enrolled is not a second factor yet
+    match check_code(pending.user_id, &body.code, true) {
+        Ok(true) => mfa::complete(&req, &sess, pending).await,
+        Ok(false) => {
+            warn!("[{}] -- Invalid code", "UserService::totp_login");
+            HttpResponse::Unauthorized().finish()
+        },
+        Err(e) => {
+            error!("[{}] -- {}", "UserService::totp_login", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
@@ -0,0 +1,110 @@
+use actix_web::{rt, web, HttpResponse};
+use chrono::Utc;
+use log::{error, info, warn};
+use serde::Deserialize;
+
+use crate::jwt;
+use crate::local_env::*;
+use crate::mailer::{self, Email};
+use crate::models::User;
+
+use super::{database, Mode};
+
+/// Audience suffix of the verification tokens
+const PURPOSE: &str = "verify-email";
+
+#[derive(Deserialize)]
+pub struct VerifyRequest {
+    pub token: String,
+}
+
+#[derive(Deserialize)]
+pub struct ResendRequest {
+    /// Username or email
+    pub login: String,
+}
+
+/// Email a verification link, the token is only valid for the current address
+pub async fn send(user: &User) -> Result<(), anyhow::Error> {
+    let (token, _claims) = jwt::issue_email_token(&user.id.to_string(), &user.email, PURPOSE, *EMAIL_VERIFICATION_TTL)?;
+    let link = format!("{}/users/verify-email?token={}", PUBLIC_URL.trim_end_matches('/'), token);
+
+    mailer::send(Email {
+        to: user.email.clone(),
+        subject: String::from("Verify your email address"),
+        body: format!(
+            "Hello {},\n\nPlease confirm your email address by following this link:\n\n{}\n\nThe link expires in {} hours. If you didn't create an account, you can ignore this email.\n",
+            user.name, link, *EMAIL_VERIFICATION_TTL / 3600,
+        ),
+    }).await
+}
+
+async fn verify_token(token: &str) -> HttpResponse {
+    let claims = match jwt::decode_email_token(token, PURPOSE) {
+        Ok(x) => x,
+        Err(e) => {
+            warn!("[{}] -- Invalid token: {}", "UserService::verify_email", e);
+            return HttpResponse::BadRequest().body("Invalid or expired token");
+        }
+    };
+    let user_id: i32 = match claims.sub.parse() {
+        Ok(x) => x,
+        Err(_e) => return HttpResponse::BadRequest().body("Invalid or expired token"),
+    };
+
+    match database::verify_email(user_id, &claims.email, Utc::now().naive_utc()) {
+        Ok(true) => {
+            info!("[{}] -- Email verified for user {}", "UserService::verify_email", user_id);
+            return HttpResponse::Ok().finish();
+        },
+        Ok(false) => {},
+        Err(e) => {
+            error!("[{}] -- {}", "UserService::verify_email", e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    }
+
+    // Following the link twice is fine, a token for an old address isn't
+    match database::get_user(Mode::Id(user_id)).await {
+        Ok(user) if user.email == claims.email && user.email_verified_at.is_some() => HttpResponse::Ok().finish(),
+        Ok(_) => {
+            warn!("[{}] -- Address changed since the token was issued", "UserService::verify_email");
+            HttpResponse::BadRequest().body("Invalid or expired token")
+        },
+        Err(e) => {
+            warn!("[{}] -- {}", "UserService::verify_email", e);
+            HttpResponse::BadRequest().body("Invalid or expired token")
+        }
+    }
+}
+
+/// Link from the verification email
+pub async fn verify_link(query: web::Query<VerifyRequest>) -> HttpResponse {
+    verify_token(&query.token).await
+}
+
+/// Same as the link, for clients posting the token themselves
+pub async fn verify(body: web::Json<VerifyRequest>) -> HttpResponse {
+    verify_token(&body.token).await
+}
+
+/// Send a new link, the response doesn't tell whether the account exists
+pub async fn resend(body: web::Json<ResendRequest>) -> HttpResponse {
+    let login = body.into_inner().login;
+
+    // Sent in the background so the response time doesn't tell which accounts are unverified
+    rt::spawn(async move {
+        match database::get_user(Mode::Login(login)).await {
+            Ok(user) if user.email_verified_at.is_none() => {
+                match send(&user).await {
+                    Ok(()) => info!("[{}] -- Verification email sent to user {}", "UserService::resend_verification", user.name),
+                    Err(e) => error!("[{}] -- Verification email not sent: {}", "UserService::resend_verification", e),
+                }
+            },
+            Ok(_) => info!("[{}] -- Address already verified", "UserService::resend_verification"),
+            Err(_e) => info!("[{}] -- Unknown login", "UserService::resend_verification"),
+        }
+    });
+
+    HttpResponse::Accepted().finish()
+}
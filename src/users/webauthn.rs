@@ -0,0 +1,365 @@
+use std::error::Error;
+use actix_identity::Identity;
+use actix_session::Session;
+use actix_web::{web, HttpRequest, HttpResponse};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::Utc;
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::crypto;
+use crate::local_env::*;
+use crate::models::WebauthnCredential;
+use crate::webauthn::{self, ES256, RS256};
+
+use super::{current_user, database, login, mfa, recovery, Mode};
+
+/// Keys of the ceremony challenges in the session
+const REGISTRATION_KEY: &str = "webauthn_registration";
+const LOGIN_KEY: &str = "webauthn_login";
+/// Time allowed to complete a ceremony, in seconds
+const CEREMONY_TTL: i64 = 300;
+
+#[derive(Serialize, Deserialize)]
+struct Ceremony {
+    challenge: String,
+    expires_at: i64,
+}
+
+/// `PublicKeyCredential.toJSON()` of a registration
+#[derive(Deserialize)]
+pub struct RegistrationRequest {
+    pub response: AttestationResponse,
+    /// Label chosen by the user, "Laptop", "YubiKey"...
+    pub name: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct AttestationResponse {
+    #[serde(rename = "clientDataJSON")]
+    pub client_data_json: String,
+    #[serde(rename = "attestationObject")]
+    pub attestation_object: String,
+}
+
+/// `PublicKeyCredential.toJSON()` of an authentication
+#[derive(Deserialize)]
+pub struct AssertionRequest {
+    pub id: String,
+    pub response: AssertionResponse,
+    /// Same as `AuthRequest::token`, for passwordless logins
+    #[serde(default)]
+    pub token: bool,
+}
+
+#[derive(Deserialize)]
+pub struct AssertionResponse {
+    #[serde(rename = "clientDataJSON")]
+    pub client_data_json: String,
+    #[serde(rename = "authenticatorData")]
+    pub authenticator_data: String,
+    pub signature: String,
+}
+
+#[derive(Serialize)]
+struct ResCredential {
+    id: i32,
+    name: Option<String>,
+    created_at: String,
+    last_used_at: Option<String>,
+}
+
+/// Start a ceremony, the challenge can only be answered once
+fn start_ceremony(sess: &Session, key: &str) -> Result<String, Box<dyn Error>> {
+    let challenge = webauthn::generate_challenge();
+    sess.insert(key, Ceremony {
+        challenge: challenge.clone(),
+        expires_at: Utc::now().timestamp() + CEREMONY_TTL,
+    })?;
+    Ok(challenge)
+}
+
+fn take_challenge(sess: &Session, key: &str) -> Option<String> {
+    let ceremony = sess.remove_as::<Ceremony>(key)?.ok()?;
+    (ceremony.expires_at > Utc::now().timestamp()).then_some(ceremony.challenge)
+}
+
+fn descriptors(credentials: &[WebauthnCredential]) -> Vec<serde_json::Value> {
+    credentials.iter()
+        .map(|x| json!({ "type": "public-key", "id": x.credential_id }))
+        .collect()
+}
+
+/// Options for `navigator.credentials.create()`
+pub async fn register_options(user: Option<Identity>, sess: Session) -> HttpResponse {
+    let user = match current_user(&user).await {
+        Some(x) => x,
+        None => {
+            error!("[{}] -- Unauthorized", "UserService::webauthn_register");
+            return HttpResponse::Unauthorized().finish();
+        }
+    };
+    // Passkeys are a second factor, their recovery codes are keyed with MFA_ENCRYPTION_KEY
+    if !crypto::is_enabled() {
+        error!("[{}] -- No MFA_ENCRYPTION_KEY, can't issue recovery codes", "UserService::webauthn_register");
+        return HttpResponse::ServiceUnavailable().finish();
+    }
+
+    let credentials = match database::find_webauthn_credentials(user.id) {
+        Ok(x) => x,
+        Err(e) => {
+            error!("[{}] -- {}", "UserService::webauthn_register", e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+
+    match start_ceremony(&sess, REGISTRATION_KEY) {
+        Ok(challenge) => HttpResponse::Ok().json(json!({
+            "challenge": challenge,
+            "rp": { "id": *WEBAUTHN_RP_ID, "name": *WEBAUTHN_RP_NAME },
+            "user": {
+                "id": webauthn::encode(&user.id.to_be_bytes()),
+                "name": user.name,
+                "displayName": user.name,
+            },
+            "pubKeyCredParams": [
+                { "type": "public-key", "alg": ES256 },
+                { "type": "public-key", "alg": RS256 },
+            ],
+            "timeout": CEREMONY_TTL * 1000,
+            "attestation": "none",
+            "excludeCredentials": descriptors(&credentials),
+            "authenticatorSelection": { "residentKey": "preferred", "userVerification": "preferred" },
+        })),
+        Err(e) => {
+            error!("[{}] -- {}", "UserService::webauthn_register", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+pub async fn register(user: Option<Identity>, sess: Session, body: web::Json<RegistrationRequest>) -> HttpResponse {
+    let user = match current_user(&user).await {
+        Some(x) => x,
+        None => {
+            error!("[{}] -- Unauthorized", "UserService::webauthn_register");
+            return HttpResponse::Unauthorized().finish();
+        }
+    };
+    let challenge = match take_challenge(&sess, REGISTRATION_KEY) {
+        Some(x) => x,
+        None => return HttpResponse::BadRequest().body("No registration in progress"),
+    };
+
+    let credential = webauthn::decode(&body.response.client_data_json)
+        .and_then(|client_data| webauthn::check_client_data(&client_data, "webauthn.create", &challenge))
+        .and_then(|_| webauthn::decode(&body.response.attestation_object))
+        .and_then(|attestation| webauthn::parse_attestation_object(&attestation));
+    let auth_data = match credential {
+        Ok(x) => x,
+        Err(e) => {
+            warn!("[{}] -- Registration rejected: {}", "UserService::webauthn_register", e);
+            return HttpResponse::BadRequest().body(e);
+        }
+    };
+    let credential = auth_data.credential.unwrap();
+
+    let created = database::create_webauthn_credential(
+        user.id,
+        &webauthn::encode(&credential.id),
+        &STANDARD.encode(&credential.public_key),
+        credential.algorithm as i32,
+        auth_data.sign_count as i64,
+        body.name.as_deref(),
+    );
+    match created {
+        Ok(()) => {},
+        Err(diesel::result::Error::DatabaseError(_kind, info)) if info.constraint_name() == Some("webauthn_credentials_credential_id_unique") => {
+            return HttpResponse::Conflict().body("Credential already registered");
+        },
+        Err(e) => {
+            error!("[{}] -- {}", "UserService::webauthn_register", e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    }
+    info!("[{}] -- Credential registered for user {}", "UserService::webauthn_register", user.name);
+
+    match recovery::issue_if_missing(user.id) {
+        Ok(Some(recovery_codes)) => HttpResponse::Created().json(recovery_codes),
+        Ok(None) => HttpResponse::Created().finish(),
+        Err(e) => {
+            error!("[{}] -- {}", "UserService::webauthn_register", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+pub async fn list_credentials(user: Option<Identity>) -> HttpResponse {
+    let user = match current_user(&user).await {
+        Some(x) => x,
+        None => {
+            error!("[{}] -- Unauthorized", "UserService::webauthn_list");
+            return HttpResponse::Unauthorized().finish();
+        }
+    };
+
+    match database::find_webauthn_credentials(user.id) {
+        Ok(credentials) => {
+            let credentials: Vec<ResCredential> = credentials.into_iter()
+                .map(|x| ResCredential {
+                    id: x.id,
+                    name: x.name,
+                    created_at: x.created_at.to_string(),
+                    last_used_at: x.last_used_at.map(|x| x.to_string()),
+                })
+                .collect();
+            HttpResponse::Ok().json(credentials)
+        },
+        Err(e) => {
+            error!("[{}] -- {}", "UserService::webauthn_list", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+pub async fn delete_credential(user: Option<Identity>, id: web::Path<i32>) -> HttpResponse {
+    let user = match current_user(&user).await {
+        Some(x) => x,
+        None => {
+            error!("[{}] -- Unauthorized", "UserService::webauthn_delete");
+            return HttpResponse::Unauthorized().finish();
+        }
+    };
+
+    let deleted = database::delete_webauthn_credential(user.id, *id)
+        .and_then(|rows| {
+            recovery::clear_if_unused(user.id)?;
+            Ok(rows)
+        });
+    match deleted {
+        Ok(1) => {
+            info!("[{}] -- Credential removed for user {}", "UserService::webauthn_delete", user.name);
+            HttpResponse::NoContent().finish()
+        },
+        Ok(_) => HttpResponse::NotFound().finish(),
+        Err(e) => {
+            error!("[{}] -- {}", "UserService::webauthn_delete", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+/// Options for `navigator.credentials.get()`, restricted to the user's credentials when
+/// a password login waits for its second factor, otherwise for a passwordless login
+pub async fn login_options(sess: Session) -> HttpResponse {
+    let credentials = match mfa::pending(&sess).map(|x| database::find_webauthn_credentials(x.user_id)) {
+        Some(Ok(x)) => Some(x),
+        Some(Err(e)) => {
+            error!("[{}] -- {}", "UserService::webauthn_login", e);
+            return HttpResponse::InternalServerError().finish();
+        },
+        None => None,
+    };
+
+    match start_ceremony(&sess, LOGIN_KEY) {
+        Ok(challenge) => HttpResponse::Ok().json(json!({
+            "challenge": challenge,
+            "rpId": *WEBAUTHN_RP_ID,
+            "timeout": CEREMONY_TTL * 1000,
+            "userVerification": if credentials.is_some() { "preferred" } else { "required" },
+            "allowCredentials": credentials.as_deref().map(descriptors).unwrap_or_default(),
+        })),
+        Err(e) => {
+            error!("[{}] -- {}", "UserService::webauthn_login", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+/// Check an assertion and record the new signature counter, None when it is rejected
+fn verify_assertion(body: &AssertionRequest, challenge: &str, user_verification: bool) -> Result<Option<WebauthnCredential>, Box<dyn Error>> {
+    let credential = match database::find_webauthn_credential(&body.id)? {
+        Some(x) => x,
+        None => {
+            warn!("[{}] -- Unknown credential", "UserService::webauthn_login");
+            return Ok(None);
+        }
+    };
+
+    let checked = webauthn::decode(&body.response.client_data_json)
+        .and_then(|client_data| {
+            webauthn::check_client_data(&client_data, "webauthn.get", challenge)?;
+            let authenticator_data = webauthn::decode(&body.response.authenticator_data)?;
+            let parsed = webauthn::parse_authenticator_data(&authenticator_data)?;
+            if user_verification && !parsed.user_verified() {
+                return Err("User not verified");
+            }
+            let signature = webauthn::decode(&body.response.signature)?;
+            Ok((client_data, authenticator_data, parsed, signature))
+        });
+    let (client_data, authenticator_data, parsed, signature) = match checked {
+        Ok(x) => x,
+        Err(e) => {
+            warn!("[{}] -- Assertion rejected: {}", "UserService::webauthn_login", e);
+            return Ok(None);
+        }
+    };
+
+    let public_key = STANDARD.decode(&credential.public_key)?;
+    if !webauthn::verify_signature(&public_key, &authenticator_data, &client_data, &signature)? {
+        warn!("[{}] -- Invalid signature", "UserService::webauthn_login");
+        return Ok(None);
+    }
+
+    // Authenticators without a counter always send 0, a counter going backwards means a cloned key
+    let sign_count = parsed.sign_count as i64;
+    if (sign_count != 0 || credential.sign_count != 0) && sign_count <= credential.sign_count {
+        warn!("[{}] -- Signature counter went backwards, possible cloned credential {}", "UserService::webauthn_login", credential.id);
+        return Ok(None);
+    }
+
+    database::use_webauthn_credential(credential.id, sign_count, Utc::now().naive_utc())?;
+    Ok(Some(credential))
+}
+
+/// Second step of `users::auth`, or a passwordless login on its own
+pub async fn login_finish(req: HttpRequest, sess: Session, body: web::Json<AssertionRequest>) -> HttpResponse {
+    let challenge = match take_challenge(&sess, LOGIN_KEY) {
+        Some(x) => x,
+        None => {
+            error!("[{}] -- No login in progress", "UserService::webauthn_login");
+            return HttpResponse::Unauthorized().finish();
+        }
+    };
+    let pending = mfa::attempt(&sess);
+
+    // A passkey replacing the password must prove the user was verified (PIN, biometrics)
+    let credential = match verify_assertion(&body, &challenge, pending.is_none()) {
+        Ok(x) => x,
+        Err(e) => {
+            error!("[{}] -- {}", "UserService::webauthn_login", e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+
+    match (credential, pending) {
+        (Some(credential), Some(pending)) if credential.user_id == pending.user_id => {
+            mfa::complete(&req, &sess, pending).await
+        },
+        (Some(credential), None) => {
+            match database::get_user(Mode::Id(credential.user_id)).await {
+                Ok(user) => {
+                    info!("[{}] -- Passwordless login for user {}", "UserService::webauthn_login", user.name);
+                    login(&req, user, body.token)
+                },
+                Err(e) => {
+                    error!("[{}] -- {}", "UserService::webauthn_login", e);
+                    HttpResponse::InternalServerError().finish()
+                }
+            }
+        },
+        (_credential, Some(_pending)) => HttpResponse::Unauthorized().finish(),
+        (None, None) => HttpResponse::Unauthorized().finish(),
+    }
+}
@@ -0,0 +1,439 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use openssl::{
+    bn::BigNum,
+    ec::{EcGroup, EcKey},
+    error::ErrorStack,
+    hash::MessageDigest,
+    nid::Nid,
+    pkey::PKey,
+    rand::rand_bytes,
+    rsa::Rsa,
+    sha::sha256,
+    sign::Verifier
+};
+use serde::Deserialize;
+
+use crate::local_env::*;
+
+/// COSE algorithms offered to authenticators, in order of preference
+pub const ES256: i64 = -7;
+pub const RS256: i64 = -257;
+
+/// Authenticator data flags
+const USER_PRESENT: u8 = 0x01;
+const USER_VERIFIED: u8 = 0x04;
+const ATTESTED_CREDENTIAL: u8 = 0x40;
+
+/// Nesting accepted in CBOR documents, attestation objects only need a few levels
+const MAX_DEPTH: usize = 8;
+
+pub type WebauthnResult<T> = Result<T, &'static str>;
+
+/// Subset of CBOR (RFC 8949) used by attestation objects and COSE keys
+#[derive(Debug, PartialEq)]
+enum Cbor {
+    Int(i64),
+    Bytes(Vec<u8>),
+    Text(String),
+    Array(Vec<Cbor>),
+    Map(Vec<(Cbor, Cbor)>),
+    Bool(bool),
+    Null,
+}
+
+impl Cbor {
+    fn get(&self, key: &Cbor) -> Option<&Cbor> {
+        match self {
+            Cbor::Map(entries) => entries.iter().find(|(k, _v)| k == key).map(|(_k, v)| v),
+            _ => None,
+        }
+    }
+
+    fn get_int(&self, key: i64) -> Option<&Cbor> {
+        self.get(&Cbor::Int(key))
+    }
+
+    fn get_text(&self, key: &str) -> Option<&Cbor> {
+        self.get(&Cbor::Text(key.to_string()))
+    }
+
+    fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            Cbor::Bytes(x) => Some(x),
+            _ => None,
+        }
+    }
+
+    fn as_int(&self) -> Option<i64> {
+        match self {
+            Cbor::Int(x) => Some(*x),
+            _ => None,
+        }
+    }
+}
+
+/// Decode one CBOR item, returns it with the number of bytes read
+fn decode_cbor(data: &[u8], depth: usize) -> WebauthnResult<(Cbor, usize)> {
+    if depth > MAX_DEPTH {
+        return Err("CBOR nested too deeply");
+    }
+    let initial = *data.first().ok_or("Truncated CBOR")?;
+    let (major, info) = (initial >> 5, initial & 0x1f);
+
+    let (argument, mut read) = match info {
+        0..=23 => (info as u64, 1),
+        24..=27 => {
+            let len = 1 << (info - 24);
+            let bytes = data.get(1..1 + len).ok_or("Truncated CBOR")?;
+            (bytes.iter().fold(0u64, |acc, x| (acc << 8) | *x as u64), 1 + len)
+        },
+        _ => return Err("Unsupported CBOR encoding"),
+    };
+
+    let item = match major {
+        0 => Cbor::Int(i64::try_from(argument).map_err(|_e| "CBOR integer overflow")?),
+        1 => Cbor::Int(-1 - i64::try_from(argument).map_err(|_e| "CBOR integer overflow")?),
+        2 | 3 => {
+            let len = usize::try_from(argument).map_err(|_e| "CBOR length overflow")?;
+            let bytes = data.get(read..read.checked_add(len).ok_or("CBOR length overflow")?).ok_or("Truncated CBOR")?;
+            read += len;
+            if major == 2 {
+                Cbor::Bytes(bytes.to_vec())
+            } else {
+                Cbor::Text(String::from_utf8(bytes.to_vec()).map_err(|_e| "Invalid CBOR text")?)
+            }
+        },
+        4 | 5 => {
+            // Every item is at least one byte, bounds the allocation on hostile lengths
+            let len = usize::try_from(argument).map_err(|_e| "CBOR length overflow")?;
+            if len > data.len() {
+                return Err("Truncated CBOR");
+            }
+            let mut items = Vec::with_capacity(len * (major as usize - 3));
+            for _ in 0..len * (major as usize - 3) {
+                let (item, size) = decode_cbor(&data[read..], depth + 1)?;
+                items.push(item);
+                read += size;
+            }
+            if major == 4 {
+                Cbor::Array(items)
+            } else {
+                let mut entries = Vec::with_capacity(len);
+                let mut items = items.into_iter();
+                while let (Some(k), Some(v)) = (items.next(), items.next()) {
+                    entries.push((k, v));
+                }
+                Cbor::Map(entries)
+            }
+        },
+        7 => match info {
+            20 => Cbor::Bool(false),
+            21 => Cbor::Bool(true),
+            22 => Cbor::Null,
+            _ => return Err("Unsupported CBOR simple value"),
+        },
+        _ => return Err("Unsupported CBOR type"),
+    };
+
+    Ok((item, read))
+}
+
+#[derive(Deserialize)]
+struct ClientData {
+    #[serde(rename = "type")]
+    kind: String,
+    challenge: String,
+    origin: String,
+}
+
+pub struct AttestedCredential {
+    pub id: Vec<u8>,
+    /// DER encoded SubjectPublicKeyInfo
+    pub public_key: Vec<u8>,
+    pub algorithm: i64,
+}
+
+pub struct AuthenticatorData {
+    pub flags: u8,
+    pub sign_count: u32,
+    /// Only present during registration
+    pub credential: Option<AttestedCredential>,
+}
+
+impl AuthenticatorData {
+    pub fn user_verified(&self) -> bool {
+        self.flags & USER_VERIFIED != 0
+    }
+}
+
+/// Random challenge, base64url encoded as in clientDataJSON
+pub fn generate_challenge() -> String {
+    let mut buf = [0u8; 32];
+    rand_bytes(&mut buf).unwrap();
+    URL_SAFE_NO_PAD.encode(buf)
+}
+
+pub fn encode(data: &[u8]) -> String {
+    URL_SAFE_NO_PAD.encode(data)
+}
+
+pub fn decode(data: &str) -> WebauthnResult<Vec<u8>> {
+    URL_SAFE_NO_PAD.decode(data.trim_end_matches('=')).map_err(|_e| "Invalid base64url")
+}
+
+/// Check the ceremony type, the challenge we issued and the origin the browser saw
+pub fn check_client_data(client_data_json: &[u8], kind: &str, challenge: &str) -> WebauthnResult<()> {
+    let client_data: ClientData = serde_json::from_slice(client_data_json).map_err(|_e| "Invalid clientDataJSON")?;
+
+    if client_data.kind != kind {
+        return Err("Unexpected ceremony type");
+    }
+    if client_data.challenge != challenge {
+        return Err("Challenge mismatch");
+    }
+    if client_data.origin != *WEBAUTHN_ORIGIN {
+        return Err("Origin mismatch");
+    }
+    Ok(())
+}
+
+/// COSE_Key (RFC 8152) to a DER public key, for ES256 and RS256
+fn cose_to_der(key: &Cbor) -> WebauthnResult<(Vec<u8>, i64)> {
+    let algorithm = key.get_int(3).and_then(Cbor::as_int).ok_or("Missing COSE algorithm")?;
+    let param = |label: i64| key.get_int(label).and_then(Cbor::as_bytes).ok_or("Missing COSE parameter");
+
+    let der = match (key.get_int(1).and_then(Cbor::as_int), algorithm) {
+        // EC2 on P-256
+        (Some(2), ES256) => {
+            if key.get_int(-1).and_then(Cbor::as_int) != Some(1) {
+                return Err("Unsupported curve");
+            }
+            ec_der(param(-2)?, param(-3)?).map_err(|_e| "Invalid EC key")?
+        },
+        (Some(3), RS256) => {
+            rsa_der(param(-1)?, param(-2)?).map_err(|_e| "Invalid RSA key")?
+        },
+        _ => return Err("Unsupported COSE key"),
+    };
+    Ok((der, algorithm))
+}
+
+fn ec_der(x: &[u8], y: &[u8]) -> Result<Vec<u8>, ErrorStack> {
+    let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?;
+    let (x, y) = (BigNum::from_slice(x)?, BigNum::from_slice(y)?);
+    let key = EcKey::from_public_key_affine_coordinates(&group, &x, &y)?;
+    key.check_key()?;
+    PKey::from_ec_key(key)?.public_key_to_der()
+}
+
+fn rsa_der(n: &[u8], e: &[u8]) -> Result<Vec<u8>, ErrorStack> {
+    let key = Rsa::from_public_components(BigNum::from_slice(n)?, BigNum::from_slice(e)?)?;
+    PKey::from_rsa(key)?.public_key_to_der()
+}
+
+/// Parse authenticator data and check it was produced for our relying party by a present user
+pub fn parse_authenticator_data(data: &[u8]) -> WebauthnResult<AuthenticatorData> {
+    if data.len() < 37 {
+        return Err("Truncated authenticator data");
+    }
+    if data[..32] != sha256(WEBAUTHN_RP_ID.as_bytes()) {
+        return Err("Relying party mismatch");
+    }
+    let flags = data[32];
+    if flags & USER_PRESENT == 0 {
+        return Err("User not present");
+    }
+    let sign_count = u32::from_be_bytes([data[33], data[34], data[35], data[36]]);
+
+    let credential = if flags & ATTESTED_CREDENTIAL != 0 {
+        // aaguid (16 bytes), credential id length (2 bytes), credential id, COSE key
+        let len = data.get(53..55).ok_or("Truncated attested credential")?;
+        let len = u16::from_be_bytes([len[0], len[1]]) as usize;
+        let id = data.get(55..55 + len).ok_or("Truncated attested credential")?.to_vec();
+        let (key, _size) = decode_cbor(&data[55 + len..], 0)?;
+        let (public_key, algorithm) = cose_to_der(&key)?;
+        Some(AttestedCredential { id, public_key, algorithm })
+    } else {
+        None
+    };
+
+    Ok(AuthenticatorData { flags, sign_count, credential })
+}
+
+/// Authenticator data of a registration. We request "none" attestation: the statement
+/// is not verified, the credential is trusted because the user is logged in.
+pub fn parse_attestation_object(attestation_object: &[u8]) -> WebauthnResult<AuthenticatorData> {
+    let (attestation, _size) = decode_cbor(attestation_object, 0)?;
+    let auth_data = attestation.get_text("authData").and_then(Cbor::as_bytes).ok_or("Missing authData")?;
+
+    let auth_data = parse_authenticator_data(auth_data)?;
+    if auth_data.credential.is_none() {
+        return Err("Missing attested credential");
+    }
+    Ok(auth_data)
+}
+
+/// Check an assertion signature, made over the authenticator data and the client data hash
+pub fn verify_signature(public_key: &[u8], authenticator_data: &[u8], client_data_json: &[u8], signature: &[u8]) -> Result<bool, ErrorStack> {
+    let key = PKey::public_key_from_der(public_key)?;
+    let mut verifier = Verifier::new(MessageDigest::sha256(), &key)?;
+    verifier.update(authenticator_data)?;
+    verifier.update(&sha256(client_data_json))?;
+    verifier.verify(signature)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::{bn::BigNumContext, pkey::Private, sign::Signer};
+
+    /// Uncompressed point coordinates, to build COSE keys
+    fn ec_coordinates(key: &EcKey<Private>) -> (Vec<u8>, Vec<u8>) {
+        let mut ctx = BigNumContext::new().unwrap();
+        let mut x = BigNum::new().unwrap();
+        let mut y = BigNum::new().unwrap();
+        key.public_key().affine_coordinates(key.group(), &mut x, &mut y, &mut ctx).unwrap();
+        (x.to_vec_padded(32).unwrap(), y.to_vec_padded(32).unwrap())
+    }
+
+    fn cose_es256(x: &[u8], y: &[u8]) -> Vec<u8> {
+        // {1: 2, 3: -7, -1: 1, -2: x, -3: y}
+        [&[0xa5, 0x01, 0x02, 0x03, 0x26, 0x20, 0x01, 0x21, 0x58, 0x20][..], x, &[0x22, 0x58, 0x20], y].concat()
+    }
+
+    // Registration then assertion of a P-256 credential for "localhost". Synthetic, signed
+    // with a fixed test key rather than captured from a browser, but laid out like browser
+    // output: "none" attestation with a zeroed AAGUID, clientDataJSON with crossOrigin
+    const ATTESTATION_OBJECT: &str = "o2NmbXRkbm9uZWdhdHRTdG10oGhhdXRoRGF0YViUSZYN5YgOjGh0NBcPZHZgW4_krrmihjLHmVzzuoMdl2NFAAAAAAAAAAAAAAAAAAAAAAAAAAAAEKChoqOkpaanqKmqq6ytrq-lAQIDJiABIVggvXxzuIsum0ztpiAistqL4TGTpbVu3Cbn33hC4kzQtesiWCAGBa2nvag6xqK4DX4xQED6R_8WuDushc7bAURRu3znGg";
+    const REGISTRATION_CLIENT_DATA: &str = r#"{"type":"webauthn.create","challenge":"Ck9zbXlEcmFnb25GbHlzQXRNaWRuaWdodA","origin":"https://localhost","crossOrigin":false}"#;
+    const CREDENTIAL_ID: &str = "oKGio6SlpqeoqaqrrK2urw";
+    const ASSERTION_AUTH_DATA: &str = "SZYN5YgOjGh0NBcPZHZgW4_krrmihjLHmVzzuoMdl2MFAAAAAQ";
+    const ASSERTION_CLIENT_DATA: &str = r#"{"type":"webauthn.get","challenge":"U2lsdmVyV2luZ3NPdmVyVGhlQmF5","origin":"https://localhost","crossOrigin":false}"#;
+    const ASSERTION_SIGNATURE: &str = "MEUCIQCLJzZbnQEfEKqa1nc_CyPYX5IOSEvR3cr199bvLENS0QIgUbZCLLz8vacm--2B4G_t94GgHkkuWcu4QF1UWLjBEhE";
+
+    fn fixture_auth_data() -> Vec<u8> {
+        let (attestation, _size) = decode_cbor(&decode(ATTESTATION_OBJECT).unwrap(), 0).unwrap();
+        attestation.get_text("authData").and_then(Cbor::as_bytes).unwrap().to_vec()
+    }
+
+    #[test]
+    fn test_decode_cbor() {
+        // {"fmt": "none", "n": [-1, h'01']}
+        let data = [0xa2, 0x63, b'f', b'm', b't', 0x64, b'n', b'o', b'n', b'e', 0x61, b'n', 0x82, 0x20, 0x41, 0x01];
+        let (item, size) = decode_cbor(&data, 0).unwrap();
+        assert_eq!(size, data.len());
+        assert_eq!(item.get_text("fmt"), Some(&Cbor::Text(String::from("none"))));
+        assert_eq!(item.get_text("n"), Some(&Cbor::Array(vec![Cbor::Int(-1), Cbor::Bytes(vec![1])])));
+
+        assert!(decode_cbor(&[0x9f], 0).is_err());
+        assert!(decode_cbor(&[0x5a, 0xff, 0xff, 0xff, 0xff], 0).is_err());
+    }
+
+    #[test]
+    fn test_malformed_cbor() {
+        // Map missing a value, invalid UTF-8, undefined, tag, integer past i64
+        assert_eq!(decode_cbor(&[0xa1, 0x01], 0), Err("Truncated CBOR"));
+        assert_eq!(decode_cbor(&[0x62, 0xff, 0xfe], 0), Err("Invalid CBOR text"));
+        assert!(decode_cbor(&[0xf7], 0).is_err());
+        assert!(decode_cbor(&[0xc0, 0x00], 0).is_err());
+        assert!(decode_cbor(&[0x1b, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff], 0).is_err());
+        assert!(decode_cbor(&[0x9b, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff], 0).is_err());
+
+        let nested = [&[0x81; MAX_DEPTH + 1][..], &[0x00]].concat();
+        assert_eq!(decode_cbor(&nested, 0), Err("CBOR nested too deeply"));
+
+        // Cut anywhere, the attestation object is rejected
+        let attestation_object = decode(ATTESTATION_OBJECT).unwrap();
+        for len in 0..attestation_object.len() {
+            assert!(parse_attestation_object(&attestation_object[..len]).is_err());
+        }
+        // authData under another key
+        let mut attestation_object = attestation_object;
+        attestation_object[23] = b'x';
+        assert_eq!(parse_attestation_object(&attestation_object).err(), Some("Missing authData"));
+    }
+
+    #[test]
+    fn test_fixture_registration_and_assertion() {
+        check_client_data(REGISTRATION_CLIENT_DATA.as_bytes(), "webauthn.create", "Ck9zbXlEcmFnb25GbHlzQXRNaWRuaWdodA").unwrap();
+        let registration = parse_attestation_object(&decode(ATTESTATION_OBJECT).unwrap()).unwrap();
+        assert!(registration.user_verified());
+        assert_eq!(registration.sign_count, 0);
+        let credential = registration.credential.unwrap();
+        assert_eq!(encode(&credential.id), CREDENTIAL_ID);
+        assert_eq!(credential.algorithm, ES256);
+
+        check_client_data(ASSERTION_CLIENT_DATA.as_bytes(), "webauthn.get", "U2lsdmVyV2luZ3NPdmVyVGhlQmF5").unwrap();
+        assert_eq!(check_client_data(ASSERTION_CLIENT_DATA.as_bytes(), "webauthn.create", "U2lsdmVyV2luZ3NPdmVyVGhlQmF5"), Err("Unexpected ceremony type"));
+        let auth_data = decode(ASSERTION_AUTH_DATA).unwrap();
+        let assertion = parse_authenticator_data(&auth_data).unwrap();
+        assert_eq!(assertion.sign_count, 1);
+        assert!(assertion.credential.is_none());
+
+        let signature = decode(ASSERTION_SIGNATURE).unwrap();
+        assert!(verify_signature(&credential.public_key, &auth_data, ASSERTION_CLIENT_DATA.as_bytes(), &signature).unwrap());
+        // Signature over another counter
+        let mut replayed = auth_data.clone();
+        replayed[36] = 2;
+        assert!(!verify_signature(&credential.public_key, &replayed, ASSERTION_CLIENT_DATA.as_bytes(), &signature).unwrap());
+    }
+
+    #[test]
+    fn test_truncated_authenticator_data() {
+        let auth_data = fixture_auth_data();
+        for len in 0..auth_data.len() {
+            assert!(parse_authenticator_data(&auth_data[..len]).is_err());
+        }
+        assert_eq!(parse_authenticator_data(&auth_data[..37]).err(), Some("Truncated attested credential"));
+
+        // Credential id length past the end
+        let mut auth_data = auth_data;
+        auth_data[53..55].copy_from_slice(&[0xff, 0xff]);
+        assert_eq!(parse_authenticator_data(&auth_data).err(), Some("Truncated attested credential"));
+    }
+
+    #[test]
+    fn test_registration_and_assertion() {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let key = EcKey::generate(&group).unwrap();
+        let (x, y) = ec_coordinates(&key);
+
+        // Registration: attested credential with a 4 bytes id
+        let rp_id_hash = sha256(WEBAUTHN_RP_ID.as_bytes());
+        let mut auth_data = [&rp_id_hash[..], &[USER_PRESENT | ATTESTED_CREDENTIAL], &[0, 0, 0, 0], &[0u8; 16], &[0, 4], b"cred"].concat();
+        auth_data.extend(cose_es256(&x, &y));
+        let parsed = parse_authenticator_data(&auth_data).unwrap();
+        let credential = parsed.credential.unwrap();
+        assert_eq!(credential.id, b"cred");
+        assert_eq!(credential.algorithm, ES256);
+
+        // Assertion
+        let auth_data = [&rp_id_hash[..], &[USER_PRESENT | USER_VERIFIED], &[0, 0, 0, 1]].concat();
+        let parsed = parse_authenticator_data(&auth_data).unwrap();
+        assert!(parsed.user_verified());
+        assert_eq!(parsed.sign_count, 1);
+
+        let client_data = br#"{"type":"webauthn.get","challenge":"abc","origin":"https://localhost"}"#;
+        let pkey = PKey::from_ec_key(key).unwrap();
+        let mut signer = Signer::new(MessageDigest::sha256(), &pkey).unwrap();
+        signer.update(&auth_data).unwrap();
+        signer.update(&sha256(client_data)).unwrap();
+        let signature = signer.sign_to_vec().unwrap();
+
+        assert!(verify_signature(&credential.public_key, &auth_data, client_data, &signature).unwrap());
+        assert!(!verify_signature(&credential.public_key, &auth_data, b"{}", &signature).unwrap());
+    }
+
+    #[test]
+    fn test_wrong_relying_party() {
+        let auth_data = [&sha256(b"evil.example")[..], &[USER_PRESENT], &[0, 0, 0, 0]].concat();
+        assert!(parse_authenticator_data(&auth_data).is_err());
+
+        let mut auth_data = fixture_auth_data();
+        auth_data[0] ^= 0x01;
+        assert_eq!(parse_authenticator_data(&auth_data).err(), Some("Relying party mismatch"));
+        let mut auth_data = decode(ASSERTION_AUTH_DATA).unwrap();
+        auth_data[31] ^= 0x80;
+        assert_eq!(parse_authenticator_data(&auth_data).err(), Some("Relying party mismatch"));
+    }
+}